@@ -0,0 +1,126 @@
+// Passwords and login sessions.
+//
+// Passwords are stored as Argon2 hashes in PHC string format, which carries
+// its own salt and parameters. Each connection has a Session; LOGIN binds it
+// to a user and hands back a token, which another connection can present with
+// LOGIN TOKEN to act as the same user until LOGOUT or until the token expires.
+// A user holds a limited number of tokens; past it, logging in again ends the
+// oldest one. A session also remembers its SUBSCRIBE, if it made one, the
+// protocol its replies are written in, and whether it has asked for the
+// server to shut down.
+//
+
+use crate::{push::Feed, Protocol};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How long a token lasts after LOGIN, unless the server is told otherwise.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+// Most tokens a user holds at once.
+pub const MAX_TOKENS: usize = 16;
+
+pub fn hash_password(password: &str) -> Result<String, &'static str> {
+    // A v4 uuid is 122 random bits from the OS, plenty for a salt.
+    let salt = match SaltString::encode_b64(Uuid::new_v4().as_bytes()) {
+        Ok(s) => s,
+        Err(_) => return Err("Error salting password!"),
+    };
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(_) => Err("Error hashing password!"),
+    }
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// A fresh random session token.
+pub fn new_token() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+// Who a connection is acting as.
+#[derive(Debug, Default)]
+pub struct Session {
+    user: Option<Uuid>,
+    token: Option<String>,
+    subscription: Option<u64>,
+    // A new subscription's feed, until the transport takes it.
+    feed: Option<Feed>,
+    protocol: Protocol,
+    // Set by an admin's END, for the transport to shut the server down.
+    ending: bool,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    pub fn user(&self) -> Option<Uuid> {
+        self.user
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub(crate) fn bind(&mut self, user: Uuid, token: String) {
+        self.user = Some(user);
+        self.token = Some(token);
+    }
+
+    pub(crate) fn clear(&mut self) -> Option<String> {
+        self.user = None;
+        self.subscription = None;
+        self.feed = None;
+        self.token.take()
+    }
+
+    pub(crate) fn subscription(&self) -> Option<u64> {
+        self.subscription
+    }
+
+    pub(crate) fn subscribe(&mut self, id: u64, feed: Feed) {
+        self.subscription = Some(id);
+        self.feed = Some(feed);
+    }
+
+    pub(crate) fn unsubscribe(&mut self) -> Option<u64> {
+        self.feed = None;
+        self.subscription.take()
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.ending = true;
+    }
+
+    // Whether this session asked for the server to shut down.
+    pub fn ending(&self) -> bool {
+        self.ending
+    }
+
+    // The feed of pushes started by the last SUBSCRIBE, for the transport to
+    // drain. Only handed out once.
+    pub fn take_feed(&mut self) -> Option<Feed> {
+        self.feed.take()
+    }
+}
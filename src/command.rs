@@ -0,0 +1,635 @@
+// Commands understood by the server, and the parser for their wire format.
+//
+// Grammar (one command per frame, keywords are case sensitive):
+//   GET USER (ID|NAME|EMAIL) <search>
+//   GET USER MULT (NAME|EMAIL) <search>
+//   GET CONV [MULT] NAME <name>
+//   GET CONV [MULT] MEMBERS <id>,<id>,...
+//   GET REL <user id> <user id>
+//   GET MSG ID <msg id>
+//   GET MSG CONV <conv id> [<newest n>]
+//   GET MSG CONV <conv id> (BEFORE|AFTER) (<msg id>|<rfc3339>) [<limit n>]
+//   GET MSG USER <user id> [(BEFORE|AFTER) (<msg id>|<rfc3339>)] [<limit n>]
+//   GET MSG TIME <rfc3339 from> <rfc3339 to> [(BEFORE|AFTER) ...] [<limit n>]
+//   GET READS <conv id>
+//   GET UNREAD
+//   ADD USER <email> <password> <name>
+//   ADD CONV <id>,<id>,... <name>
+//   SEND MSG <conv id> <text>
+//   EDIT MSG <msg id> <text>
+//   DELETE MSG <msg id>
+//   MARK READ <conv id> <msg id>
+//   ADD MEMBER <conv id> <user id>
+//   REMOVE MEMBER <conv id> <user id>
+//   LEAVE CONV <conv id>
+//   SET OWNER <conv id> <user id>
+//   SET ROLE <conv id> <user id> (admin|member)
+//   LOGIN <email> <password>
+//   LOGIN TOKEN <token>
+//   LOGOUT
+//   BLOCK <user id>
+//   UNBLOCK <user id>
+//   FRIEND (REQUEST|ACCEPT|DECLINE|CANCEL|PROMOTE|REMOVE) <user id>
+//   SUBSCRIBE (ALL|<conv id>)
+//   UNSUBSCRIBE
+//   PROTOCOL (TEXT|JSON)
+//   END
+//
+// Search terms run to the end of the line, so they may contain spaces.
+// A conversation's history is ordered by time, then by message id, and paged
+// through with BEFORE and AFTER: each gives up to n messages on that side of
+// a message or a time, not counting the message itself. Pages come back
+// oldest first, so the first id is the cursor for the page before.
+// Passwords are a single word. Messages are sent as the logged in user.
+// Conversations and their messages are only shown to members and admins;
+// asking for someone else's gets PERMISSION DENIED.
+// Once subscribed, a connection is also sent "PUSH MSG <msg>" frames as new
+// messages arrive, and "PUSH EDIT <msg>" or "PUSH DELETE <msg>" when one is
+// changed; SUBSCRIBE ALL follows every conversation the user is in.
+// Authors edit and delete their own messages, and conversation owners and
+// admins anyone's. A deleted message stays behind as a tombstone, without
+// its text.
+// MARK READ moves the user's read marker in a conversation up to a message,
+// never back. GET UNREAD lists the user's conversations with how many
+// messages from others came after their marker, and GET READS shows members
+// how far each of them has read.
+// PROTOCOL switches a connection's replies and pushes between the text
+// records and JSON, and works without logging in.
+// END shuts the server down, letting requests in flight finish; only site
+// admins may send it.
+//
+
+use crate::{Protocol, Role};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use uuid::Uuid;
+
+// How many messages GET MSG CONV, USER or TIME returns when no count is
+// given.
+pub const DEFAULT_MSG_COUNT: usize = 50;
+// The most any of them returns, whatever count is asked for.
+pub const MAX_MSG_COUNT: usize = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    GetUser {
+        field: UserField,
+        search: String,
+        mult: bool,
+    },
+    GetConv {
+        query: ConvQuery,
+        mult: bool,
+    },
+    GetRel(Uuid, Uuid),
+    GetMsg(MsgQuery),
+    AddUser {
+        name: String,
+        email: String,
+        password: String,
+    },
+    AddConv {
+        name: String,
+        members: Vec<Uuid>,
+    },
+    SendMsg {
+        conv: Uuid,
+        text: String,
+    },
+    EditMsg {
+        msg: Uuid,
+        text: String,
+    },
+    DeleteMsg(Uuid),
+    MarkRead {
+        conv: Uuid,
+        msg: Uuid,
+    },
+    GetReads(Uuid),
+    GetUnread,
+    AddMember {
+        conv: Uuid,
+        user: Uuid,
+    },
+    RemoveMember {
+        conv: Uuid,
+        user: Uuid,
+    },
+    LeaveConv(Uuid),
+    SetOwner {
+        conv: Uuid,
+        user: Uuid,
+    },
+    SetRole {
+        conv: Uuid,
+        user: Uuid,
+        role: Role,
+    },
+    Login {
+        email: String,
+        password: String,
+    },
+    LoginToken(String),
+    Logout,
+    Block(Uuid),
+    Unblock(Uuid),
+    Friend(FriendAction, Uuid),
+    // None subscribes to all of the user's conversations.
+    Subscribe(Option<Uuid>),
+    Unsubscribe,
+    Protocol(Protocol),
+    End,
+}
+
+// Steps in the friend request workflow, taken by the logged in user towards
+// another user.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FriendAction {
+    Request,
+    Accept,
+    Decline,
+    Cancel,
+    Promote,
+    Remove,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UserField {
+    Id,
+    Name,
+    Email,
+}
+
+impl UserField {
+    // The option name used by App::get_user and App::get_user_mult.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UserField::Id => "ID",
+            UserField::Name => "NAME",
+            UserField::Email => "EMAIL",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConvQuery {
+    Name(String),
+    Members(Vec<Uuid>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MsgQuery {
+    Id(Uuid),
+    Conv {
+        conv: Uuid,
+        newest: usize,
+    },
+    // A page of a conversation's history next to `cursor`.
+    Page {
+        conv: Uuid,
+        cursor: Cursor,
+        limit: usize,
+    },
+    // Without a cursor, the newest `limit` matches.
+    Author {
+        user: Uuid,
+        cursor: Option<Cursor>,
+        limit: usize,
+    },
+    Time {
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        cursor: Option<Cursor>,
+        limit: usize,
+    },
+}
+
+// Which side of a position in the results a page is from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Cursor {
+    Before(Position),
+    After(Position),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Position {
+    Msg(Uuid),
+    Time(DateTime<Utc>),
+}
+
+impl Position {
+    // A message id or an rfc3339 time.
+    pub fn parse(at: &str) -> Result<Position, ParseError> {
+        if let Ok(id) = Uuid::parse_str(at) {
+            return Ok(Position::Msg(id));
+        }
+        match DateTime::parse_from_rfc3339(at) {
+            Ok(t) => Ok(Position::Time(DateTime::from(t))),
+            Err(_) => Err(ParseError::InvalidCursor(at.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownVerb(String),
+    UnknownTarget(String),
+    InvalidOption(String),
+    Missing(&'static str),
+    InvalidId(String),
+    InvalidNumber(String),
+    InvalidTime(String),
+    InvalidCursor(String),
+    TrailingInput(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "EMPTY COMMAND"),
+            ParseError::UnknownVerb(v) => write!(f, "UNKNOWN COMMAND '{}'", v),
+            ParseError::UnknownTarget(t) => write!(f, "UNKNOWN TARGET '{}'", t),
+            ParseError::InvalidOption(o) => write!(f, "INVALID OPTION '{}'", o),
+            ParseError::Missing(what) => write!(f, "NO {} PROVIDED", what),
+            ParseError::InvalidId(id) => write!(f, "INVALID ID '{}'", id),
+            ParseError::InvalidNumber(n) => write!(f, "INVALID NUMBER '{}'", n),
+            ParseError::InvalidTime(t) => write!(f, "INVALID TIME '{}'", t),
+            ParseError::InvalidCursor(c) => write!(f, "INVALID CURSOR '{}'", c),
+            ParseError::TrailingInput(s) => write!(f, "UNEXPECTED INPUT '{}'", s),
+        }
+    }
+}
+
+// Splits a request into words, leaving whatever is left over intact.
+struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    fn new(input: &'a str) -> Args<'a> {
+        Args { rest: input.trim() }
+    }
+
+    fn word(&mut self, what: &'static str) -> Result<&'a str, ParseError> {
+        if self.rest.is_empty() {
+            return Err(ParseError::Missing(what));
+        }
+        let (word, rest) = match self.rest.find(char::is_whitespace) {
+            Some(i) => (&self.rest[..i], &self.rest[i..]),
+            None => (self.rest, ""),
+        };
+        self.rest = rest.trim_start();
+        Ok(word)
+    }
+
+    fn peek(&self) -> &'a str {
+        self.rest.split_whitespace().next().unwrap_or("")
+    }
+
+    fn rest(&mut self, what: &'static str) -> Result<&'a str, ParseError> {
+        if self.rest.is_empty() {
+            return Err(ParseError::Missing(what));
+        }
+        Ok(std::mem::take(&mut self.rest))
+    }
+
+    fn id(&mut self, what: &'static str) -> Result<Uuid, ParseError> {
+        parse_id(self.word(what)?)
+    }
+
+    fn time(&mut self, what: &'static str) -> Result<DateTime<Utc>, ParseError> {
+        let time = self.word(what)?;
+        match DateTime::parse_from_rfc3339(time) {
+            Ok(t) => Ok(DateTime::from(t)),
+            Err(_) => Err(ParseError::InvalidTime(time.to_string())),
+        }
+    }
+
+    // A count at the end of a command, if there is one.
+    fn count(&mut self) -> Result<usize, ParseError> {
+        match self.peek() {
+            "" => Ok(DEFAULT_MSG_COUNT),
+            n => {
+                self.word("COUNT")?;
+                n.parse()
+                    .map_err(|_| ParseError::InvalidNumber(n.to_string()))
+            }
+        }
+    }
+
+    // BEFORE or AFTER a position, if either is next.
+    fn cursor(&mut self) -> Result<Option<Cursor>, ParseError> {
+        let before = match self.peek() {
+            "BEFORE" => true,
+            "AFTER" => false,
+            _ => return Ok(None),
+        };
+        self.word("OPTION")?;
+        let at = self.position()?;
+        Ok(Some(if before {
+            Cursor::Before(at)
+        } else {
+            Cursor::After(at)
+        }))
+    }
+
+    fn position(&mut self) -> Result<Position, ParseError> {
+        Position::parse(self.word("CURSOR")?)
+    }
+
+    fn finish(&self) -> Result<(), ParseError> {
+        if self.rest.is_empty() {
+            Ok(())
+        } else {
+            Err(ParseError::TrailingInput(self.rest.to_string()))
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(input: &str) -> Result<Command, ParseError> {
+        let mut args = Args::new(input);
+        if args.rest.is_empty() {
+            return Err(ParseError::Empty);
+        }
+        match args.word("COMMAND")? {
+            "GET" => match args.word("TARGET")? {
+                "USER" => Command::parse_get_user(&mut args),
+                "CONV" => Command::parse_get_conv(&mut args),
+                "REL" => {
+                    let user1 = args.id("USER")?;
+                    let user2 = args.id("USER")?;
+                    args.finish()?;
+                    Ok(Command::GetRel(user1, user2))
+                }
+                "MSG" => Command::parse_get_msg(&mut args),
+                "READS" => {
+                    let conv = args.id("CONV")?;
+                    args.finish()?;
+                    Ok(Command::GetReads(conv))
+                }
+                "UNREAD" => {
+                    args.finish()?;
+                    Ok(Command::GetUnread)
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "ADD" => match args.word("TARGET")? {
+                "USER" => {
+                    let email = args.word("EMAIL")?.to_string();
+                    let password = args.word("PASSWORD")?.to_string();
+                    let name = args.rest("NAME")?.to_string();
+                    Ok(Command::AddUser {
+                        name,
+                        email,
+                        password,
+                    })
+                }
+                "CONV" => {
+                    let members = parse_ids(args.word("USERS")?)?;
+                    let name = args.rest("NAME")?.to_string();
+                    Ok(Command::AddConv { name, members })
+                }
+                "MEMBER" => {
+                    let conv = args.id("CONV")?;
+                    let user = args.id("USER")?;
+                    args.finish()?;
+                    Ok(Command::AddMember { conv, user })
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "REMOVE" => match args.word("TARGET")? {
+                "MEMBER" => {
+                    let conv = args.id("CONV")?;
+                    let user = args.id("USER")?;
+                    args.finish()?;
+                    Ok(Command::RemoveMember { conv, user })
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "LEAVE" => match args.word("TARGET")? {
+                "CONV" => {
+                    let conv = args.id("CONV")?;
+                    args.finish()?;
+                    Ok(Command::LeaveConv(conv))
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "SET" => match args.word("TARGET")? {
+                "OWNER" => {
+                    let conv = args.id("CONV")?;
+                    let user = args.id("USER")?;
+                    args.finish()?;
+                    Ok(Command::SetOwner { conv, user })
+                }
+                "ROLE" => {
+                    let conv = args.id("CONV")?;
+                    let user = args.id("USER")?;
+                    let role = match args.word("ROLE")? {
+                        "admin" => Role::Admin,
+                        "member" => Role::Member,
+                        o => return Err(ParseError::InvalidOption(o.to_string())),
+                    };
+                    args.finish()?;
+                    Ok(Command::SetRole { conv, user, role })
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "SEND" => match args.word("TARGET")? {
+                "MSG" => {
+                    let conv = args.id("CONV")?;
+                    let text = args.rest("TEXT")?.to_string();
+                    Ok(Command::SendMsg { conv, text })
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "EDIT" => match args.word("TARGET")? {
+                "MSG" => {
+                    let msg = args.id("MSG")?;
+                    let text = args.rest("TEXT")?.to_string();
+                    Ok(Command::EditMsg { msg, text })
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "DELETE" => match args.word("TARGET")? {
+                "MSG" => {
+                    let msg = args.id("MSG")?;
+                    args.finish()?;
+                    Ok(Command::DeleteMsg(msg))
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "MARK" => match args.word("TARGET")? {
+                "READ" => {
+                    let conv = args.id("CONV")?;
+                    let msg = args.id("MSG")?;
+                    args.finish()?;
+                    Ok(Command::MarkRead { conv, msg })
+                }
+                t => Err(ParseError::UnknownTarget(t.to_string())),
+            },
+            "LOGIN" => {
+                let cmd = match args.word("EMAIL")? {
+                    "TOKEN" => Command::LoginToken(args.word("TOKEN")?.to_string()),
+                    email => Command::Login {
+                        email: email.to_string(),
+                        password: args.word("PASSWORD")?.to_string(),
+                    },
+                };
+                args.finish()?;
+                Ok(cmd)
+            }
+            "LOGOUT" => {
+                args.finish()?;
+                Ok(Command::Logout)
+            }
+            "BLOCK" => {
+                let user = args.id("USER")?;
+                args.finish()?;
+                Ok(Command::Block(user))
+            }
+            "UNBLOCK" => {
+                let user = args.id("USER")?;
+                args.finish()?;
+                Ok(Command::Unblock(user))
+            }
+            "FRIEND" => {
+                let action = match args.word("OPTION")? {
+                    "REQUEST" => FriendAction::Request,
+                    "ACCEPT" => FriendAction::Accept,
+                    "DECLINE" => FriendAction::Decline,
+                    "CANCEL" => FriendAction::Cancel,
+                    "PROMOTE" => FriendAction::Promote,
+                    "REMOVE" => FriendAction::Remove,
+                    o => return Err(ParseError::InvalidOption(o.to_string())),
+                };
+                let user = args.id("USER")?;
+                args.finish()?;
+                Ok(Command::Friend(action, user))
+            }
+            "SUBSCRIBE" => {
+                let conv = match args.word("CONV")? {
+                    "ALL" => None,
+                    id => Some(parse_id(id)?),
+                };
+                args.finish()?;
+                Ok(Command::Subscribe(conv))
+            }
+            "UNSUBSCRIBE" => {
+                args.finish()?;
+                Ok(Command::Unsubscribe)
+            }
+            "PROTOCOL" => {
+                let protocol = match args.word("OPTION")? {
+                    "TEXT" => Protocol::Text,
+                    "JSON" => Protocol::Json,
+                    o => return Err(ParseError::InvalidOption(o.to_string())),
+                };
+                args.finish()?;
+                Ok(Command::Protocol(protocol))
+            }
+            "END" => {
+                args.finish()?;
+                Ok(Command::End)
+            }
+            v => Err(ParseError::UnknownVerb(v.to_string())),
+        }
+    }
+
+    // The request as it is safe to log, with anything after the email of a
+    // LOGIN or ADD USER left out.
+    pub fn redact(input: &str) -> String {
+        let words: Vec<&str> = input.split_whitespace().collect();
+        match words.as_slice() {
+            ["LOGIN", "TOKEN", ..] => String::from("LOGIN TOKEN ..."),
+            ["LOGIN", email, ..] => format!("LOGIN {} ...", email),
+            ["ADD", "USER", email, ..] => format!("ADD USER {} ...", email),
+            _ => input.to_string(),
+        }
+    }
+
+    fn parse_get_user(args: &mut Args) -> Result<Command, ParseError> {
+        let mult = args.peek() == "MULT";
+        if mult {
+            args.word("OPTION")?;
+        }
+        let field = match args.word("OPTION")? {
+            "ID" if !mult => UserField::Id,
+            "NAME" => UserField::Name,
+            "EMAIL" => UserField::Email,
+            o => return Err(ParseError::InvalidOption(o.to_string())),
+        };
+        let search = args.rest("SEARCH TERM")?.to_string();
+        Ok(Command::GetUser {
+            field,
+            search,
+            mult,
+        })
+    }
+
+    fn parse_get_conv(args: &mut Args) -> Result<Command, ParseError> {
+        let mult = args.peek() == "MULT";
+        if mult {
+            args.word("OPTION")?;
+        }
+        let query = match args.word("OPTION")? {
+            "NAME" => ConvQuery::Name(args.rest("SEARCH TERM")?.to_string()),
+            "MEMBERS" => ConvQuery::Members(parse_ids(args.rest("USERS")?)?),
+            o => return Err(ParseError::InvalidOption(o.to_string())),
+        };
+        Ok(Command::GetConv { query, mult })
+    }
+
+    fn parse_get_msg(args: &mut Args) -> Result<Command, ParseError> {
+        let query = match args.word("OPTION")? {
+            "ID" => MsgQuery::Id(args.id("MSG")?),
+            "CONV" => {
+                let conv = args.id("CONV")?;
+                match args.cursor()? {
+                    Some(cursor) => MsgQuery::Page {
+                        conv,
+                        cursor,
+                        limit: args.count()?,
+                    },
+                    None => MsgQuery::Conv {
+                        conv,
+                        newest: args.count()?,
+                    },
+                }
+            }
+            "USER" => MsgQuery::Author {
+                user: args.id("USER")?,
+                cursor: args.cursor()?,
+                limit: args.count()?,
+            },
+            "TIME" => MsgQuery::Time {
+                from: args.time("START TIME")?,
+                to: args.time("END TIME")?,
+                cursor: args.cursor()?,
+                limit: args.count()?,
+            },
+            o => return Err(ParseError::InvalidOption(o.to_string())),
+        };
+        args.finish()?;
+        Ok(Command::GetMsg(query))
+    }
+}
+
+// Parses a comma separated list of ids, ignoring empty entries.
+fn parse_ids(input: &str) -> Result<Vec<Uuid>, ParseError> {
+    let ids = input
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(parse_id)
+        .collect::<Result<Vec<Uuid>, ParseError>>()?;
+    if ids.is_empty() {
+        return Err(ParseError::Missing("USERS"));
+    }
+    Ok(ids)
+}
+
+fn parse_id(input: &str) -> Result<Uuid, ParseError> {
+    Uuid::parse_str(input).map_err(|_| ParseError::InvalidId(input.to_string()))
+}
@@ -0,0 +1,313 @@
+// Server settings, from a config file and command line flags.
+//
+// The config file has one "key = value" setting per line; '#' starts a
+// comment. Every key can also be given as a flag, with dashes for
+// underscores (--data-dir files or --data-dir=files), and flags win over the
+// file. --print-config prints the settings in the file's format and exits.
+//
+
+use crate::{LoadMode, LogLevel, GRACE_PERIOD, IDLE_TIMEOUT, MAX_CONNECTIONS, TOKEN_LIFETIME};
+use std::{fmt, fs, net::IpAddr, path::Path, time::Duration};
+
+const USAGE: &str = "Usage: chat_server [--config <file>] [--print-config] [--<key> <value>]...
+Keys (and their defaults):
+  bind            address to listen on (127.0.0.1)
+  port            raw TCP protocol port (8080)
+  ws_port         WebSocket port, or off (8081)
+  http_port       HTTP/JSON API port, or off (8082)
+  data_dir        directory holding the data files (files)
+  storage         files, or sqlite for a chat.db in data_dir (files)
+  load_mode       strict stops at a bad record, lenient skips it (strict)
+  threads         worker threads per listener (4)
+  max_connections connections each listener keeps open at once (1024)
+  idle_timeout    seconds a connection may send nothing before it's closed (300)
+  token_lifetime  seconds a LOGIN token lasts (86400)
+  admin           email of a user to make an admin at startup (none)
+  log_level       error, warn, info or debug (info)
+  shutdown_grace  seconds requests get to finish on shutdown (10)";
+
+// Most threads a listener can be given.
+const MAX_THREADS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub ws_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub data_dir: String,
+    pub storage: StorageKind,
+    pub load_mode: LoadMode,
+    pub threads: usize,
+    pub max_connections: usize,
+    pub idle_timeout: Duration,
+    pub token_lifetime: Duration,
+    pub admin: Option<String>,
+    pub log_level: LogLevel,
+    pub shutdown_grace: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 8080,
+            ws_port: Some(8081),
+            http_port: Some(8082),
+            data_dir: String::from("files"),
+            storage: StorageKind::Files,
+            load_mode: LoadMode::Strict,
+            threads: 4,
+            max_connections: MAX_CONNECTIONS,
+            idle_timeout: IDLE_TIMEOUT,
+            token_lifetime: TOKEN_LIFETIME,
+            admin: None,
+            log_level: LogLevel::Info,
+            shutdown_grace: GRACE_PERIOD,
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let port = |p: Option<u16>| p.map_or(String::from("off"), |p| p.to_string());
+        writeln!(f, "bind = {}", self.bind)?;
+        writeln!(f, "port = {}", self.port)?;
+        writeln!(f, "ws_port = {}", port(self.ws_port))?;
+        writeln!(f, "http_port = {}", port(self.http_port))?;
+        writeln!(f, "data_dir = {}", self.data_dir)?;
+        writeln!(f, "storage = {}", self.storage)?;
+        writeln!(f, "load_mode = {}", self.load_mode)?;
+        writeln!(f, "threads = {}", self.threads)?;
+        writeln!(f, "max_connections = {}", self.max_connections)?;
+        writeln!(f, "idle_timeout = {}", self.idle_timeout.as_secs())?;
+        writeln!(f, "token_lifetime = {}", self.token_lifetime.as_secs())?;
+        if let Some(email) = &self.admin {
+            writeln!(f, "admin = {}", email)?;
+        }
+        writeln!(f, "log_level = {}", self.log_level)?;
+        writeln!(f, "shutdown_grace = {}", self.shutdown_grace.as_secs())
+    }
+}
+
+// Where the records are kept.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StorageKind {
+    // Flat files plus a journal, in data_dir.
+    Files,
+    // A SQLite database, data_dir/chat.db.
+    Sqlite,
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageKind::Files => write!(f, "files"),
+            StorageKind::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+// What the command line asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Startup {
+    Run(Config),
+    PrintConfig(Config),
+    Help(&'static str),
+}
+
+impl Config {
+    // Reads the command line (without the program name), and the config file
+    // it names, if any.
+    pub fn from_args<I>(args: I) -> Result<Startup, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut file = None;
+        let mut print = false;
+        let mut flags = Vec::new();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => return Err(format!("unexpected argument '{}'", arg)),
+            };
+            let (key, value) = match flag.find('=') {
+                Some(i) => (&flag[..i], Some(flag[i + 1..].to_string())),
+                None => (flag, None),
+            };
+            match key {
+                "help" => return Ok(Startup::Help(USAGE)),
+                "print-config" => print = true,
+                _ => {
+                    let value = match value.or_else(|| args.next()) {
+                        Some(v) => v,
+                        None => return Err(format!("--{} needs a value", key)),
+                    };
+                    match key {
+                        "config" => file = Some(value),
+                        _ => flags.push((key.replace('-', "_"), value)),
+                    }
+                }
+            }
+        }
+
+        let mut config = match file {
+            Some(file) => Config::from_file(&file)?,
+            None => Config::default(),
+        };
+        for (key, value) in flags {
+            config
+                .set(&key, &value)
+                .map_err(|e| format!("--{}: {}", key.replace('_', "-"), e))?;
+        }
+        config.validate()?;
+        Ok(if print {
+            Startup::PrintConfig(config)
+        } else {
+            Startup::Run(config)
+        })
+    }
+
+    pub fn from_file(file: &str) -> Result<Config, String> {
+        let contents =
+            fs::read_to_string(file).map_err(|e| format!("{}: can't read: {}", file, e))?;
+        Config::parse(&contents).map_err(|e| format!("{}:{}", file, e))
+    }
+
+    // Reads settings over the defaults. Errors start with the line number.
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(start) => &line[..start],
+                None => line,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => return Err(format!("{}: expected 'key = value'", i + 1)),
+            };
+            config
+                .set(key, value)
+                .map_err(|e| format!("{}: {}: {}", i + 1, key, e))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "bind" => {
+                self.bind = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not an IP address", value))?
+            }
+            "port" => self.port = parse_port(value)?,
+            "ws_port" => self.ws_port = parse_optional_port(value)?,
+            "http_port" => self.http_port = parse_optional_port(value)?,
+            "data_dir" => {
+                if value.is_empty() {
+                    return Err(String::from("can't be empty"));
+                }
+                self.data_dir = value.to_string()
+            }
+            "storage" => {
+                self.storage = match value {
+                    "files" => StorageKind::Files,
+                    "sqlite" if cfg!(feature = "sqlite") => StorageKind::Sqlite,
+                    "sqlite" => return Err(String::from("built without sqlite")),
+                    _ => return Err(format!("'{}' is not files or sqlite", value)),
+                }
+            }
+            "load_mode" => {
+                self.load_mode = LoadMode::from_str(value)
+                    .ok_or_else(|| format!("'{}' is not strict or lenient", value))?
+            }
+            "threads" => {
+                self.threads = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number", value))?
+            }
+            "max_connections" => {
+                self.max_connections = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number", value))?
+            }
+            "idle_timeout" => {
+                let secs = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number of seconds", value))?;
+                self.idle_timeout = Duration::from_secs(secs)
+            }
+            "token_lifetime" => {
+                let secs = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number of seconds", value))?;
+                self.token_lifetime = Duration::from_secs(secs)
+            }
+            "admin" => {
+                if !value.contains('@') {
+                    return Err(format!("'{}' is not an email", value));
+                }
+                self.admin = Some(value.to_string())
+            }
+            "log_level" => {
+                self.log_level = LogLevel::from_str(value).ok_or_else(|| {
+                    format!("'{}' is not one of error, warn, info or debug", value)
+                })?
+            }
+            "shutdown_grace" => {
+                let secs = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number of seconds", value))?;
+                self.shutdown_grace = Duration::from_secs(secs)
+            }
+            _ => return Err(String::from("unknown setting")),
+        }
+        Ok(())
+    }
+
+    // Checks the settings make sense together, and against the system.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threads == 0 || self.threads > MAX_THREADS {
+            return Err(format!("threads must be from 1 to {}", MAX_THREADS));
+        }
+        if self.max_connections == 0 {
+            return Err(String::from("max_connections must be at least 1"));
+        }
+        // A zero read timeout is an error rather than none at all.
+        if self.idle_timeout.is_zero() {
+            return Err(String::from("idle_timeout must be at least 1 second"));
+        }
+        if self.token_lifetime.is_zero() {
+            return Err(String::from("token_lifetime must be at least 1 second"));
+        }
+        let mut ports = vec![self.port];
+        for port in self.ws_port.iter().chain(self.http_port.iter()) {
+            if ports.contains(port) {
+                return Err(format!("port {} is used twice", port));
+            }
+            ports.push(*port);
+        }
+        if !Path::new(&self.data_dir).is_dir() {
+            return Err(format!("data_dir '{}' is not a directory", self.data_dir));
+        }
+        Ok(())
+    }
+}
+
+fn parse_port(value: &str) -> Result<u16, String> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(format!("'{}' is not a port from 1 to 65535", value)),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn parse_optional_port(value: &str) -> Result<Option<u16>, String> {
+    match value {
+        "off" => Ok(None),
+        _ => parse_port(value).map(Some),
+    }
+}
@@ -0,0 +1,123 @@
+// Framing for the wire protocol.
+//
+// Requests are newline-delimited: one command per line, with an optional
+// trailing '\r'. Responses can span several lines, so they are length-prefixed:
+// the payload size in bytes on its own line, followed by the payload itself.
+//
+
+use std::{
+    fmt,
+    io::{self, prelude::*, BufReader},
+};
+
+// Largest frame either side will accept, in bytes.
+pub const MAX_FRAME: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    TooLong,
+    BadLength(String),
+    InvalidUtf8,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "IO ERROR: {}", e),
+            FrameError::TooLong => write!(f, "FRAME LONGER THAN {} BYTES", MAX_FRAME),
+            FrameError::BadLength(len) => write!(f, "INVALID FRAME LENGTH '{}'", len),
+            FrameError::InvalidUtf8 => write!(f, "FRAME IS NOT VALID UTF-8"),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> FrameError {
+        FrameError::Io(e)
+    }
+}
+
+impl FrameError {
+    // Whether the stream can still be read after this error. Once a frame
+    // boundary is lost there is no way to find the next one.
+    pub fn recoverable(&self) -> bool {
+        matches!(self, FrameError::InvalidUtf8)
+    }
+}
+
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: BufReader<R>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner: BufReader::new(inner),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    // Reads one newline-delimited frame. Returns None once the stream is closed.
+    // Data left unterminated at the end of the stream still counts as a frame.
+    pub fn read_line(&mut self) -> Result<Option<String>, FrameError> {
+        let mut buf = Vec::new();
+        let read = (&mut self.inner)
+            .take(MAX_FRAME as u64 + 1)
+            .read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        }
+        if buf.len() > MAX_FRAME {
+            return Err(FrameError::TooLong);
+        }
+        match String::from_utf8(buf) {
+            Ok(s) => Ok(Some(s)),
+            Err(_) => Err(FrameError::InvalidUtf8),
+        }
+    }
+
+    // Reads one length-prefixed frame. Returns None once the stream is closed.
+    pub fn read_sized(&mut self) -> Result<Option<String>, FrameError> {
+        let header = match self.read_line() {
+            Ok(Some(h)) => h,
+            Ok(None) => return Ok(None),
+            Err(FrameError::InvalidUtf8) => return Err(FrameError::BadLength(String::new())),
+            Err(e) => return Err(e),
+        };
+        let len: usize = match header.trim().parse() {
+            Ok(len) => len,
+            Err(_) => return Err(FrameError::BadLength(header)),
+        };
+        if len > MAX_FRAME {
+            return Err(FrameError::TooLong);
+        }
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf)?;
+        match String::from_utf8(buf) {
+            Ok(s) => Ok(Some(s)),
+            Err(_) => Err(FrameError::InvalidUtf8),
+        }
+    }
+}
+
+// Frames go out in a single write so they aren't split across packets.
+pub fn write_line<W: Write>(stream: &mut W, payload: &str) -> io::Result<()> {
+    stream.write_all(format!("{}\n", payload).as_bytes())?;
+    stream.flush()
+}
+
+pub fn write_sized<W: Write>(stream: &mut W, payload: &str) -> io::Result<()> {
+    stream.write_all(format!("{}\n{}", payload.len(), payload).as_bytes())?;
+    stream.flush()
+}
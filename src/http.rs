@@ -0,0 +1,568 @@
+// HTTP/JSON API, for tools that speak HTTP rather than the wire protocol.
+//
+// Resources (bodies are JSON, and so is every response):
+//   POST   /login                     {"email", "password"} -> {"id", "token"}
+//   POST   /logout
+//   POST   /users                     {"name", "email", "password"}
+//   GET    /users?name=<search>       or ?email=<search>
+//   GET    /users/<id>
+//   GET    /convs?name=<search>       or ?members=<id>,<id>,...
+//   POST   /convs                     {"name", "members": [<id>, ...]}
+//   GET    /convs/<id>/messages       [?newest=<n>]
+//                                     or ?before=<msg id|time>[&limit=<n>]
+//                                     or ?after=<msg id|time>[&limit=<n>]
+//   POST   /convs/<id>/messages       {"text"}
+//   PATCH  /messages/<id>             {"text"}
+//   DELETE /messages/<id>
+//   POST   /convs/<id>/read           {"msg"}
+//   GET    /convs/<id>/reads
+//   GET    /unread
+//   GET    /rels?with=<user id>
+//
+// Everything but POST /login and POST /users needs the token from POST /login
+// in an "Authorization: Bearer <token>" header. Each request is turned into a
+// Command and run like one from any other connection, so the same rules
+// apply; only the status code and the JSON are particular to HTTP.
+//
+
+use crate::{
+    command::DEFAULT_MSG_COUNT, lock, reply::error_json, App, Command, ConvQuery, Cursor,
+    JobHandle, Listener, MsgQuery, Position, Reply, Session, ShutdownHandle, ThreadPool, UserField,
+    MAX_FRAME, WRITE_TIMEOUT,
+};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+// Largest request head we will read, request line and headers together.
+const MAX_HEAD: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    BadRequest(&'static str),
+    TooLarge,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(e) => write!(f, "IO ERROR: {}", e),
+            HttpError::BadRequest(e) => write!(f, "BAD REQUEST: {}", e),
+            HttpError::TooLarge => write!(f, "REQUEST LARGER THAN {} BYTES", MAX_FRAME),
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> HttpError {
+        HttpError::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    // Header names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    // Reads a request line and headers, but not the body. Returns None if the
+    // stream closes before a request starts.
+    pub fn read_head<R: BufRead>(stream: &mut R) -> Result<Option<Request>, HttpError> {
+        let mut lines = Vec::new();
+        let mut read = 0;
+        loop {
+            let mut line = String::new();
+            let n = (&mut *stream)
+                .take((MAX_HEAD - read) as u64)
+                .read_line(&mut line)?;
+            read += n;
+            if n == 0 && lines.is_empty() {
+                return Ok(None);
+            }
+            if read >= MAX_HEAD {
+                return Err(HttpError::TooLarge);
+            }
+            if n == 0 || !line.ends_with('\n') {
+                return Err(HttpError::BadRequest("request ended early"));
+            }
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+        let mut lines = lines.into_iter();
+        let start = lines.next().unwrap_or_default();
+        let parts: Vec<&str> = start.split(' ').collect();
+        if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+            return Err(HttpError::BadRequest("bad request line"));
+        }
+        let (path, query) = match parts[1].find('?') {
+            Some(i) => (&parts[1][..i], &parts[1][i + 1..]),
+            None => (parts[1], ""),
+        };
+        let mut headers = Vec::new();
+        for line in lines {
+            match line.find(':') {
+                Some(i) => headers.push((
+                    line[..i].trim().to_lowercase(),
+                    line[i + 1..].trim().to_string(),
+                )),
+                None => return Err(HttpError::BadRequest("bad header")),
+            }
+        }
+        Ok(Some(Request {
+            method: parts[0].to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            version: parts[2].to_string(),
+            headers,
+            body: Vec::new(),
+        }))
+    }
+
+    // Reads the body that Content-Length promises.
+    fn read_body<R: Read>(&mut self, stream: &mut R) -> Result<(), HttpError> {
+        if self.header("transfer-encoding").is_some() {
+            return Err(HttpError::BadRequest("chunked bodies aren't supported"));
+        }
+        let len = match self.header("content-length") {
+            Some(len) => match len.parse::<usize>() {
+                Ok(len) => len,
+                Err(_) => return Err(HttpError::BadRequest("bad Content-Length")),
+            },
+            None => 0,
+        };
+        if len > MAX_FRAME {
+            return Err(HttpError::TooLarge);
+        }
+        self.body = vec![0; len];
+        stream.read_exact(&mut self.body)?;
+        Ok(())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Whether a header holds `token` in its comma separated list.
+    pub fn header_has(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    }
+
+    fn keep_alive(&self) -> bool {
+        self.version == "HTTP/1.1" && !self.header_has("connection", "close")
+    }
+
+    fn param(&self, name: &str) -> Option<String> {
+        self.query.split('&').find_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            if pair.next()? == name {
+                Some(percent_decode(pair.next().unwrap_or("")))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn token(&self) -> Option<&str> {
+        let auth = self.header("authorization")?;
+        match auth.find(' ') {
+            Some(i) if auth[..i].eq_ignore_ascii_case("bearer") => Some(auth[i + 1..].trim()),
+            _ => None,
+        }
+    }
+}
+
+// Decodes a query string value, where '+' is a space.
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match input
+                .get(i + 1..i + 3)
+                .map(|hex| u8::from_str_radix(hex, 16))
+            {
+                Some(Ok(b)) => {
+                    out.push(b);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// A response: the status code and the JSON to send.
+type Response = (u16, Value);
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+// An error found by the HTTP layer itself, shaped like a reply's.
+fn error(status: u16, message: &str) -> Response {
+    let kind = match status {
+        401 => "unauthorized",
+        404 => "not_found",
+        405 => "method_not_allowed",
+        413 => "too_large",
+        _ => "bad_request",
+    };
+    (status, json!({ "error": error_json(kind, message) }))
+}
+
+fn respond(reply: Reply) -> Response {
+    (reply.status(), reply.to_json())
+}
+
+// Like respond, for a reply of one record that is sent on its own rather than
+// in a list. `created` is the status to use when it succeeds.
+fn respond_one(reply: Reply, created: u16) -> Response {
+    match reply {
+        Reply::Empty(_, e) => error(404, e),
+        reply if reply.is_ok() => match reply.to_json() {
+            Value::Array(mut records) if !records.is_empty() => (created, records.remove(0)),
+            json => (created, json),
+        },
+        reply => respond(reply),
+    }
+}
+
+// A string field of a JSON body.
+fn field<'a>(body: &'a Value, name: &'static str) -> Result<&'a str, Response> {
+    body.get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| error(400, &format!("missing \"{}\"", name)))
+}
+
+fn parse_id(id: &str) -> Result<Uuid, Response> {
+    Uuid::parse_str(id).map_err(|_| error(400, &format!("invalid id '{}'", id)))
+}
+
+fn parse_position(at: &str) -> Result<Position, Response> {
+    Position::parse(at).map_err(|_| error(400, &format!("invalid cursor '{}'", at)))
+}
+
+// A count of messages in the query string.
+fn count(req: &Request, name: &str) -> Result<usize, Response> {
+    match req.param(name) {
+        Some(n) => n
+            .parse()
+            .map_err(|_| error(400, &format!("invalid \"{}\"", name))),
+        None => Ok(DEFAULT_MSG_COUNT),
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpServer {
+    listener: Listener,
+}
+
+impl HttpServer {
+    pub fn bind(addr: SocketAddr, threads: usize) -> io::Result<HttpServer> {
+        Ok(HttpServer {
+            listener: Listener::bind(addr, threads)?,
+        })
+    }
+
+    pub fn with_shutdown(mut self, shutdown: ShutdownHandle) -> io::Result<HttpServer> {
+        self.listener.set_shutdown(shutdown)?;
+        Ok(self)
+    }
+
+    // Like TcpServer::with_limits. Keep-alive connections count as idle
+    // between requests.
+    pub fn with_limits(mut self, max_conns: usize, idle: Duration) -> HttpServer {
+        self.listener.set_limits(max_conns, idle);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.listener.shutdown_handle()
+    }
+
+    // Accepts connections until shut down, running their requests on the
+    // pool. A kept-alive connection is closed after the request it is on.
+    pub fn serve(self, app: Arc<Mutex<App>>) {
+        self.listener
+            .serve(move |stream, pool| HttpServer::handle(stream, pool, &app));
+    }
+
+    // Serves requests on one connection until the client is done with it.
+    fn handle(stream: TcpStream, pool: &ThreadPool, app: &Arc<Mutex<App>>) {
+        let mut writer = match stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        let mut reader = BufReader::new(stream);
+        loop {
+            let read = Request::read_head(&mut reader).and_then(|req| match req {
+                Some(mut req) => req.read_body(&mut reader).map(|_| Some(req)),
+                None => Ok(None),
+            });
+            let (req, (status, body)) = match read {
+                Ok(Some(req)) => {
+                    info!("Request: {} {}", req.method, req.path);
+                    let app = Arc::clone(app);
+                    let job = pool.execute(move || {
+                        let resp = HttpServer::route(&app, &req);
+                        (req, resp)
+                    });
+                    match job.and_then(JobHandle::join) {
+                        Ok((req, resp)) => (Some(req), resp),
+                        Err(e) => {
+                            error!("{}", e);
+                            (None, error(500, e))
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(HttpError::Io(e)) => {
+                    warn!("{}", e);
+                    break;
+                }
+                Err(HttpError::TooLarge) => (None, error(413, &HttpError::TooLarge.to_string())),
+                Err(e) => (None, error(400, &e.to_string())),
+            };
+            // A request we couldn't read leaves the stream somewhere unknown.
+            let keep_alive = req.as_ref().is_some_and(Request::keep_alive);
+            let body = body.to_string();
+            let resp = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: {}\r\n\r\n{}",
+                status,
+                reason(status),
+                body.len(),
+                if keep_alive { "keep-alive" } else { "close" },
+                body
+            );
+            if let Err(e) = writer.write_all(resp.as_bytes()) {
+                warn!("{}", e);
+                break;
+            }
+            if !keep_alive {
+                break;
+            }
+        }
+    }
+
+    fn route(app: &Mutex<App>, req: &Request) -> Response {
+        let body = if req.body.is_empty() {
+            Value::Null
+        } else {
+            match serde_json::from_slice(&req.body) {
+                Ok(body) => body,
+                Err(e) => return error(400, &format!("invalid JSON: {}", e)),
+            }
+        };
+        let path: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+        let result = match (req.method.as_str(), path.as_slice()) {
+            ("POST", ["login"]) => HttpServer::login(app, &body),
+            ("POST", ["users"]) => HttpServer::add_user(app, &body),
+            (method, path) => {
+                // Everything else is run as the user the token belongs to.
+                let mut session = Session::new();
+                if let Some(token) = req.token() {
+                    let mut app = lock(app);
+                    app.run(&mut session, Command::LoginToken(token.to_string()));
+                }
+                if session.user().is_none() {
+                    return error(401, "NOT LOGGED IN");
+                }
+                HttpServer::route_as(app, &mut session, method, path, req, &body)
+            }
+        };
+        result.unwrap_or_else(|resp| resp)
+    }
+
+    fn route_as(
+        app: &Mutex<App>,
+        session: &mut Session,
+        method: &str,
+        path: &[&str],
+        req: &Request,
+        body: &Value,
+    ) -> Result<Response, Response> {
+        let run = |session: &mut Session, cmd| lock(app).run(session, cmd);
+        let resp = match (method, path) {
+            ("POST", ["logout"]) => respond(run(session, Command::Logout)),
+            ("GET", ["users"]) => {
+                let (field, search) = match (req.param("name"), req.param("email")) {
+                    (Some(name), _) => (UserField::Name, name),
+                    (_, Some(email)) => (UserField::Email, email),
+                    _ => return Err(error(400, "search by name or email")),
+                };
+                let cmd = Command::GetUser {
+                    field,
+                    search,
+                    mult: true,
+                };
+                respond(run(session, cmd))
+            }
+            ("GET", ["users", id]) => {
+                let cmd = Command::GetUser {
+                    field: UserField::Id,
+                    search: parse_id(id)?.to_string(),
+                    mult: false,
+                };
+                respond_one(run(session, cmd), 200)
+            }
+            ("GET", ["convs"]) => {
+                let query = match (req.param("name"), req.param("members")) {
+                    (Some(name), _) => ConvQuery::Name(name),
+                    (_, Some(members)) => {
+                        let ids = members
+                            .split(',')
+                            .filter(|id| !id.trim().is_empty())
+                            .map(|id| parse_id(id.trim()))
+                            .collect::<Result<Vec<Uuid>, Response>>()?;
+                        ConvQuery::Members(ids)
+                    }
+                    _ => return Err(error(400, "search by name or members")),
+                };
+                respond(run(session, Command::GetConv { query, mult: true }))
+            }
+            ("POST", ["convs"]) => {
+                let name = field(body, "name")?.to_string();
+                let members = body
+                    .get("members")
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| error(400, "missing \"members\""))?
+                    .iter()
+                    .map(|id| parse_id(id.as_str().unwrap_or("")))
+                    .collect::<Result<Vec<Uuid>, Response>>()?;
+                respond_one(run(session, Command::AddConv { name, members }), 201)
+            }
+            ("GET", ["convs", conv, "messages"]) => {
+                let conv = parse_id(conv)?;
+                let cursor = match (req.param("before"), req.param("after")) {
+                    (Some(at), _) => Some(Cursor::Before(parse_position(&at)?)),
+                    (_, Some(at)) => Some(Cursor::After(parse_position(&at)?)),
+                    _ => None,
+                };
+                let query = match cursor {
+                    Some(cursor) => MsgQuery::Page {
+                        conv,
+                        cursor,
+                        limit: count(req, "limit")?,
+                    },
+                    None => MsgQuery::Conv {
+                        conv,
+                        newest: count(req, "newest")?,
+                    },
+                };
+                respond(run(session, Command::GetMsg(query)))
+            }
+            ("POST", ["convs", conv, "messages"]) => {
+                let conv = parse_id(conv)?;
+                let text = field(body, "text")?.to_string();
+                respond_one(run(session, Command::SendMsg { conv, text }), 201)
+            }
+            ("PATCH", ["messages", msg]) => {
+                let msg = parse_id(msg)?;
+                let text = field(body, "text")?.to_string();
+                respond_one(run(session, Command::EditMsg { msg, text }), 200)
+            }
+            ("DELETE", ["messages", msg]) => {
+                let msg = parse_id(msg)?;
+                respond_one(run(session, Command::DeleteMsg(msg)), 200)
+            }
+            ("POST", ["convs", conv, "read"]) => {
+                let conv = parse_id(conv)?;
+                let msg = parse_id(field(body, "msg")?)?;
+                respond_one(run(session, Command::MarkRead { conv, msg }), 200)
+            }
+            ("GET", ["convs", conv, "reads"]) => {
+                let conv = parse_id(conv)?;
+                respond(run(session, Command::GetReads(conv)))
+            }
+            ("GET", ["unread"]) => respond(run(session, Command::GetUnread)),
+            ("GET", ["rels"]) => {
+                let with = match req.param("with") {
+                    Some(id) => parse_id(&id)?,
+                    None => return Err(error(400, "missing \"with\"")),
+                };
+                let me = session.user().unwrap();
+                respond(run(session, Command::GetRel(me, with)))
+            }
+            (_, ["logout"])
+            | (_, ["users"])
+            | (_, ["users", _])
+            | (_, ["convs"])
+            | (_, ["convs", _, "messages"])
+            | (_, ["messages", _])
+            | (_, ["convs", _, "read"])
+            | (_, ["convs", _, "reads"])
+            | (_, ["unread"])
+            | (_, ["rels"]) => error(405, "method not allowed"),
+            _ => error(404, "no such resource"),
+        };
+        Ok(resp)
+    }
+
+    fn login(app: &Mutex<App>, body: &Value) -> Result<Response, Response> {
+        let cmd = Command::Login {
+            email: field(body, "email")?.to_string(),
+            password: field(body, "password")?.to_string(),
+        };
+        let reply = App::run_shared(app, &mut Session::new(), cmd);
+        match reply {
+            Reply::Error(e) => Ok(error(401, e)),
+            reply => Ok(respond(reply)),
+        }
+    }
+
+    fn add_user(app: &Mutex<App>, body: &Value) -> Result<Response, Response> {
+        let cmd = Command::AddUser {
+            name: field(body, "name")?.to_string(),
+            email: field(body, "email")?.to_string(),
+            password: field(body, "password")?.to_string(),
+        };
+        let reply = App::run_shared(app, &mut Session::new(), cmd);
+        Ok(respond_one(reply, 201))
+    }
+}
@@ -0,0 +1,121 @@
+// Write-ahead journal of changes made since the last save.
+//
+// Every change is appended and synced before it is applied in memory, so a
+// crash between saves loses nothing. The journal's header names the save
+// generation it sits on top of; once a save moves past that generation the
+// entries are already in the data files and the journal is started afresh.
+//
+
+use crate::persist;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+};
+
+const BASE_TAG: &str = "# Base: ";
+
+// One change, holding the record in the same format as the data files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    User(String),
+    Conv(String),
+    Msg(String),
+    Rel(String),
+    Read(String),
+}
+
+impl Entry {
+    fn to_line(&self) -> String {
+        let (tag, record) = match self {
+            Entry::User(r) => ("USER", r),
+            Entry::Conv(r) => ("CONV", r),
+            Entry::Msg(r) => ("MSG", r),
+            Entry::Rel(r) => ("REL", r),
+            Entry::Read(r) => ("READ", r),
+        };
+        format!("{} {}\n", tag, record.trim_end_matches('\n'))
+    }
+
+    fn from_line(line: &str) -> Option<Entry> {
+        let mut parts = line.splitn(2, ' ');
+        let tag = parts.next()?;
+        let record = parts.next()?.to_string();
+        match tag {
+            "USER" => Some(Entry::User(record)),
+            "CONV" => Some(Entry::Conv(record)),
+            "MSG" => Some(Entry::Msg(record)),
+            "REL" => Some(Entry::Rel(record)),
+            "READ" => Some(Entry::Read(record)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    // Opens the journal on top of save generation `gen`, returning the entries
+    // that still need replaying with their line numbers. A half written entry
+    // at the end is dropped.
+    pub fn open(path: &Path, gen: u64) -> io::Result<(Journal, Vec<(usize, Entry)>)> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let base = contents
+            .lines()
+            .find_map(|l| l.strip_prefix(BASE_TAG))
+            .and_then(|b| b.trim().parse::<u64>().ok());
+        let mut entries = Vec::new();
+        if base == Some(gen) {
+            for (n, line) in contents.split_inclusive('\n').enumerate() {
+                if line.starts_with('#') || !line.ends_with('\n') {
+                    continue;
+                }
+                match Entry::from_line(line.trim_end_matches('\n')) {
+                    Some(entry) => entries.push((n + 1, entry)),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("bad entry in {}: {}", path.display(), line.trim_end()),
+                        ))
+                    }
+                }
+            }
+        }
+        // Rewrite what we keep so new entries start on a clean line.
+        let body = entries.iter().map(|(_, e)| e.to_line()).collect::<String>();
+        persist::replace(path, Journal::contents(gen, &body).as_bytes())?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file,
+        };
+        Ok((journal, entries))
+    }
+
+    fn contents(gen: u64, body: &str) -> String {
+        format!(
+            "# Journal of changes since the last save.\n{}{}\n{}",
+            BASE_TAG, gen, body
+        )
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        self.file.write_all(entry.to_line().as_bytes())?;
+        self.file.sync_data()
+    }
+
+    // Empties the journal once save generation `gen` holds everything in it.
+    pub fn reset(&mut self, gen: u64) -> io::Result<()> {
+        persist::replace(&self.path, Journal::contents(gen, "").as_bytes())?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
@@ -2,49 +2,341 @@
 // Started May 03, 2020.
 //
 
+// Modules
+#[macro_use]
+mod log;
+mod auth;
+mod command;
+mod config;
+mod escape;
+mod frame;
+mod http;
+mod journal;
+mod load;
+mod persist;
+mod push;
+mod reply;
+mod shutdown;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod storage;
+mod thread_pool;
+mod websocket;
+
 // Imports
+pub use auth::{Session, MAX_TOKENS, TOKEN_LIFETIME};
 use chrono::{DateTime, Utc};
+use command::MAX_MSG_COUNT;
+pub use command::{
+    Command, ConvQuery, Cursor, FriendAction, MsgQuery, ParseError, Position, UserField,
+};
+pub use config::{Config, Startup, StorageKind};
+use escape::{escape, unescape};
+pub use frame::{write_line, write_sized, FrameError, FrameReader, MAX_FRAME};
+pub use http::{HttpError, HttpServer};
+use journal::Entry;
+pub use load::{LoadError, LoadMode, LoadReport};
+pub use log::{set_log_level, LogLevel};
+use push::Subscriptions;
+pub use push::{Feed, FEED_SIZE};
+pub use reply::{Protocol, Records, Reply};
+use serde_json::json;
+pub use shutdown::{ShutdownHandle, GRACE_PERIOD};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
 use std::{
-    cell::RefCell,
-    fs::{read_to_string, OpenOptions},
-    io,
-    io::prelude::*,
-    net::{SocketAddr, TcpListener},
-    rc::Rc,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::read_to_string,
+    io, mem,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+    thread::{self, JoinHandle as ThreadHandle},
+    time::{Duration, Instant},
 };
+pub use storage::{FlatFileStorage, Storage};
+pub use thread_pool::{JobHandle, PoolStats, ThreadPool, QUEUE_SIZE};
 use uuid::Uuid;
+pub use websocket::{Opcode, WsError, WsFrame, WsServer};
 
 // TCP server.
 
+// A client that hasn't read anything for this long is disconnected.
+pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// Most connections a listener keeps open at once, unless told otherwise.
+pub const MAX_CONNECTIONS: usize = 1024;
+// How long a connection may go without sending anything, unless told
+// otherwise. Subscribers waiting on pushes aren't idle.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
 #[derive(Debug)]
-pub struct TcpServer;
+pub struct TcpServer {
+    listener: Listener,
+}
 
 impl TcpServer {
-    pub fn listen(port: u16, app: &mut App) {
-        let addr = SocketAddr::from(([127, 0, 0, 1], port));
-        let listener = TcpListener::bind(&addr).unwrap();
+    pub fn bind(addr: SocketAddr, threads: usize) -> io::Result<TcpServer> {
+        Ok(TcpServer {
+            listener: Listener::bind(addr, threads)?,
+        })
+    }
+
+    // Stops this server along with every other one using `shutdown`.
+    pub fn with_shutdown(mut self, shutdown: ShutdownHandle) -> io::Result<TcpServer> {
+        self.listener.set_shutdown(shutdown)?;
+        Ok(self)
+    }
+
+    // Refuses connections past `max_conns`, and closes ones idle for `idle`.
+    pub fn with_limits(mut self, max_conns: usize, idle: Duration) -> TcpServer {
+        self.listener.set_limits(max_conns, idle);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.listener.shutdown_handle()
+    }
+
+    // Accepts connections until shut down, running their requests on the pool.
+    pub fn serve(self, app: Arc<Mutex<App>>) {
+        let shutdown = self.shutdown_handle();
+        self.listener
+            .serve(move |stream, pool| TcpServer::handle(stream, pool, &app, &shutdown));
+    }
+
+    // Serves one client for as long as it keeps the connection open.
+    // Replies and pushes share the writer, a whole frame at a time.
+    fn handle(
+        stream: TcpStream,
+        pool: &ThreadPool,
+        app: &Arc<Mutex<App>>,
+        shutdown: &ShutdownHandle,
+    ) {
+        let writer = match stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(s) => Arc::new(Mutex::new(s)),
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        // As set by the listener; lifted while subscribed.
+        let idle = stream.read_timeout().unwrap_or(None);
+        let mut reader = FrameReader::new(stream);
+        let session = Arc::new(Mutex::new(Session::new()));
+        loop {
+            let req = match reader.read_line() {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(FrameError::Io(e)) => {
+                    warn!("{}", e);
+                    break;
+                }
+                Err(e) => {
+                    let resp = format!("MALFORMED FRAME: {}", e);
+                    let written = write_sized(&mut *lock(&writer), &resp);
+                    if written.is_err() || !e.recoverable() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            info!("Request: {}", Command::redact(&req));
+            let resp = run_request(pool, app, &session, req);
+            if let Err(e) = write_sized(&mut *lock(&writer), &resp) {
+                warn!("{}", e);
+                break;
+            }
+            let mut session = lock(&session);
+            // Reading stops, and so this loop, once the shutdown reaches us.
+            if session.ending() {
+                shutdown.stop();
+            }
+            // Started after the reply, so SUBSCRIBED arrives before any push.
+            if let Some(feed) = session.take_feed() {
+                push_feed(feed, Arc::clone(&writer), |stream, frame| {
+                    write_sized(stream, frame)
+                });
+            }
+            set_idle_timeout(reader.get_ref(), &session, idle);
+        }
+        lock(app).unsubscribe(&mut lock(&session));
+    }
+}
+
+// What the TCP, WebSocket and HTTP servers have in common: the socket, the
+// pool their requests run on and the handle that stops them.
+#[derive(Debug)]
+pub(crate) struct Listener {
+    listener: TcpListener,
+    pool: ThreadPool,
+    shutdown: ShutdownHandle,
+    max_conns: usize,
+    idle: Duration,
+}
+
+impl Listener {
+    pub(crate) fn bind(addr: SocketAddr, threads: usize) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr)?;
+        let shutdown = ShutdownHandle::new(GRACE_PERIOD);
+        shutdown.listen(listener.local_addr()?);
+        Ok(Listener {
+            listener,
+            pool: ThreadPool::new(threads)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            shutdown,
+            max_conns: MAX_CONNECTIONS,
+            idle: IDLE_TIMEOUT,
+        })
+    }
+
+    pub(crate) fn set_limits(&mut self, max_conns: usize, idle: Duration) {
+        self.max_conns = max_conns;
+        self.idle = idle;
+    }
+
+    pub(crate) fn set_shutdown(&mut self, shutdown: ShutdownHandle) -> io::Result<()> {
+        shutdown.listen(self.local_addr()?);
+        self.shutdown = shutdown;
+        Ok(())
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub(crate) fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Accepts connections until shut down. Each connection gets a thread of
+    // its own to wait on the client, and hands its requests to the pool, so
+    // a worker is only taken while a request runs and idle connections don't
+    // keep anyone else waiting. Connections past the limit are closed as
+    // soon as they're accepted, and each starts with the idle timeout as its
+    // read timeout.
+    pub(crate) fn serve<F>(self, handle: F)
+    where
+        F: Fn(TcpStream, &ThreadPool) + Send + Sync + 'static,
+    {
+        let Listener {
+            listener,
+            pool,
+            shutdown,
+            max_conns,
+            idle,
+        } = self;
+        let pool = Arc::new(pool);
+        let handle = Arc::new(handle);
+        let mut conns: Vec<ThreadHandle<()>> = Vec::new();
         for stream in listener.incoming() {
-            let mut stream = stream.unwrap();
-            let mut buffer = [0; 512];
-            stream.read(&mut buffer).unwrap();
-            let req = String::from_utf8_lossy(&buffer[..]);
-            if req.starts_with("END") {
+            if shutdown.is_stopping() {
                 break;
-            } else {
-                stream
-                    .write(app.execute(req.to_string()).as_bytes())
-                    .unwrap();
-                stream.flush().unwrap();
             }
-            println!("Request: {}", &req);
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            conns.retain(|t| !t.is_finished());
+            if conns.len() >= max_conns {
+                warn!("Refusing a connection, {} are open", conns.len());
+                continue;
+            }
+            if let Err(e) = stream.set_read_timeout(Some(idle)) {
+                warn!("{}", e);
+                continue;
+            }
+            let conn = match shutdown.track(&stream) {
+                Some(conn) => conn,
+                None => break,
+            };
+            let pool = Arc::clone(&pool);
+            let handle = Arc::clone(&handle);
+            let thread = thread::Builder::new().spawn(move || {
+                handle(stream, &pool);
+                drop(conn);
+            });
+            match thread {
+                Ok(thread) => conns.push(thread),
+                Err(e) => warn!("{}", e),
+            }
         }
+        shutdown.drain(pool, conns);
     }
 }
 
-// Interface-------------------------------------------------------------
-// Command Struct
-pub enum Command {}
+// Waits on the client for at most `idle`, or for as long as it takes while
+// it's subscribed.
+pub(crate) fn set_idle_timeout(stream: &TcpStream, session: &Session, idle: Option<Duration>) {
+    let timeout = match session.subscription() {
+        Some(_) => None,
+        None => idle,
+    };
+    if let Err(e) = stream.set_read_timeout(timeout) {
+        warn!("{}", e);
+    }
+}
+
+// Runs one request as `session` on the pool and waits for the reply.
+pub(crate) fn run_request(
+    pool: &ThreadPool,
+    app: &Arc<Mutex<App>>,
+    session: &Arc<Mutex<Session>>,
+    req: String,
+) -> String {
+    let job = {
+        let app = Arc::clone(app);
+        let session = Arc::clone(session);
+        pool.execute(move || App::execute_shared(&app, &mut lock(&session), req))
+    };
+    match job.and_then(JobHandle::join) {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("{}", e);
+            Reply::Error(e).format(lock(session).protocol())
+        }
+    }
+}
+
+// Locks `mutex` even if a request panicked while holding it. The pool
+// contains the panic and the client gets an error, so the next request
+// shouldn't fail too.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Writes pushes from `feed` with `write` until the subscription ends. Runs on
+// its own thread, as the connection's thread is busy waiting on requests.
+fn push_feed<F>(feed: Feed, writer: Arc<Mutex<TcpStream>>, write: F)
+where
+    F: Fn(&mut TcpStream, &str) -> io::Result<()> + Send + 'static,
+{
+    thread::spawn(move || {
+        while let Some(frame) = feed.next() {
+            let mut stream = lock(&writer);
+            if let Err(e) = write(&mut stream, &frame) {
+                // Part of a frame may have gone out, so the connection
+                // can't be trusted for replies either.
+                warn!("{}", e);
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+        }
+    });
+}
 
+// Interface-------------------------------------------------------------
 fn _get_input() -> String {
     let mut input = String::new();
     io::stdin()
@@ -54,13 +346,71 @@ fn _get_input() -> String {
 }
 
 // System----------------------------------------------------------------
+// Header comments written at the top of each data file.
+const MSGS_HEADER: &str = "# Format of the messages file:
+# UUIDv4_for_msg;text;time_stamp;UUIDv4_for_user;UUIDv4_for_conv;(chat|system);edited_at;deleted_at[;<written_at> <earlier text>]...
+# System messages note membership changes, made by the user they name.
+# Fields from edited_at on are only written once a message is edited or
+# deleted. Earlier versions of an edited message follow, oldest first.
+# Text fields escape backslash, ';' and line breaks as \\\\, \\s, \\n and \\r
+";
+const CONVS_HEADER: &str = "# Format of the conversation file:
+# UUIDv4_for_conv;conv_name;comma_seperated_list_of_UUIDv4_for_members;time_of_creation;time_of_last_message
+# Each member is written as UUIDv4:(owner|admin|member)
+# Text fields escape backslash, ';' and line breaks as \\\\, \\s, \\n and \\r
+";
+const USERS_HEADER: &str = "# Format of the Users file:
+# UUIDv4;Name;Email;Time_of_Creation;Password_hash;(user|admin)
+# Text fields escape backslash, ';' and line breaks as \\\\, \\s, \\n and \\r
+";
+const RELS_HEADER: &str = "# Format of the relationships:
+# UUIDv4_for_mem1;UUIDv4_for_mem2;RelStatus,
+# RelStatus can be of the format:
+# (Neutral,|Pending,UUIDv4_for_mem_that_asked,|Friends,|BestFriends,|Blocked,UUIDv4_for_mem_that_blocked_first)
+";
+const READS_HEADER: &str = "# Format of the read markers:
+# UUIDv4_for_user;UUIDv4_for_conv;UUIDv4_for_last_msg_read;time_marked
+# The user has read every message in the conv up to and including that one.
+";
+
+// The reply to anything the logged in user isn't allowed to see or do.
+const DENIED: &str = "PERMISSION DENIED";
+
+fn display_name(user: &User) -> String {
+    user.read().unwrap().name().to_string()
+}
+
 #[derive(Debug)]
 pub struct App {
     users: Vec<User>,
     convs: Vec<Conversation>,
-    msgs: Vec<Message>,
+    // Every message in order, so a time range needn't scan them all.
+    msgs: BTreeMap<MsgKey, Message>,
     rels: Vec<Relationship>,
     start: DateTime<Utc>,
+    // Lookups by id, so they don't scan the vectors above.
+    user_ids: HashMap<Uuid, User>,
+    conv_ids: HashMap<Uuid, Conversation>,
+    msg_ids: HashMap<Uuid, Message>,
+    // Each conversation's messages in order, so its history can be paged
+    // through without scanning every message.
+    conv_msgs: HashMap<Uuid, BTreeMap<MsgKey, Message>>,
+    // The same for each user's messages.
+    user_msgs: HashMap<Uuid, BTreeMap<MsgKey, Message>>,
+    // How far each user has read each conversation, keyed by (user, conv).
+    reads: HashMap<(Uuid, Uuid), ReadMarker>,
+    storage: Option<Box<dyn Storage>>,
+    // Session tokens handed out by LOGIN, the user each one is for and when
+    // it was handed out.
+    sessions: HashMap<String, (Uuid, Instant)>,
+    token_lifetime: Duration,
+    subscriptions: Subscriptions,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App::new()
+    }
 }
 
 impl App {
@@ -68,340 +418,1250 @@ impl App {
         App {
             users: Vec::new(),
             convs: Vec::new(),
-            msgs: Vec::new(),
+            msgs: BTreeMap::new(),
             rels: Vec::new(),
             start: Utc::now(),
+            user_ids: HashMap::new(),
+            conv_ids: HashMap::new(),
+            msg_ids: HashMap::new(),
+            conv_msgs: HashMap::new(),
+            user_msgs: HashMap::new(),
+            reads: HashMap::new(),
+            storage: None,
+            sessions: HashMap::new(),
+            token_lifetime: TOKEN_LIFETIME,
+            subscriptions: Subscriptions::default(),
+        }
+    }
+
+    // Loads everything from `storage`, which then keeps every later change.
+    // In lenient mode the report lists the records that were skipped.
+    pub fn open(
+        mut storage: Box<dyn Storage>,
+        mode: LoadMode,
+    ) -> Result<(App, LoadReport), LoadError> {
+        let mut app = App::new();
+        let report = storage.load(&mut app, mode)?;
+        app.storage = Some(storage);
+        Ok((app, report))
+    }
+
+    pub fn start_time(&self) -> DateTime<Utc> {
+        self.start
+    }
+
+    // Runs one request as whoever `session` is logged in as, replying in the
+    // session's protocol.
+    pub fn execute(&mut self, session: &mut Session, req: String) -> String {
+        let reply = match Command::parse(&req) {
+            Ok(cmd) => self.run(session, cmd),
+            Err(e) => Reply::BadRequest(e),
+        };
+        reply.format(session.protocol())
+    }
+
+    // Like execute, for an app shared between connections. See run_shared.
+    pub fn execute_shared(app: &Mutex<App>, session: &mut Session, req: String) -> String {
+        let reply = match Command::parse(&req) {
+            Ok(cmd) => App::run_shared(app, session, cmd),
+            Err(e) => Reply::BadRequest(e),
+        };
+        reply.format(session.protocol())
+    }
+
+    // Like run, for an app shared between connections. Hashing a password
+    // is slow on purpose, so ADD USER and LOGIN do it with the app unlocked
+    // rather than holding up every other connection.
+    pub fn run_shared(app: &Mutex<App>, session: &mut Session, cmd: Command) -> Reply {
+        match cmd {
+            Command::AddUser {
+                name,
+                email,
+                password,
+            } => {
+                let new = lock(app).new_user(&name, &email);
+                let hashed = new.and_then(|mut user| user.set_password(&password).map(|_| user));
+                match hashed.and_then(|user| lock(app).add_registered(user)) {
+                    Ok(user) => Reply::Users(vec![user]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::Login { email, password } => {
+                let stored = lock(app).stored_password(&email);
+                let checked = stored.filter(|(_, hash)| auth::verify_password(hash, &password));
+                let mut app = lock(app);
+                match checked.and_then(|(user, hash)| app.start_session(&user, &hash)) {
+                    Some((id, token)) => app.log_in(session, id, token),
+                    None => Reply::from_err("Wrong email or password!"),
+                }
+            }
+            cmd => lock(app).run(session, cmd),
+        }
+    }
+
+    pub fn run(&mut self, session: &mut Session, cmd: Command) -> Reply {
+        match cmd {
+            Command::AddUser {
+                name,
+                email,
+                password,
+            } => match self.register(&name, &email, &password) {
+                Ok(user) => Reply::Users(vec![user]),
+                Err(e) => Reply::from_err(e),
+            },
+            Command::Login { email, password } => match self.login(&email, &password) {
+                Ok((user, token)) => {
+                    let id = user.read().unwrap().id();
+                    self.log_in(session, id, token)
+                }
+                Err(e) => Reply::from_err(e),
+            },
+            Command::LoginToken(token) => match self.token_user(&token) {
+                Some(id) => self.log_in(session, id, token),
+                None => Reply::Unauthorized("INVALID TOKEN"),
+            },
+            Command::Logout => {
+                self.end_session(session);
+                Reply::Done("LOGGED OUT")
+            }
+            Command::Protocol(protocol) => {
+                self.set_protocol(session, protocol);
+                Reply::Done(match protocol {
+                    Protocol::Text => "PROTOCOL TEXT",
+                    Protocol::Json => "PROTOCOL JSON",
+                })
+            }
+            Command::Subscribe(conv) => match self.session_user(session) {
+                Some(me) => self.subscribe(&me, session, conv),
+                None => Reply::Unauthorized("NOT LOGGED IN"),
+            },
+            Command::Unsubscribe => match self.session_user(session) {
+                Some(_) => {
+                    self.unsubscribe(session);
+                    Reply::Done("UNSUBSCRIBED")
+                }
+                None => Reply::Unauthorized("NOT LOGGED IN"),
+            },
+            Command::End => match self.session_user(session) {
+                Some(me) if me.read().unwrap().is_admin() => {
+                    info!("Shutdown asked for by {}.", me.read().unwrap().id());
+                    session.end();
+                    Reply::Done("SHUTTING DOWN")
+                }
+                Some(_) => Reply::Denied,
+                None => Reply::Unauthorized("NOT LOGGED IN"),
+            },
+            cmd => match self.session_user(session) {
+                Some(me) => self.run_as(me, cmd),
+                None => Reply::Unauthorized("NOT LOGGED IN"),
+            },
+        }
+    }
+
+    // The user a session is logged in as, if its token hasn't been ended.
+    fn session_user(&self, session: &Session) -> Option<User> {
+        let id = self.token_user(session.token()?)?;
+        if session.user() != Some(id) {
+            return None;
+        }
+        self.user_ids.get(&id).map(User::clone)
+    }
+
+    // The user a token is for, if it hasn't been ended or expired.
+    fn token_user(&self, token: &str) -> Option<Uuid> {
+        match self.sessions.get(token) {
+            Some((id, issued)) if issued.elapsed() < self.token_lifetime => Some(*id),
+            _ => None,
         }
     }
 
-    pub fn execute(&mut self, req: String) -> String {
-        let mut req = req.split(" ");
-        match req.next().unwrap() {
-            "GET" => match req.next().unwrap() {
-                "USER" => {
-                    let mut mult = false;
-                    let option = match req.next() {
-                        Some(o) => {
-                            if !["ID", "NAME", "EMAIL", "MULT"].contains(&o) {
-                                return String::from("INVALID GET USER <_> COMMAND");
+    // Runs a command that needs a logged in user.
+    fn run_as(&mut self, me: User, cmd: Command) -> Reply {
+        match cmd {
+            Command::GetUser {
+                field,
+                search,
+                mult,
+            } => {
+                let found = match field {
+                    UserField::Id => self.get_user("ID", &search).map(|u| vec![u]),
+                    _ => self.get_user_mult(field.as_str(), &search.to_lowercase()),
+                };
+                // Nobody finds a user who has blocked them.
+                let me_id = me.read().unwrap().id();
+                let mut users: Vec<User> = found
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|u| !self.has_blocked(u.read().unwrap().id(), me_id))
+                    .collect();
+                if !mult {
+                    users.truncate(1);
+                }
+                if users.is_empty() {
+                    return Reply::Empty(
+                        Records::Users,
+                        if mult {
+                            "NO USERS FOUND"
+                        } else {
+                            "NO USER FOUND"
+                        },
+                    );
+                }
+                Reply::Users(users)
+            }
+            Command::GetConv { query, mult } => {
+                let search = match query {
+                    ConvQuery::Name(name) => ConvSearch::Name(name),
+                    ConvQuery::Members(ids) => {
+                        let mut users = Vec::new();
+                        for id in ids {
+                            match self.get_user("ID", &id.to_string()) {
+                                Some(u) => users.push(u),
+                                None => return Reply::NotFound("INVALID USER PROVIDED"),
                             }
-                            if o == "MULT" {
-                                mult = true;
-                                match req.next() {
-                                    Some(opt) => opt,
-                                    None => {
-                                        return String::from("NO OPTION OR SEARCH TERM PROVIDED")
-                                    }
+                        }
+                        ConvSearch::Members(users)
+                    }
+                };
+                let found = self.get_conv_mult(search);
+                match App::readable(&me, found, Conversation::clone) {
+                    Some(mut convs) => {
+                        if !mult {
+                            convs.truncate(1);
+                        }
+                        Reply::Convs(convs)
+                    }
+                    None => Reply::Empty(Records::Convs, "NO CONVS FOUND"),
+                }
+            }
+            Command::GetRel(id1, id2) => {
+                let user1 = match self.get_user("ID", &id1.to_string()) {
+                    Some(u) => u,
+                    None => return Reply::NotFound("INVALID USER PROVIDED"),
+                };
+                let user2 = match self.get_user("ID", &id2.to_string()) {
+                    Some(u) => u,
+                    None => return Reply::NotFound("INVALID USER PROVIDED"),
+                };
+                // Only the pair themselves and admins see where they stand.
+                let mine = Arc::ptr_eq(&me, &user1) || Arc::ptr_eq(&me, &user2);
+                if !mine && !me.read().unwrap().is_admin() {
+                    return Reply::Denied;
+                }
+                Reply::Rel(self.get_rel_status(&user1, &user2))
+            }
+            Command::GetMsg(query) => {
+                let search = match query {
+                    MsgQuery::Id(id) => {
+                        return match self.get_msg(id) {
+                            Some(msg) => {
+                                if App::can_read(&me, &msg.read().unwrap().conv) {
+                                    Reply::Msgs(vec![msg])
+                                } else {
+                                    Reply::Denied
                                 }
-                            } else {
-                                o
                             }
+                            None => Reply::Empty(Records::Msgs, "NO MSG FOUND"),
+                        }
+                    }
+                    MsgQuery::Conv { conv, newest } => match self.get_conv(ConvSearch::Id(conv)) {
+                        Some(c) if App::can_read(&me, &c) => MsgSearch {
+                            scope: MsgScope::Conv(c),
+                            range: (Bound::Unbounded, Bound::Unbounded),
+                            limit: newest,
+                            oldest: false,
+                        },
+                        Some(_) => return Reply::Denied,
+                        None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                    },
+                    MsgQuery::Page {
+                        conv,
+                        cursor,
+                        limit,
+                    } => {
+                        let conv = match self.get_conv(ConvSearch::Id(conv)) {
+                            Some(c) if App::can_read(&me, &c) => c,
+                            Some(_) => return Reply::Denied,
+                            None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                        };
+                        let (range, oldest) = match self.page(&me, Some(cursor), Some(&conv)) {
+                            Ok(page) => page,
+                            Err(reply) => return reply,
+                        };
+                        MsgSearch {
+                            scope: MsgScope::Conv(conv),
+                            range,
+                            limit,
+                            oldest,
                         }
-                        None => return String::from("NO OPTION PROVIDED"),
-                    };
-                    let search = match req.next() {
-                        Some(s) => s,
-                        None => return String::from("NO SEARCH TERM PROVIDED"),
-                    };
-                    if mult {
-                        let users = self.get_user_mult(option, search.trim());
-                        let users = match users {
-                            Some(usrs) => usrs,
-                            None => return String::from("NO USERS FOUND"),
+                    }
+                    MsgQuery::Author {
+                        user,
+                        cursor,
+                        limit,
+                    } => {
+                        let user = match self.get_user("ID", &user.to_string()) {
+                            Some(u) => u,
+                            None => return Reply::NotFound("INVALID USER PROVIDED"),
+                        };
+                        let (range, oldest) = match self.page(&me, cursor, None) {
+                            Ok(page) => page,
+                            Err(reply) => return reply,
                         };
-                        users.iter().fold(String::new(), |acc, u| {
-                            let u = u.borrow();
-                            format!("{}{}", acc, u.to_string())
-                        })
-                    } else {
-                        let user = self.get_user(option, search.trim());
-                        let user = match user {
-                            Some(usr) => usr,
-                            None => return String::from("NO USER FOUND"),
+                        MsgSearch {
+                            scope: MsgScope::Author(user),
+                            range,
+                            limit,
+                            oldest,
+                        }
+                    }
+                    MsgQuery::Time {
+                        from,
+                        to,
+                        cursor,
+                        limit,
+                    } => {
+                        let (range, oldest) = match self.page(&me, cursor, None) {
+                            Ok(page) => page,
+                            Err(reply) => return reply,
                         };
-                        let user = user.borrow();
-                        user.to_string()
+                        let window = (
+                            Bound::Included((from, Uuid::nil())),
+                            Bound::Included((to, Uuid::from_u128(u128::MAX))),
+                        );
+                        match overlap(range, window) {
+                            Some(range) => MsgSearch {
+                                scope: MsgScope::Time,
+                                range,
+                                limit,
+                                oldest,
+                            },
+                            None => return Reply::Empty(Records::Msgs, "NO MSGS FOUND"),
+                        }
+                    }
+                };
+                match self.get_msg_mult(&me, search) {
+                    Some(msgs) => Reply::Msgs(msgs),
+                    None => Reply::Empty(Records::Msgs, "NO MSGS FOUND"),
+                }
+            }
+            Command::AddConv { name, members } => {
+                let mut users = Vec::new();
+                for id in members {
+                    match self.get_user("ID", &id.to_string()) {
+                        Some(u) => users.push(u),
+                        None => return Reply::NotFound("INVALID USER PROVIDED"),
                     }
                 }
+                // Whoever starts a conversation owns it.
+                if let Some(i) = users.iter().position(|u| Arc::ptr_eq(u, &me)) {
+                    users.remove(i);
+                }
+                users.insert(0, me);
+                match self.add_conv(&name, users) {
+                    Ok(conv) => Reply::Convs(vec![conv]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::SendMsg { conv, text } => {
+                let conv = match self.get_conv(ConvSearch::Id(conv)) {
+                    Some(c) if App::can_post(&me, &c) => c,
+                    Some(_) => return Reply::Denied,
+                    None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                };
+                match self.send_msg(me, conv, &text) {
+                    Ok(msg) => Reply::Msgs(vec![msg]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::EditMsg { msg, text } => {
+                let msg = match self.get_msg(msg) {
+                    Some(m) => m,
+                    None => return Reply::NotFound("INVALID MSG PROVIDED"),
+                };
+                match self.edit_msg(&me, &msg, &text) {
+                    Ok(()) => Reply::Msgs(vec![msg]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::DeleteMsg(msg) => {
+                let msg = match self.get_msg(msg) {
+                    Some(m) => m,
+                    None => return Reply::NotFound("INVALID MSG PROVIDED"),
+                };
+                match self.delete_msg(&me, &msg) {
+                    Ok(()) => Reply::Msgs(vec![msg]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::MarkRead { conv, msg } => {
+                let conv = match self.get_conv(ConvSearch::Id(conv)) {
+                    Some(c) => c,
+                    None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                };
+                let msg = match self.get_msg(msg) {
+                    Some(m) => m,
+                    None => return Reply::NotFound("INVALID MSG PROVIDED"),
+                };
+                match self.mark_read(&me, &conv, &msg) {
+                    Ok(marker) => Reply::Reads(vec![marker]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::GetReads(conv) => {
+                let conv = match self.get_conv(ConvSearch::Id(conv)) {
+                    Some(c) if App::can_read(&me, &c) => c,
+                    Some(_) => return Reply::Denied,
+                    None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                };
+                let reads = self.get_reads(&conv);
+                if reads.is_empty() {
+                    return Reply::Empty(Records::Reads, "NO READS FOUND");
+                }
+                Reply::Reads(reads)
+            }
+            Command::GetUnread => {
+                let unread: Vec<(Conversation, usize)> = self
+                    .convs
+                    .iter()
+                    .filter(|c| c.read().unwrap().has_member(&me))
+                    .map(|c| (Conversation::clone(c), self.unread(&me, c)))
+                    .collect();
+                if unread.is_empty() {
+                    return Reply::Empty(Records::Unread, "NO CONVS FOUND");
+                }
+                Reply::Unread(unread)
+            }
+            Command::AddMember { conv, user }
+            | Command::RemoveMember { conv, user }
+            | Command::SetOwner { conv, user }
+            | Command::SetRole { conv, user, .. } => {
+                let conv = match self.get_conv(ConvSearch::Id(conv)) {
+                    Some(c) => c,
+                    None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                };
+                let user = match self.get_user("ID", &user.to_string()) {
+                    Some(u) => u,
+                    None => return Reply::NotFound("INVALID USER PROVIDED"),
+                };
+                let result = match cmd {
+                    Command::AddMember { .. } => self.add_member(&me, &conv, &user),
+                    Command::RemoveMember { .. } => self.remove_member(&me, &conv, &user),
+                    Command::SetOwner { .. } => self.set_owner(&me, &conv, &user),
+                    Command::SetRole { role, .. } => self.set_role(&me, &conv, &user, role),
+                    _ => unreachable!(),
+                };
+                match result {
+                    Ok(()) => Reply::Convs(vec![conv]),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::LeaveConv(conv) => {
+                let conv = match self.get_conv(ConvSearch::Id(conv)) {
+                    Some(c) => c,
+                    None => return Reply::NotFound("INVALID CONV PROVIDED"),
+                };
+                match self.leave_conv(&me, &conv) {
+                    Ok(()) => Reply::Done("LEFT CONV"),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::Block(id) | Command::Unblock(id) => {
+                let user = match self.get_user("ID", &id.to_string()) {
+                    Some(u) => u,
+                    None => return Reply::NotFound("INVALID USER PROVIDED"),
+                };
+                let result = match cmd {
+                    Command::Block(_) => self.block(&me, &user),
+                    _ => self.unblock(&me, &user),
+                };
+                match result {
+                    Ok(status) => Reply::Rel(status),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::Friend(action, id) => {
+                let user = match self.get_user("ID", &id.to_string()) {
+                    Some(u) => u,
+                    None => return Reply::NotFound("INVALID USER PROVIDED"),
+                };
+                match self.befriend(&me, &user, action) {
+                    Ok(status) => Reply::Rel(status),
+                    Err(e) => Reply::from_err(e),
+                }
+            }
+            Command::AddUser { .. }
+            | Command::Login { .. }
+            | Command::LoginToken(_)
+            | Command::Logout
+            | Command::Protocol(_)
+            | Command::Subscribe(_)
+            | Command::Unsubscribe
+            | Command::End => unreachable!("handled by App::run"),
+        }
+    }
+
+    // Whether `me` may see a conversation's details, members and messages.
+    // Admins can see every conversation.
+    fn can_read(me: &User, conv: &Conversation) -> bool {
+        me.read().unwrap().is_admin() || conv.read().unwrap().has_member(me)
+    }
+
+    // Only members post, admins included.
+    fn can_post(me: &User, conv: &Conversation) -> bool {
+        conv.read().unwrap().has_member(me)
+    }
+
+    // Keeps the results `me` may read. Finding none readable looks the same
+    // as finding nothing, so a search doesn't give away what's hidden.
+    fn readable<T, F>(me: &User, found: Option<Vec<T>>, conv_of: F) -> Option<Vec<T>>
+    where
+        F: Fn(&T) -> Conversation,
+    {
+        let readable: Vec<T> = found?
+            .into_iter()
+            .filter(|t| App::can_read(me, &conv_of(t)))
+            .collect();
+        if readable.is_empty() {
+            None
+        } else {
+            Some(readable)
+        }
+    }
 
-                "CONV" => {
-                    let option = req.next().unwrap_or("NONE");
-                    let search = req.next().unwrap_or("NONE");
-                    let extra = req.next().unwrap_or("NONE");
-                    if option == "NONE" || search == "NONE" {
-                        return String::from("INCOMPLETE COMMAND");
+    // The part of the history `cursor` points to, and whether the page is
+    // taken from its oldest end. Without a cursor it's the newest of
+    // everything. A message as the cursor has to be one `me` can read, and in
+    // `conv` if there is one.
+    fn page(
+        &self,
+        me: &User,
+        cursor: Option<Cursor>,
+        conv: Option<&Conversation>,
+    ) -> Result<(KeyRange, bool), Reply> {
+        let (at, after) = match cursor {
+            Some(Cursor::Before(at)) => (at, false),
+            Some(Cursor::After(at)) => (at, true),
+            None => return Ok(((Bound::Unbounded, Bound::Unbounded), false)),
+        };
+        // Times fall between the messages either side of them, so nothing
+        // sent at that time is on the page.
+        let key = match at {
+            Position::Msg(id) => match self.get_msg(id) {
+                Some(m) => {
+                    let m = m.read().unwrap();
+                    if conv.is_some_and(|c| !Arc::ptr_eq(&m.conv, c)) {
+                        return Err(Reply::Error("That message isn't in that conv."));
                     }
-                    match option {
-                        "NAME" => {
-                            let search = ConvSearch::Name(String::from(search.trim()));
-                            let result = match self.get_conv(search) {
-                                Some(c) => c,
-                                None => return String::from("NO CONVS FOUND"),
-                            };
-                            let result = result.borrow();
-                            result.to_string()
-                        }
-                        "MEMBERS" => {
-                            let mut search: Vec<&str> =
-                                search.split(|c| c == ',' || c == '\n').collect();
-                            search.pop();
-                            if search.len() == 0 {
-                                return String::from("NO VALID USERS PROVIDED");
-                            }
-                            let search: Vec<Option<User>> =
-                                search.iter().map(|id| self.get_user("ID", id)).collect();
-                            if search.contains(&None) {
-                                return String::from("INVALID USER PROVIDED");
-                            }
-                            let search = search
-                                .iter()
-                                .map(|u| User::clone(u.as_ref().unwrap()))
-                                .collect();
-                            let search = ConvSearch::Members(search);
-                            let result = match self.get_conv(search) {
-                                Some(c) => c,
-                                None => return String::from("NO CONVS FOUND"),
-                            };
-                            let result = result.borrow();
-                            result.to_string()
-                        }
-                        "MULT" => match search {
-                            "NAME" => {
-                                let extra = ConvSearch::Name(String::from(extra.trim()));
-                                let result = match self.get_conv_mult(extra) {
-                                    Some(c) => c,
-                                    None => return String::from("NO CONVS FOUND"),
-                                };
-                                result.iter().fold(String::new(), |acc, c| {
-                                    let c = c.borrow();
-                                    format!("{}{}", acc, c.to_string())
-                                })
-                            }
-                            "MEMBERS" => {
-                                let mut extra: Vec<&str> =
-                                    extra.split(|c| c == ',' || c == '\n').collect();
-                                extra.pop();
-                                if extra.len() == 0 {
-                                    return String::from("NO VALID USERS PROVIDED");
-                                }
-                                let extra: Vec<Option<User>> =
-                                    extra.iter().map(|id| self.get_user("ID", id)).collect();
-                                if extra.contains(&None) {
-                                    return String::from("INVALID USER PROVIDED");
-                                }
-                                let extra = extra
-                                    .iter()
-                                    .map(|u| User::clone(u.as_ref().unwrap()))
-                                    .collect();
-                                let extra = ConvSearch::Members(extra);
-                                let result = match self.get_conv_mult(extra) {
-                                    Some(c) => c,
-                                    None => return String::from("NO CONVS FOUND"),
-                                };
-                                result.iter().fold(String::new(), |acc, c| {
-                                    let c = c.borrow();
-                                    format!("{}{}", acc, c.to_string())
-                                })
-                            }
-                            _ => return String::from("INCOMPLETE GET CONV MULT <_> COMMAND"),
-                        },
-                        _ => String::new(),
+                    if !App::can_read(me, &m.conv) {
+                        return Err(Reply::NotFound("INVALID MSG PROVIDED"));
                     }
+                    m.key()
                 }
-                "REL" => String::new(),
-                "MSG" => String::new(),
-                _ => panic!(),
+                None => return Err(Reply::NotFound("INVALID MSG PROVIDED")),
             },
+            Position::Time(t) if after => (t, Uuid::from_u128(u128::MAX)),
+            Position::Time(t) => (t, Uuid::nil()),
+        };
+        let range = if after {
+            (Bound::Excluded(key), Bound::Unbounded)
+        } else {
+            (Bound::Unbounded, Bound::Excluded(key))
+        };
+        Ok((range, after))
+    }
 
-            _ => String::from("NOT GET"),
+    // Feeds each record line of `filename` to `load`. Bad records stop the
+    // load or are skipped into the report, depending on `mode`.
+    fn load_file<F>(
+        &mut self,
+        filename: &str,
+        mode: LoadMode,
+        mut load: F,
+    ) -> Result<LoadReport, LoadError>
+    where
+        F: FnMut(&mut App, &str) -> Result<(), LoadError>,
+    {
+        let contents =
+            read_to_string(filename).map_err(|e| LoadError::file(filename, e.to_string()))?;
+        let mut report = LoadReport::default();
+        for (n, line) in contents.lines().enumerate() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if let Err(e) = load(self, line) {
+                report.skip(mode, e.at(filename, n + 1))?;
+            }
         }
+        Ok(report)
     }
 
-    pub fn load_rels(&mut self, filename: &str) -> Result<(), &'static str> {
-        for line in read_to_string(filename).unwrap().lines() {
-            if line.starts_with("#") {
-                continue;
+    pub fn load_rels(&mut self, filename: &str, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        self.load_file(filename, mode, |app, line| {
+            let rel = App::parse_rel(line)?;
+            app.insert_rel(rel);
+            Ok(())
+        })
+    }
+
+    pub fn load_users(&mut self, filename: &str, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        self.load_file(filename, mode, |app, line| {
+            let user = App::parse_user(line)?;
+            app.insert_user(user);
+            Ok(())
+        })
+    }
+
+    pub fn load_convs(&mut self, filename: &str, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        self.load_file(filename, mode, |app, line| {
+            let conv = app.parse_conv(line)?;
+            app.insert_conv(conv);
+            Ok(())
+        })
+    }
+
+    pub fn load_msgs(&mut self, filename: &str, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        self.load_file(filename, mode, |app, line| {
+            let msg = app.parse_msg(line)?;
+            app.insert_msg(msg);
+            Ok(())
+        })
+    }
+
+    // Data dirs saved before read markers existed have no reads file.
+    pub fn load_reads(&mut self, filename: &str, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        if !Path::new(filename).exists() {
+            return Ok(LoadReport::default());
+        }
+        self.load_file(filename, mode, |app, line| {
+            let read = app.parse_read(line)?;
+            app.insert_read(read);
+            Ok(())
+        })
+    }
+
+    fn parse_rel(line: &str) -> Result<Relationship, LoadError> {
+        let mut line = line.split(';');
+        let mem1 = load::parse_uuid("mem1", load::next_field(&mut line, "mem1")?)?;
+        let mem2 = load::parse_uuid("mem2", load::next_field(&mut line, "mem2")?)?;
+        let status = RelStatus::from_str(load::next_field(&mut line, "status")?)?;
+        Ok(Relationship::new([mem1, mem2], Some(status)))
+    }
+
+    fn parse_user(line: &str) -> Result<UserInfo, LoadError> {
+        let mut line = line.split(';');
+        let id = load::parse_uuid("id", load::next_field(&mut line, "id")?)?;
+        let name = unescape(load::next_field(&mut line, "name")?);
+        let email = unescape(load::next_field(&mut line, "email")?);
+        let create_time =
+            load::parse_time("create_time", load::next_field(&mut line, "create_time")?)?;
+        // Files written before passwords existed have no hash field.
+        let password = line.next().filter(|p| !p.is_empty()).map(String::from);
+        let admin = match line.next() {
+            Some("admin") => true,
+            Some("user") | Some("") | None => false,
+            Some(role) => return Err(LoadError::field("role", format!("unknown role '{}'", role))),
+        };
+        let mut user = UserInfo::load(id, &name, &email, create_time, password);
+        user.admin = admin;
+        Ok(user)
+    }
+
+    fn parse_conv(&self, line: &str) -> Result<ConvInfo, LoadError> {
+        let mut line = line.split(';');
+        let id = load::parse_uuid("id", load::next_field(&mut line, "id")?)?;
+        let name = unescape(load::next_field(&mut line, "name")?);
+        let mems = load::next_field(&mut line, "members")?;
+        let start = load::parse_time("start", load::next_field(&mut line, "start")?)?;
+        let last_msg = load::parse_time("last_msg", load::next_field(&mut line, "last_msg")?)?;
+        let mut members = Vec::new();
+        let mut roles = HashMap::new();
+        for mem in mems.split(',') {
+            let mut mem = mem.splitn(2, ':');
+            let id = load::parse_uuid("members", mem.next().unwrap_or_default())?;
+            match self.user_ids.get(&id) {
+                Some(u) => members.push(User::clone(u)),
+                None => return Err(LoadError::field("members", format!("unknown user {}", id))),
             }
-            let mut line = line.split(';');
-            let mem1 = match line.next() {
-                Some(id) => Uuid::parse_str(id).unwrap(),
-                None => return Err("Invalid $mem1 in RELS file."),
-            };
-            let mem2 = match line.next() {
-                Some(id) => Uuid::parse_str(id).unwrap(),
-                None => return Err("Invalid $mem2 in RELS file."),
+            let role = match mem.next() {
+                Some(role) => Role::from_str(role)?,
+                None => Role::Member,
             };
-            let status = match line.next() {
-                Some(stat) => RelStatus::from_str(stat),
-                None => return Err("Invalid $status in RELS file."),
-            };
-            self.rels.push(Relationship::new([mem1, mem2], status));
+            roles.insert(id, role);
         }
-        Ok(())
+        // Files from before roles existed have no owner; give it to the
+        // first member.
+        if !roles.values().any(|r| *r == Role::Owner) {
+            if let Some(first) = members.first() {
+                roles.insert(first.read().unwrap().id(), Role::Owner);
+            }
+        }
+        Ok(ConvInfo::load(id, &name, members, roles, start, last_msg))
     }
 
-    pub fn load_users(&mut self, filename: &str) -> Result<(), &'static str> {
-        for line in read_to_string(filename).unwrap().lines() {
-            if line.starts_with("#") {
-                continue;
+    fn parse_msg(&self, line: &str) -> Result<MsgInfo, LoadError> {
+        let mut line = line.split(';');
+        let id = load::parse_uuid("id", load::next_field(&mut line, "id")?)?;
+        let text = unescape(load::next_field(&mut line, "text")?);
+        let time_stamp =
+            load::parse_time("time_stamp", load::next_field(&mut line, "time_stamp")?)?;
+        let user_id = load::parse_uuid("user", load::next_field(&mut line, "user")?)?;
+        let user = match self.user_ids.get(&user_id) {
+            Some(u) => User::clone(u),
+            None => {
+                return Err(LoadError::field(
+                    "user",
+                    format!("unknown user {}", user_id),
+                ))
             }
-            let mut line = line.split(';');
-            let id = match line.next() {
-                Some(id) => Uuid::parse_str(id).unwrap(),
-                None => return Err("Invalid $id in USERS file."),
-            };
-            let user = match line.next() {
-                Some(u) => u,
-                None => return Err("Invalid $name in USERS file."),
-            };
-            let email = match line.next() {
-                Some(e) => e,
-                None => return Err("Invalid $email in USERS file."),
-            };
-            let create_time: DateTime<Utc> = match line.next() {
-                Some(ct) => DateTime::from(DateTime::parse_from_rfc3339(ct).unwrap()),
-                None => return Err("Invalid $create_time in USERS file."),
-            };
-            let new_user = User::new(RefCell::new(UserInfo::load(id, user, email, create_time)));
-            self.users.push(new_user);
+        };
+        let conv_id = load::parse_uuid("conv", load::next_field(&mut line, "conv")?)?;
+        let conv = match self.conv_ids.get(&conv_id) {
+            Some(c) => Conversation::clone(c),
+            None => {
+                return Err(LoadError::field(
+                    "conv",
+                    format!("unknown conv {}", conv_id),
+                ))
+            }
+        };
+        let system = match line.next() {
+            Some("system") => true,
+            Some("chat") | Some("") | None => false,
+            Some(kind) => {
+                return Err(LoadError::field(
+                    "kind",
+                    format!("unknown message kind '{}'", kind),
+                ))
+            }
+        };
+        // Files from before edits existed end here.
+        let edited = App::parse_optional_time("edited", line.next())?;
+        let deleted = App::parse_optional_time("deleted", line.next())?;
+        let mut history = Vec::new();
+        for version in line {
+            let (time, text) = version.split_once(' ').unwrap_or((version, ""));
+            history.push(MsgVersion {
+                text: unescape(text),
+                time_stamp: load::parse_time("history", time)?,
+            });
         }
-        Ok(())
+        let mut msg = MsgInfo::load(id, text, time_stamp, user, conv);
+        msg.system = system;
+        msg.edited = edited;
+        msg.deleted = deleted;
+        msg.history = history;
+        Ok(msg)
     }
 
-    pub fn load_convs(&mut self, filename: &str) -> Result<(), &'static str> {
-        for line in read_to_string(filename).unwrap().lines() {
-            if line.starts_with("#") {
-                continue;
+    fn parse_read(&self, line: &str) -> Result<ReadMarker, LoadError> {
+        let mut line = line.split(';');
+        let user = load::parse_uuid("user", load::next_field(&mut line, "user")?)?;
+        if !self.user_ids.contains_key(&user) {
+            return Err(LoadError::field("user", format!("unknown user {}", user)));
+        }
+        let conv = load::parse_uuid("conv", load::next_field(&mut line, "conv")?)?;
+        if !self.conv_ids.contains_key(&conv) {
+            return Err(LoadError::field("conv", format!("unknown conv {}", conv)));
+        }
+        let msg = load::parse_uuid("msg", load::next_field(&mut line, "msg")?)?;
+        if !self.msg_ids.contains_key(&msg) {
+            return Err(LoadError::field("msg", format!("unknown msg {}", msg)));
+        }
+        let time = load::parse_time("time", load::next_field(&mut line, "time")?)?;
+        Ok(ReadMarker {
+            user,
+            conv,
+            msg,
+            time,
+        })
+    }
+
+    fn parse_optional_time(
+        field: &'static str,
+        input: Option<&str>,
+    ) -> Result<Option<DateTime<Utc>>, LoadError> {
+        match input {
+            Some("") | None => Ok(None),
+            Some(time) => load::parse_time(field, time).map(Some),
+        }
+    }
+
+    // Adds a user, or updates the one with the same id in place.
+    fn insert_user(&mut self, user: UserInfo) -> User {
+        if let Some(u) = self.user_ids.get(&user.id()) {
+            *u.write().unwrap() = user;
+            return User::clone(u);
+        }
+        let user = User::new(RwLock::new(user));
+        self.user_ids
+            .insert(user.read().unwrap().id(), User::clone(&user));
+        self.users.push(User::clone(&user));
+        user
+    }
+
+    fn insert_conv(&mut self, conv: ConvInfo) -> Conversation {
+        if let Some(c) = self.conv_ids.get(&conv.id()) {
+            *c.write().unwrap() = conv;
+            return Conversation::clone(c);
+        }
+        let conv = Conversation::new(RwLock::new(conv));
+        self.conv_ids
+            .insert(conv.read().unwrap().id(), Conversation::clone(&conv));
+        self.convs.push(Conversation::clone(&conv));
+        conv
+    }
+
+    fn insert_msg(&mut self, msg: MsgInfo) -> Message {
+        if let Some(m) = self.msg_ids.get(&msg.id).map(Message::clone) {
+            self.unindex_msg(&m.read().unwrap());
+            *m.write().unwrap() = msg;
+            self.index_msg(&m);
+            return m;
+        }
+        let msg = Message::new(RwLock::new(msg));
+        self.msg_ids
+            .insert(msg.read().unwrap().id, Message::clone(&msg));
+        self.index_msg(&msg);
+        msg
+    }
+
+    fn index_msg(&mut self, msg: &Message) {
+        let (conv, user, key) = {
+            let m = msg.read().unwrap();
+            let conv = m.conv.read().unwrap().id();
+            let user = m.user.read().unwrap().id();
+            (conv, user, m.key())
+        };
+        self.msgs.insert(key, Message::clone(msg));
+        self.conv_msgs
+            .entry(conv)
+            .or_default()
+            .insert(key, Message::clone(msg));
+        self.user_msgs
+            .entry(user)
+            .or_default()
+            .insert(key, Message::clone(msg));
+    }
+
+    fn unindex_msg(&mut self, msg: &MsgInfo) {
+        let key = msg.key();
+        self.msgs.remove(&key);
+        let conv = msg.conv.read().unwrap().id();
+        if let Some(history) = self.conv_msgs.get_mut(&conv) {
+            history.remove(&key);
+        }
+        let user = msg.user.read().unwrap().id();
+        if let Some(history) = self.user_msgs.get_mut(&user) {
+            history.remove(&key);
+        }
+    }
+
+    fn insert_rel(&mut self, rel: Relationship) {
+        match self.rels.iter_mut().find(|r| r.same_members(&rel)) {
+            Some(r) => r.status = rel.status,
+            None => self.rels.push(rel),
+        }
+    }
+
+    fn insert_read(&mut self, read: ReadMarker) {
+        self.reads.insert((read.user, read.conv), read);
+    }
+
+    // Hands a change to the storage backend, if there is one.
+    fn store<F>(&mut self, f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut dyn Storage) -> Result<(), &'static str>,
+    {
+        match self.storage.as_mut() {
+            Some(storage) => f(storage.as_mut()),
+            None => Ok(()),
+        }
+    }
+
+    // Upserts one stored record. The caller says where it came from.
+    fn apply(&mut self, entry: Entry) -> Result<(), LoadError> {
+        match entry {
+            Entry::User(line) => {
+                let user = App::parse_user(&line)?;
+                self.insert_user(user);
             }
-            let mut line = line.split(';');
-            let id = match line.next() {
-                Some(id) => Uuid::parse_str(id).unwrap(),
-                None => return Err("Invalid $id in CONVS file."),
-            };
-            let name = match line.next() {
-                Some(nm) => nm.to_string(),
-                None => return Err("Invalid $name in CONVS file."),
-            };
-            let mems: Vec<&str> = match line.next() {
-                Some(m) => m.split(',').collect(),
-                None => return Err("Invalid $mems list in CONVS file."),
-            };
-            let start: DateTime<Utc> = match line.next() {
-                Some(st) => DateTime::from(DateTime::parse_from_rfc3339(st).unwrap()),
-                None => return Err("Invalid $start time in CONVS file."),
-            };
-            let last_msg: DateTime<Utc> = match line.next() {
-                Some(lm) => DateTime::from(DateTime::parse_from_rfc3339(lm).unwrap()),
-                None => return Err("Invalid $last_msg time in CONVS file."),
-            };
-            let mut members = Vec::new();
-            for mem in mems {
-                let mem = Uuid::parse_str(mem).unwrap();
-                if self.users.iter().any(|u| u.borrow().id() == mem) {
-                    members.push(User::clone(
-                        self.users.iter().find(|u| u.borrow().id() == mem).unwrap(),
-                    ))
+            Entry::Conv(line) => {
+                let conv = self.parse_conv(&line)?;
+                self.insert_conv(conv);
+            }
+            Entry::Msg(line) => {
+                let msg = self.parse_msg(&line)?;
+                {
+                    let mut conv = msg.conv.write().unwrap();
+                    if conv.last_msg < msg.time_stamp {
+                        conv.last_msg = msg.time_stamp;
+                    }
                 }
+                self.insert_msg(msg);
+            }
+            Entry::Rel(line) => {
+                let rel = App::parse_rel(&line)?;
+                self.insert_rel(rel);
+            }
+            Entry::Read(line) => {
+                let read = self.parse_read(&line)?;
+                self.insert_read(read);
             }
-            self.convs
-                .push(Conversation::new(RefCell::new(ConvInfo::load(
-                    id, &name, members, start, last_msg,
-                ))));
         }
         Ok(())
     }
 
-    pub fn load_msgs(&mut self, filename: &str) -> Result<(), &'static str> {
-        for line in read_to_string(filename).unwrap().lines() {
-            if line.starts_with("#") {
-                continue;
+    // The relationship between two users, if one has been recorded.
+    fn rel(&self, user1: Uuid, user2: Uuid) -> Option<&Relationship> {
+        self.rels
+            .iter()
+            .find(|r| r.members.contains(&user1) && r.members.contains(&user2))
+    }
+
+    fn set_rel(&mut self, members: [Uuid; 2], status: RelStatus) -> Result<(), &'static str> {
+        self.store(|s| s.put_rel(members, status))?;
+        self.insert_rel(Relationship::new(members, Some(status)));
+        Ok(())
+    }
+
+    // Whether `blocker` has blocked `user`.
+    fn has_blocked(&self, blocker: Uuid, user: Uuid) -> bool {
+        match self.rel(blocker, user).map(Relationship::status) {
+            Some(RelStatus::Blocked(id)) => id == blocker && blocker != user,
+            _ => false,
+        }
+    }
+
+    // Whether either of two users has blocked the other.
+    fn blocked_between(&self, user1: Uuid, user2: Uuid) -> bool {
+        self.has_blocked(user1, user2) || self.has_blocked(user2, user1)
+    }
+
+    // Fails if any two of `members` have blocked each other. Used wherever
+    // people are put in a conversation together.
+    fn check_blocks(&self, members: &[User]) -> Result<(), &'static str> {
+        let ids: Vec<Uuid> = members.iter().map(|m| m.read().unwrap().id()).collect();
+        for (i, a) in ids.iter().enumerate() {
+            if ids[i + 1..].iter().any(|b| self.blocked_between(*a, *b)) {
+                return Err("Those users have blocked each other!");
             }
-            let mut line = line.split(';');
-            let id = match line.next() {
-                Some(id) => Uuid::parse_str(id).unwrap(),
-                None => return Err("Invalid $id in MSGS file."),
-            };
-            let text = match line.next() {
-                Some(tx) => tx.to_string(),
-                None => return Err("Invalid $text in MSGS file."),
-            };
-            let time_stamp: DateTime<Utc> = match line.next() {
-                Some(ts) => DateTime::from(DateTime::parse_from_rfc3339(ts).unwrap()),
-                None => return Err("Invalid $time_stamp in MSGS file."),
-            };
-            let user = User::clone(
-                self.users
-                    .iter()
-                    .find(|u| u.borrow().id() == Uuid::parse_str(line.next().unwrap()).unwrap())
-                    .unwrap(),
-            );
-            let conv = Conversation::clone(
-                self.convs
-                    .iter()
-                    .find(|c| c.borrow().id() == Uuid::parse_str(line.next().unwrap()).unwrap())
-                    .unwrap(),
-            );
-            self.msgs.push(Message::new(RefCell::new(MsgInfo::load(
-                id, text, time_stamp, user, conv,
-            ))));
         }
         Ok(())
     }
 
-    pub fn get_rel_status(&mut self, user1: User, user2: User) -> RelStatus {
-        let user1 = user1.borrow().id();
-        let user2 = user2.borrow().id();
+    // Blocks `user` for `blocker`, replacing any other relationship between
+    // them. Only `blocker` can undo it.
+    pub fn block(&mut self, blocker: &User, user: &User) -> Result<RelStatus, &'static str> {
+        let blocker = blocker.read().unwrap().id();
+        let user = user.read().unwrap().id();
+        if blocker == user {
+            return Err("You can't block yourself!");
+        }
+        if let Some(RelStatus::Blocked(by)) = self.rel(blocker, user).map(Relationship::status) {
+            return Err(if by == blocker {
+                "You have already blocked that user!"
+            } else {
+                "That user has already blocked you!"
+            });
+        }
+        let status = RelStatus::Blocked(blocker);
+        self.set_rel([blocker, user], status)?;
+        Ok(status)
+    }
 
-        match self
-            .rels
-            .iter()
-            .find(|r| r.members.contains(&user1) && r.members.contains(&user2))
-        {
-            Some(rel) => rel.status(),
-            None => {
-                self.rels.push(Relationship::new([user1, user2], None));
-                RelStatus::Neutral
+    pub fn unblock(&mut self, blocker: &User, user: &User) -> Result<RelStatus, &'static str> {
+        let blocker = blocker.read().unwrap().id();
+        let user = user.read().unwrap().id();
+        match self.rel(blocker, user).map(Relationship::status) {
+            Some(RelStatus::Blocked(by)) if by == blocker => {
+                self.set_rel([blocker, user], RelStatus::Neutral)?;
+                Ok(RelStatus::Neutral)
             }
+            Some(RelStatus::Blocked(_)) => Err("Only the user who blocked can unblock!"),
+            _ => Err("You haven't blocked that user!"),
         }
     }
 
-    pub fn add_user(&mut self, name: &str, email: &str) -> Result<(), &'static str> {
-        if !self
-            .users
+    // Users with no recorded relationship are Neutral.
+    pub fn get_rel_status(&self, user1: &User, user2: &User) -> RelStatus {
+        let user1 = user1.read().unwrap().id();
+        let user2 = user2.read().unwrap().id();
+        self.rel(user1, user2)
+            .map_or(RelStatus::Neutral, Relationship::status)
+    }
+
+    // Takes one step of the friend request workflow from `me` towards `user`.
+    pub fn befriend(
+        &mut self,
+        me: &User,
+        user: &User,
+        action: FriendAction,
+    ) -> Result<RelStatus, &'static str> {
+        let status = self.get_rel_status(me, user);
+        let me = me.read().unwrap().id();
+        let user = user.read().unwrap().id();
+        if me == user {
+            return Err("You can't befriend yourself!");
+        }
+        let status = status.next(action, me)?;
+        self.set_rel([me, user], status)?;
+        Ok(status)
+    }
+
+    // Adds a user with no password, who can't log in until one is set.
+    pub fn add_user(&mut self, name: &str, email: &str) -> Result<User, &'static str> {
+        let user = self.new_user(name, email)?;
+        self.store(|s| s.put_user(&user))?;
+        Ok(self.insert_user(user))
+    }
+
+    // Adds a user who can log in with `password`.
+    pub fn register(
+        &mut self,
+        name: &str,
+        email: &str,
+        password: &str,
+    ) -> Result<User, &'static str> {
+        let mut user = self.new_user(name, email)?;
+        user.set_password(password)?;
+        self.add_registered(user)
+    }
+
+    // Saves a user from new_user. The email is checked again, as the app may
+    // have been unlocked while the password was hashed.
+    fn add_registered(&mut self, user: UserInfo) -> Result<User, &'static str> {
+        if self.find_email(user.email()).is_some() {
+            return Err("User already exists with that email!");
+        }
+        self.store(|s| s.put_user(&user))?;
+        Ok(self.insert_user(user))
+    }
+
+    fn new_user(&self, name: &str, email: &str) -> Result<UserInfo, &'static str> {
+        if name.trim().is_empty() {
+            return Err("User name can't be empty!");
+        }
+        if !email.contains('@') || email.contains(char::is_whitespace) {
+            return Err("That isn't a valid email!");
+        }
+        // Emails are what people log in with, so no two users can share one.
+        if self.find_email(email).is_some() {
+            return Err("User already exists with that email!");
+        }
+        Ok(UserInfo::new(name, email))
+    }
+
+    fn find_email(&self, email: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|u| u.read().unwrap().email().eq_ignore_ascii_case(email))
+    }
+
+    pub fn set_password(&mut self, user: &User, password: &str) -> Result<(), &'static str> {
+        let mut info = user.read().unwrap().clone();
+        info.set_password(password)?;
+        self.store(|s| s.put_user(&info))?;
+        *user.write().unwrap() = info;
+        Ok(())
+    }
+
+    pub fn set_admin(&mut self, user: &User, admin: bool) -> Result<(), &'static str> {
+        let mut info = user.read().unwrap().clone();
+        info.admin = admin;
+        self.store(|s| s.put_user(&info))?;
+        *user.write().unwrap() = info;
+        Ok(())
+    }
+
+    // Makes the user with `email` an admin. No user starts out as one, so
+    // this is how a server gets its first.
+    pub fn make_admin(&mut self, email: &str) -> Result<User, &'static str> {
+        let user = match self.find_email(email) {
+            Some(u) => User::clone(u),
+            None => return Err("No user has that email!"),
+        };
+        if !user.read().unwrap().is_admin() {
+            self.set_admin(&user, true)?;
+        }
+        Ok(user)
+    }
+
+    // Checks a user's password and starts a session for them.
+    pub fn login(&mut self, email: &str, password: &str) -> Result<(User, String), &'static str> {
+        let wrong = "Wrong email or password!";
+        let (user, hash) = self.stored_password(email).ok_or(wrong)?;
+        if !auth::verify_password(&hash, password) {
+            return Err(wrong);
+        }
+        let (_, token) = self.start_session(&user, &hash).ok_or(wrong)?;
+        Ok((user, token))
+    }
+
+    // The user with `email` and their password hash, to check without the
+    // app locked.
+    fn stored_password(&self, email: &str) -> Option<(User, String)> {
+        let user = self.find_email(email)?;
+        let hash = user.read().unwrap().password.clone()?;
+        Some((User::clone(user), hash))
+    }
+
+    // Starts a session for a user whose password was checked against `hash`,
+    // unless it was changed since.
+    fn start_session(&mut self, user: &User, hash: &str) -> Option<(Uuid, String)> {
+        let info = user.read().unwrap();
+        if info.password.as_deref() != Some(hash) {
+            return None;
+        }
+        let id = info.id();
+        // Makes room for the new token by ending the user's expired ones, then
+        // their oldest. Tokens only pile up per user, so that's all the
+        // clearing out they need.
+        let mut held: Vec<(Instant, String)> = self
+            .sessions
             .iter()
-            .any(|u| u.borrow().name() == name && u.borrow().email() == email)
+            .filter(|(_, (user, _))| *user == id)
+            .map(|(token, (_, issued))| (*issued, token.clone()))
+            .collect();
+        held.sort();
+        let over = held.len().saturating_sub(MAX_TOKENS - 1);
+        for (i, (issued, token)) in held.into_iter().enumerate() {
+            if i < over || issued.elapsed() >= self.token_lifetime {
+                self.end_token(&token);
+            }
+        }
+        let token = auth::new_token();
+        self.sessions.insert(token.clone(), (id, Instant::now()));
+        Some((id, token))
+    }
+
+    // Binds a session to a user and token. A subscription from before belongs
+    // to whoever the session was logged in as then, so it ends. The old token
+    // is kept, as other connections may be using it.
+    fn log_in(&mut self, session: &mut Session, id: Uuid, token: String) -> Reply {
+        self.unsubscribe(session);
+        session.bind(id, token.clone());
+        Reply::LoggedIn(id, token)
+    }
+
+    // Logs a connection out, ending its session token too, along with any
+    // subscriptions made with it.
+    pub fn end_session(&mut self, session: &mut Session) {
+        if let Some(token) = session.clear() {
+            self.end_token(&token);
+        }
+    }
+
+    // Ends a token along with any subscriptions made with it.
+    fn end_token(&mut self, token: &str) {
+        self.subscriptions.remove_token(token);
+        self.sessions.remove(token);
+    }
+
+    // How long tokens last from LOGIN. Those already handed out are held to
+    // it too.
+    pub fn set_token_lifetime(&mut self, lifetime: Duration) {
+        self.token_lifetime = lifetime;
+    }
+
+    // Has new messages in `conv`, or in all of `me`'s conversations when it is
+    // None, pushed to the session. A session has one feed however many times
+    // it subscribes.
+    fn subscribe(&mut self, me: &User, session: &mut Session, conv: Option<Uuid>) -> Reply {
+        if let Some(id) = conv {
+            match self.get_conv(ConvSearch::Id(id)) {
+                Some(c) if App::can_read(me, &c) => {}
+                Some(_) => return Reply::Denied,
+                None => return Reply::NotFound("INVALID CONV PROVIDED"),
+            }
+        }
+        let existing = session
+            .subscription()
+            .and_then(|id| self.subscriptions.get_mut(id));
+        if let Some(sub) = existing {
+            match (conv, &mut sub.convs) {
+                (Some(id), Some(convs)) => {
+                    convs.insert(id);
+                }
+                (Some(_), None) => {}
+                (None, convs) => *convs = None,
+            }
+            return Reply::Subscribed(conv);
+        }
+        let convs = conv.map(|id| vec![id].into_iter().collect::<HashSet<Uuid>>());
+        let token = session.token().unwrap_or_default().to_string();
+        let (id, feed) =
+            self.subscriptions
+                .add(me.read().unwrap().id(), &token, convs, session.protocol());
+        session.subscribe(id, feed);
+        Reply::Subscribed(conv)
+    }
+
+    // Switches how replies, and pushes to any subscription, are written.
+    fn set_protocol(&mut self, session: &mut Session, protocol: Protocol) {
+        session.set_protocol(protocol);
+        if let Some(sub) = session
+            .subscription()
+            .and_then(|id| self.subscriptions.get_mut(id))
         {
-            self.users
-                .push(User::new(RefCell::new(UserInfo::new(name, email))));
-            Ok(())
-        } else {
-            return Err("User already exists with that info!");
+            sub.protocol = protocol;
         }
     }
 
+    pub fn unsubscribe(&mut self, session: &mut Session) {
+        if let Some(id) = session.unsubscribe() {
+            self.subscriptions.remove(id);
+        }
+    }
+
+    // Pushes a new, edited ("edit") or deleted ("delete") message to the
+    // subscribers who can read it.
+    fn push_msg(&mut self, event: &str, msg: &MsgInfo) {
+        let conv = msg.conv.read().unwrap();
+        let conv_id = conv.id();
+        let users = &self.user_ids;
+        let text = format!("PUSH {} {}", event.to_uppercase(), msg);
+        let json = json!({ "push": event, "data": reply::msg_info_json(msg) }).to_string();
+        self.subscriptions.push(&text, &json, |sub| {
+            let user = match users.get(&sub.user) {
+                Some(u) => u,
+                None => return false,
+            };
+            match &sub.convs {
+                Some(convs) => {
+                    convs.contains(&conv_id)
+                        && (conv.has_member(user) || user.read().unwrap().is_admin())
+                }
+                None => conv.has_member(user),
+            }
+        });
+    }
+
     pub fn get_user(&self, option: &str, search: &str) -> Option<User> {
         let mut users = self.users.iter();
         match option {
-            "ID" => match users.find(|u| u.borrow().id().to_string() == search.trim()) {
-                Some(ur) => Some(Rc::clone(ur)),
-                None => None,
+            "ID" => match Uuid::parse_str(search.trim()) {
+                Ok(id) => self.user_ids.get(&id).map(User::clone),
+                Err(_) => None,
             },
             "NAME" => match users.find(|u| {
-                u.borrow()
+                u.read()
+                    .unwrap()
                     .name()
                     .to_lowercase()
                     .contains(&search.to_lowercase())
             }) {
-                Some(ur) => Some(Rc::clone(ur)),
+                Some(ur) => Some(Arc::clone(ur)),
                 None => None,
             },
             "EMAIL" => match users.find(|u| {
-                u.borrow()
+                u.read()
+                    .unwrap()
                     .email()
                     .to_lowercase()
                     .contains(&search.to_lowercase())
             }) {
-                Some(ur) => Some(Rc::clone(ur)),
+                Some(ur) => Some(Arc::clone(ur)),
                 None => None,
             },
             _ => None,
@@ -413,10 +1673,10 @@ impl App {
         let users = self.users.iter();
         match option {
             "EMAIL" => users
-                .filter(|u| u.borrow().email().to_lowercase().contains(search))
+                .filter(|u| u.read().unwrap().email().to_lowercase().contains(search))
                 .for_each(|u| list.push(User::clone(u))),
             "NAME" => users
-                .filter(|u| u.borrow().name().to_lowercase().contains(search))
+                .filter(|u| u.read().unwrap().name().to_lowercase().contains(search))
                 .for_each(|u| list.push(User::clone(u))),
             _ => {}
         }
@@ -427,20 +1687,37 @@ impl App {
         }
     }
 
-    pub fn add_conv(&mut self, name: &str, members: Vec<User>) {
-        self.convs
-            .push(Conversation::new(RefCell::new(ConvInfo::new(
-                name, members,
-            ))))
+    pub fn add_conv(
+        &mut self,
+        name: &str,
+        members: Vec<User>,
+    ) -> Result<Conversation, &'static str> {
+        if name.trim().is_empty() {
+            return Err("Conversation name can't be empty!");
+        }
+        if members.is_empty() {
+            return Err("A conversation needs members!");
+        }
+        let mut ids: Vec<Uuid> = members.iter().map(|m| m.read().unwrap().id()).collect();
+        ids.sort();
+        ids.dedup();
+        if ids.len() != members.len() {
+            return Err("User listed twice in that conv.");
+        }
+        self.check_blocks(&members)?;
+        let conv = ConvInfo::new(name, members);
+        self.store(|s| s.put_conv(&conv))?;
+        Ok(self.insert_conv(conv))
     }
 
     fn get_conv(&self, search: ConvSearch) -> Option<Conversation> {
         match search {
+            ConvSearch::Id(id) => self.conv_ids.get(&id).map(Conversation::clone),
             ConvSearch::Name(name) => {
                 match self
                     .convs
                     .iter()
-                    .find(|c| c.borrow().name().contains(&name))
+                    .find(|c| c.read().unwrap().name().contains(&name))
                 {
                     Some(c) => Some(Conversation::clone(c)),
                     None => None,
@@ -448,9 +1725,8 @@ impl App {
             }
             ConvSearch::Members(users) => {
                 match self.convs.iter().find(|c| {
-                    let c = c.borrow();
-                    let mems = c.members();
-                    users.iter().all(|u| mems.contains(u))
+                    let c = c.read().unwrap();
+                    users.iter().all(|u| c.has_member(u))
                 }) {
                     Some(c) => Some(Conversation::clone(c)),
                     None => None,
@@ -461,13 +1737,14 @@ impl App {
 
     fn get_conv_mult(&self, search: ConvSearch) -> Option<Vec<Conversation>> {
         match search {
+            ConvSearch::Id(_) => self.get_conv(search).map(|c| vec![c]),
             ConvSearch::Name(name) => {
                 let result: Vec<&Conversation> = self
                     .convs
                     .iter()
-                    .filter(|c| c.borrow().name().contains(&name))
+                    .filter(|c| c.read().unwrap().name().contains(&name))
                     .collect();
-                if result.len() == 0 {
+                if result.is_empty() {
                     None
                 } else {
                     Some(result.iter().map(|c| Conversation::clone(c)).collect())
@@ -478,12 +1755,11 @@ impl App {
                     .convs
                     .iter()
                     .filter(|c| {
-                        let c = c.borrow();
-                        let mems = c.members();
-                        users.iter().all(|u| mems.contains(u))
+                        let c = c.read().unwrap();
+                        users.iter().all(|u| c.has_member(u))
                     })
                     .collect();
-                if result.len() == 0 {
+                if result.is_empty() {
                     None
                 } else {
                     Some(result.iter().map(|c| Conversation::clone(c)).collect())
@@ -492,138 +1768,547 @@ impl App {
         }
     }
 
+    pub fn get_msg(&self, id: Uuid) -> Option<Message> {
+        self.msg_ids.get(&id).map(Message::clone)
+    }
+
+    // Results come back oldest first, and only ones `me` can read count
+    // towards the limit.
+    fn get_msg_mult(&self, me: &User, search: MsgSearch) -> Option<Vec<Message>> {
+        let history = match &search.scope {
+            MsgScope::Conv(conv) => self.conv_msgs.get(&conv.read().unwrap().id())?,
+            MsgScope::Author(user) => self.user_msgs.get(&user.read().unwrap().id())?,
+            MsgScope::Time => &self.msgs,
+        };
+        let found = history
+            .range(search.range)
+            .map(|(_, m)| m)
+            .filter(|m| App::can_read(me, &m.read().unwrap().conv));
+        let limit = search.limit.min(MAX_MSG_COUNT);
+        let result: Vec<Message> = if search.oldest {
+            found.take(limit).map(Message::clone).collect()
+        } else {
+            let mut page: Vec<Message> = found.rev().take(limit).map(Message::clone).collect();
+            page.reverse();
+            page
+        };
+        if result.is_empty() {
+            None
+        } else {
+            Some(result)
+        }
+    }
+
+    // Membership changes. Each is saved with a system message from whoever
+    // made it, so the conversation's history shows who joined and left.
+
+    // Owners and admins add people.
+    pub fn add_member(
+        &mut self,
+        me: &User,
+        conv: &Conversation,
+        user: &User,
+    ) -> Result<(), &'static str> {
+        match conv.read().unwrap().role(me) {
+            Some(Role::Owner) | Some(Role::Admin) => {}
+            _ => return Err(DENIED),
+        }
+        if conv.read().unwrap().has_member(user) {
+            return Err("User already in that conv.");
+        }
+        let mut members = conv.read().unwrap().members().clone();
+        members.push(User::clone(user));
+        self.check_blocks(&members)?;
+        let text = format!("{} added {}", display_name(me), display_name(user));
+        self.change_conv(me, conv, text, |c| {
+            c.members.push(User::clone(user));
+            c.roles.insert(user.read().unwrap().id(), Role::Member);
+        })
+    }
+
+    // Owners remove anyone else; admins only remove plain members.
+    pub fn remove_member(
+        &mut self,
+        me: &User,
+        conv: &Conversation,
+        user: &User,
+    ) -> Result<(), &'static str> {
+        let (mine, theirs) = {
+            let c = conv.read().unwrap();
+            (c.role(me), c.role(user))
+        };
+        match (mine, theirs) {
+            (_, None) => return Err("User not in that conv."),
+            (Some(Role::Owner), Some(Role::Owner)) => {
+                return Err("Owners can't remove themselves, transfer ownership first!")
+            }
+            (Some(Role::Owner), _) | (Some(Role::Admin), Some(Role::Member)) => {}
+            _ => return Err(DENIED),
+        }
+        let text = format!("{} removed {}", display_name(me), display_name(user));
+        self.change_conv(me, conv, text, |c| c.remove_member(user))
+    }
+
+    // Anyone but the owner can leave.
+    pub fn leave_conv(&mut self, me: &User, conv: &Conversation) -> Result<(), &'static str> {
+        match conv.read().unwrap().role(me) {
+            None => return Err("User not in that conv."),
+            Some(Role::Owner) => return Err("Owners can't leave, transfer ownership first!"),
+            Some(_) => {}
+        }
+        let text = format!("{} left", display_name(me));
+        self.change_conv(me, conv, text, |c| c.remove_member(me))
+    }
+
+    // Hands the conversation to another member; the old owner becomes an admin.
+    pub fn set_owner(
+        &mut self,
+        me: &User,
+        conv: &Conversation,
+        user: &User,
+    ) -> Result<(), &'static str> {
+        let (mine, theirs) = {
+            let c = conv.read().unwrap();
+            (c.role(me), c.role(user))
+        };
+        match (mine, theirs) {
+            (Some(Role::Owner), Some(Role::Owner)) => return Err("You already own that conv!"),
+            (Some(Role::Owner), Some(_)) => {}
+            (Some(Role::Owner), None) => return Err("User not in that conv."),
+            _ => return Err(DENIED),
+        }
+        let text = format!("{} made {} the owner", display_name(me), display_name(user));
+        let (me_id, user_id) = (me.read().unwrap().id(), user.read().unwrap().id());
+        self.change_conv(me, conv, text, |c| {
+            c.roles.insert(me_id, Role::Admin);
+            c.roles.insert(user_id, Role::Owner);
+        })
+    }
+
+    // Only the owner makes or unmakes admins.
+    pub fn set_role(
+        &mut self,
+        me: &User,
+        conv: &Conversation,
+        user: &User,
+        role: Role,
+    ) -> Result<(), &'static str> {
+        let (mine, theirs) = {
+            let c = conv.read().unwrap();
+            (c.role(me), c.role(user))
+        };
+        match (mine, theirs) {
+            (Some(Role::Owner), None) => return Err("User not in that conv."),
+            (Some(Role::Owner), Some(Role::Owner)) => {
+                return Err("Transfer ownership to change your role!")
+            }
+            (Some(Role::Owner), Some(r)) if r == role => return Err("User already has that role!"),
+            (Some(Role::Owner), Some(_)) if role != Role::Owner => {}
+            (Some(Role::Owner), Some(_)) => return Err("Use SET OWNER to transfer ownership!"),
+            _ => return Err(DENIED),
+        }
+        let article = if role == Role::Admin { "an" } else { "a" };
+        let text = format!(
+            "{} made {} {} {}",
+            display_name(me),
+            display_name(user),
+            article,
+            role
+        );
+        let user_id = user.read().unwrap().id();
+        self.change_conv(me, conv, text, |c| {
+            c.roles.insert(user_id, role);
+        })
+    }
+
+    // Saves a change to a conversation along with the system message noting it.
+    fn change_conv<F>(
+        &mut self,
+        me: &User,
+        conv: &Conversation,
+        text: String,
+        change: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut ConvInfo),
+    {
+        let mut msg = MsgInfo::new(User::clone(me), Conversation::clone(conv), &text);
+        msg.system = true;
+        let mut info = conv.read().unwrap().clone();
+        change(&mut info);
+        info.last_msg = msg.time_stamp;
+        self.store(|s| s.put_conv(&info))?;
+        self.store(|s| s.put_msg(&msg))?;
+        *conv.write().unwrap() = info;
+        self.push_msg("msg", &msg);
+        self.insert_msg(msg);
+        Ok(())
+    }
+
     pub fn send_msg(
         &mut self,
         from: User,
         to: Conversation,
         text: &str,
-    ) -> Result<(), &'static str> {
-        if to.borrow().members.contains(&from) {
-            to.borrow_mut().new_msg();
-            self.msgs
-                .push(Message::new(RefCell::new(MsgInfo::new(from, to, text))));
-            Ok(())
+    ) -> Result<Message, &'static str> {
+        if text.trim().is_empty() {
+            return Err("Can't send an empty message.");
+        }
+        let from_id = from.read().unwrap().id();
+        if to
+            .read()
+            .unwrap()
+            .members()
+            .iter()
+            .any(|m| self.has_blocked(m.read().unwrap().id(), from_id))
+        {
+            return Err("Someone in that conv has blocked you.");
+        }
+        if to.read().unwrap().has_member(&from) {
+            let msg = MsgInfo::new(from, Conversation::clone(&to), text);
+            self.store(|s| s.put_msg(&msg))?;
+            to.write().unwrap().last_msg = msg.time_stamp;
+            self.push_msg("msg", &msg);
+            Ok(self.insert_msg(msg))
         } else {
-            return Err("User not in that conv.");
+            Err("User not in that conv.")
+        }
+    }
+
+    // Authors change their own messages, and conversation owners and admins
+    // anyone's. System messages aren't anyone's to change.
+    fn can_change(me: &User, msg: &MsgInfo) -> Result<(), &'static str> {
+        if msg.system {
+            return Err("Can't change a system message.");
         }
+        if msg.is_deleted() {
+            return Err("That message was deleted.");
+        }
+        match msg.conv.read().unwrap().role(me) {
+            Some(Role::Owner) | Some(Role::Admin) => Ok(()),
+            Some(Role::Member) if Arc::ptr_eq(&msg.user, me) => Ok(()),
+            _ => Err(DENIED),
+        }
+    }
+
+    pub fn edit_msg(&mut self, me: &User, msg: &Message, text: &str) -> Result<(), &'static str> {
+        if text.trim().is_empty() {
+            return Err("Can't send an empty message.");
+        }
+        self.change_msg(me, msg, "edit", |m, now| m.edit(text, now))
+    }
+
+    pub fn delete_msg(&mut self, me: &User, msg: &Message) -> Result<(), &'static str> {
+        self.change_msg(me, msg, "delete", |m, now| m.delete(now))
+    }
+
+    // Saves a change to a message and lets its subscribers know.
+    fn change_msg<F>(
+        &mut self,
+        me: &User,
+        msg: &Message,
+        event: &str,
+        change: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut MsgInfo, DateTime<Utc>),
+    {
+        let mut info = msg.read().unwrap().clone();
+        App::can_change(me, &info)?;
+        change(&mut info, Utc::now());
+        self.store(|s| s.put_msg(&info))?;
+        self.push_msg(event, &info);
+        *msg.write().unwrap() = info;
+        Ok(())
     }
 
-    pub fn close(
+    // Marks everything in `conv` up to `msg` as read by `me`. Markers only
+    // move forward, so marking an older message leaves the marker where it
+    // was; either way the marker is handed back.
+    pub fn mark_read(
         &mut self,
+        me: &User,
+        conv: &Conversation,
+        msg: &Message,
+    ) -> Result<ReadMarker, &'static str> {
+        if !conv.read().unwrap().has_member(me) {
+            return Err("User not in that conv.");
+        }
+        let conv_id = conv.read().unwrap().id();
+        let (msg_id, key) = {
+            let msg = msg.read().unwrap();
+            if msg.conv.read().unwrap().id() != conv_id {
+                return Err("That message isn't in that conv.");
+            }
+            (msg.id, msg.key())
+        };
+        let me_id = me.read().unwrap().id();
+        if let Some(read) = self.reads.get(&(me_id, conv_id)) {
+            if self.read_key(read).is_some_and(|k| k >= key) {
+                return Ok(read.clone());
+            }
+        }
+        let read = ReadMarker {
+            user: me_id,
+            conv: conv_id,
+            msg: msg_id,
+            time: Utc::now(),
+        };
+        self.store(|s| s.put_read(&read))?;
+        self.insert_read(read.clone());
+        Ok(read)
+    }
+
+    // Read receipts: how far each member of `conv` has read, for those who
+    // have read any of it.
+    pub fn get_reads(&self, conv: &Conversation) -> Vec<ReadMarker> {
+        let conv = conv.read().unwrap();
+        conv.members()
+            .iter()
+            .filter_map(|m| self.reads.get(&(m.read().unwrap().id(), conv.id())))
+            .cloned()
+            .collect()
+    }
+
+    // How many messages in `conv` came after `me`'s read marker, not counting
+    // `me`'s own or deleted ones. With no marker, nothing has been read.
+    pub fn unread(&self, me: &User, conv: &Conversation) -> usize {
+        let conv_id = conv.read().unwrap().id();
+        let history = match self.conv_msgs.get(&conv_id) {
+            Some(h) => h,
+            None => return 0,
+        };
+        let read = self
+            .reads
+            .get(&(me.read().unwrap().id(), conv_id))
+            .and_then(|r| self.read_key(r));
+        let from = read.map_or(Bound::Unbounded, Bound::Excluded);
+        history
+            .range((from, Bound::Unbounded))
+            .filter(|(_, m)| {
+                let m = m.read().unwrap();
+                !m.is_deleted() && !Arc::ptr_eq(&m.user, me)
+            })
+            .count()
+    }
+
+    // Where a read marker sits in its conversation's history.
+    fn read_key(&self, read: &ReadMarker) -> Option<MsgKey> {
+        self.msg_ids.get(&read.msg).map(|m| m.read().unwrap().key())
+    }
+
+    // Cleans up after a save that was interrupted. Call before loading.
+    // Errors name the file that couldn't be recovered.
+    pub fn recover(
         msg_file: &str,
         conv_file: &str,
         user_file: &str,
         rel_file: &str,
-    ) -> Result<(), &'static str> {
+        read_file: &str,
+    ) -> Result<(), LoadError> {
+        match persist::recover(&[msg_file, conv_file, user_file, rel_file, read_file]) {
+            Ok(_) => Ok(()),
+            Err((path, e)) => Err(LoadError::file(&path.to_string_lossy(), e.to_string())),
+        }
+    }
+
+    pub fn save(
+        &self,
+        msg_file: &str,
+        conv_file: &str,
+        user_file: &str,
+        rel_file: &str,
+        read_file: &str,
+    ) -> Result<u64, &'static str> {
         let messages = self
             .msgs
-            .drain(..)
-            .fold(String::new(), |acc, m| acc + &m.borrow().to_string());
-        let mut msg_file = match OpenOptions::new().read(true).write(true).open(msg_file) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error opening messages file!");
-            }
-        };
-        match msg_file.write_all(messages.as_bytes()) {
-            Ok(_) => println!("Messages file saved successfully!"),
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error writing messages file!");
-            }
-        }
+            .values()
+            .fold(String::new(), |acc, m| acc + &m.read().unwrap().to_string());
         let convs = self
             .convs
-            .drain(..)
-            .fold(String::new(), |acc, c| acc + &c.borrow().to_string());
-        let mut conv_file = match OpenOptions::new().read(true).write(true).open(conv_file) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error opening conversations file!");
-            }
-        };
-        match conv_file.write_all(convs.as_bytes()) {
-            Ok(_) => println!("Conversations file saved successfully!"),
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error writing conversations file!");
-            }
-        }
+            .iter()
+            .fold(String::new(), |acc, c| acc + &c.read().unwrap().to_string());
         let users = self
             .users
-            .drain(..)
-            .fold(String::new(), |acc, u| acc + &u.borrow().to_string());
-        let mut user_file = match OpenOptions::new().read(true).write(true).open(user_file) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error opening users file!");
-            }
-        };
-        match user_file.write_all(users.as_bytes()) {
-            Ok(_) => println!("Users file saved successfully!"),
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error writing users file!");
-            }
-        }
+            .iter()
+            .fold(String::new(), |acc, u| acc + &u.read().unwrap().to_string());
         let rels = self
             .rels
-            .drain(..)
+            .iter()
             .fold(String::new(), |acc, r| acc + &r.to_string());
-        let mut rel_file = match OpenOptions::new().read(true).write(true).open(rel_file) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("{}", e);
-                return Err("Error opening rels file!");
+        // Sorted, so saving the same data writes the same file.
+        let mut reads: Vec<&ReadMarker> = self.reads.values().collect();
+        reads.sort_by_key(|r| (r.user, r.conv));
+        let reads = reads
+            .iter()
+            .fold(String::new(), |acc, r| acc + &r.to_string());
+        let files = [
+            (msg_file, MSGS_HEADER, messages),
+            (conv_file, CONVS_HEADER, convs),
+            (user_file, USERS_HEADER, users),
+            (rel_file, RELS_HEADER, rels),
+            (read_file, READS_HEADER, reads),
+        ];
+        match persist::save(&files) {
+            Ok(gen) => {
+                info!("Data files saved successfully! (generation {})", gen);
+                Ok(gen)
             }
-        };
-        match rel_file.write_all(rels.as_bytes()) {
-            Ok(_) => println!("Rels file saved successfully!"),
             Err(e) => {
-                println!("{}", e);
-                return Err("Error writing users file!");
+                error!("{}", e);
+                Err("Error saving data files!")
             }
         }
-        println!("Goodbye! :)");
+    }
+
+    // Makes sure every change so far is in its final place in storage.
+    pub fn flush(&mut self) -> Result<(), &'static str> {
+        let mut storage = match self.storage.take() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let result = storage.flush(self);
+        self.storage = Some(storage);
+        result
+    }
+
+    pub fn close(&mut self) -> Result<(), &'static str> {
+        self.flush()?;
+        self.storage = None;
+        self.msgs.clear();
+        self.convs.clear();
+        self.users.clear();
+        self.rels.clear();
+        self.user_ids.clear();
+        self.conv_ids.clear();
+        self.msg_ids.clear();
+        self.conv_msgs.clear();
+        self.user_msgs.clear();
+        self.reads.clear();
+        info!("Goodbye! :)");
         Ok(())
     }
 }
 
 // Message Struct
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MsgInfo {
     pub id: Uuid,
     pub text: String,
     pub time_stamp: DateTime<Utc>,
     pub user: User,
     pub conv: Conversation,
+    // Written by the server to note a change, rather than sent by `user`.
+    pub system: bool,
+    // When the text was last changed, if it has been.
+    pub edited: Option<DateTime<Utc>>,
+    // Set once the message is deleted; its text and history are gone, but
+    // the message stays as a tombstone in its place.
+    pub deleted: Option<DateTime<Utc>>,
+    // Earlier versions of the text, oldest first.
+    pub history: Vec<MsgVersion>,
 }
 
-pub type Message = Rc<RefCell<MsgInfo>>;
+// Text a message had before an edit, and when that text was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgVersion {
+    pub text: String,
+    pub time_stamp: DateTime<Utc>,
+}
 
-impl MsgInfo {
-    fn to_string(&self) -> String {
-        format!(
-            "{};{};{};{};{}\n",
-            self.id.to_string(),
-            self.text,
+pub type Message = Arc<RwLock<MsgInfo>>;
+
+// Where a message sits in its conversation's history.
+type MsgKey = (DateTime<Utc>, Uuid);
+
+type KeyRange = (Bound<MsgKey>, Bound<MsgKey>);
+
+// Up to `limit` messages in `range` of the history `scope` picks, from its
+// oldest end or its newest.
+struct MsgSearch {
+    scope: MsgScope,
+    range: KeyRange,
+    limit: usize,
+    oldest: bool,
+}
+
+enum MsgScope {
+    Conv(Conversation),
+    Author(User),
+    // Every message; the range holds the times.
+    Time,
+}
+
+// The part of `range` inside `window`, or None if they don't overlap.
+fn overlap(range: KeyRange, window: KeyRange) -> Option<KeyRange> {
+    let key = |b: &Bound<MsgKey>| match b {
+        Bound::Included(k) | Bound::Excluded(k) => Some(*k),
+        Bound::Unbounded => None,
+    };
+    // Of two bounds on the same side, the one that lets less through.
+    let tighter = |a: Bound<MsgKey>, b: Bound<MsgKey>, start: bool| match (key(&a), key(&b)) {
+        (None, _) => b,
+        (_, None) => a,
+        (Some(x), Some(y)) if x == y => {
+            if matches!(a, Bound::Excluded(_)) {
+                a
+            } else {
+                b
+            }
+        }
+        (Some(x), Some(y)) => {
+            if (x > y) == start {
+                a
+            } else {
+                b
+            }
+        }
+    };
+    let start = tighter(range.0, window.0, true);
+    let end = tighter(range.1, window.1, false);
+    if let (Some(s), Some(e)) = (key(&start), key(&end)) {
+        let both_included = matches!((start, end), (Bound::Included(_), Bound::Included(_)));
+        if s > e || (s == e && !both_included) {
+            return None;
+        }
+    }
+    Some((start, end))
+}
+
+impl fmt::Display for MsgInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{};{};{};{}",
+            self.id,
+            escape(&self.text),
             self.time_stamp.to_rfc3339(),
-            self.user.borrow().id().to_string(),
-            self.conv.borrow().id().to_string()
-        )
+            self.user.read().unwrap().id(),
+            self.conv.read().unwrap().id(),
+            if self.system { "system" } else { "chat" }
+        )?;
+        // Left off for messages never changed, which keeps their records as
+        // they always were.
+        if self.edited.is_none() && self.deleted.is_none() {
+            return writeln!(f);
+        }
+        let time = |t: Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339()).unwrap_or_default();
+        write!(f, ";{};{}", time(self.edited), time(self.deleted))?;
+        for version in &self.history {
+            write!(
+                f,
+                ";{} {}",
+                version.time_stamp.to_rfc3339(),
+                escape(&version.text)
+            )?;
+        }
+        writeln!(f)
     }
+}
 
+impl MsgInfo {
     fn new(user: User, conv: Conversation, text: &str) -> MsgInfo {
-        MsgInfo {
-            id: Uuid::new_v4(),
-            text: text.to_string(),
-            time_stamp: Utc::now(),
-            user,
-            conv,
-        }
+        MsgInfo::load(Uuid::new_v4(), text.to_string(), Utc::now(), user, conv)
     }
 
     fn load(
@@ -639,8 +2324,64 @@ impl MsgInfo {
             time_stamp,
             user,
             conv,
+            system: false,
+            edited: None,
+            deleted: None,
+            history: Vec::new(),
         }
     }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.is_some()
+    }
+
+    // Where the message sits in its conversation's history: by time, then by
+    // id for messages sent in the same instant.
+    pub fn key(&self) -> MsgKey {
+        (self.time_stamp, self.id)
+    }
+
+    // Replaces the text, keeping the old version in the history.
+    fn edit(&mut self, text: &str, now: DateTime<Utc>) {
+        let old = mem::replace(&mut self.text, text.to_string());
+        self.history.push(MsgVersion {
+            text: old,
+            time_stamp: self.edited.unwrap_or(self.time_stamp),
+        });
+        self.edited = Some(now);
+    }
+
+    // Turns the message into a tombstone.
+    fn delete(&mut self, now: DateTime<Utc>) {
+        self.text.clear();
+        self.history.clear();
+        self.deleted = Some(now);
+    }
+}
+
+// Read Marker Struct
+//
+// How far a user has read a conversation: every message up to and including
+// `msg`, as of `time`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadMarker {
+    pub user: Uuid,
+    pub conv: Uuid,
+    pub msg: Uuid,
+    pub time: DateTime<Utc>,
+}
+
+impl fmt::Display for ReadMarker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{};{};{};{}",
+            self.user,
+            self.conv,
+            self.msg,
+            self.time.to_rfc3339()
+        )
+    }
 }
 
 // Relationship Struct
@@ -650,30 +2391,65 @@ pub enum RelStatus {
     BestFriends,
     Friends,
     Neutral,
+    // A friend request, holding the user who asked.
+    Pending(Uuid),
     Blocked(Uuid),
 }
 
-impl RelStatus {
-    fn to_string(&self) -> String {
+impl fmt::Display for RelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RelStatus::BestFriends => "BestFriends".to_owned(),
-            RelStatus::Friends => "Friends".to_owned(),
-            RelStatus::Neutral => "Neutral".to_owned(),
-            RelStatus::Blocked(id) => format!("Blocked,{}", id.to_string()),
+            RelStatus::BestFriends => write!(f, "BestFriends"),
+            RelStatus::Friends => write!(f, "Friends"),
+            RelStatus::Neutral => write!(f, "Neutral"),
+            RelStatus::Pending(id) => write!(f, "Pending,{}", id),
+            RelStatus::Blocked(id) => write!(f, "Blocked,{}", id),
+        }
+    }
+}
+
+impl RelStatus {
+    // The status after `actor` takes `action`. Requests are answered by the
+    // other side, and nothing but blocking changes a blocked relationship.
+    fn next(self, action: FriendAction, actor: Uuid) -> Result<RelStatus, &'static str> {
+        use FriendAction::*;
+        use RelStatus::*;
+        match (self, action) {
+            (Blocked(_), _) => Err("Blocked users can't be friends!"),
+            (Neutral, Request) => Ok(Pending(actor)),
+            (Pending(asker), Request) if asker == actor => Err("You already asked!"),
+            (Pending(_), Request) => Err("They already asked you, accept instead!"),
+            (Pending(asker), Accept) if asker != actor => Ok(Friends),
+            (Pending(asker), Decline) if asker != actor => Ok(Neutral),
+            (Pending(asker), Cancel) if asker == actor => Ok(Neutral),
+            (Pending(_), _) => Err("That isn't yours to answer!"),
+            (Friends, Promote) => Ok(BestFriends),
+            (Friends, Remove) | (BestFriends, Remove) => Ok(Neutral),
+            (Friends, Request) | (BestFriends, Request) => Err("You are already friends!"),
+            (BestFriends, Promote) => Err("You are already best friends!"),
+            (Neutral, _) => Err("There is no friend request!"),
+            (_, _) => Err("You are already friends!"),
         }
     }
 
-    fn from_str(input: &str) -> Option<RelStatus> {
+    fn from_str(input: &str) -> Result<RelStatus, LoadError> {
         let mut input = input.split(',');
-        match input.next().unwrap() {
-            "BestFriends" => Some(RelStatus::BestFriends),
-            "Friends" => Some(RelStatus::Friends),
-            "Neutral" => Some(RelStatus::Neutral),
+        match input.next().unwrap_or_default() {
+            "BestFriends" => Ok(RelStatus::BestFriends),
+            "Friends" => Ok(RelStatus::Friends),
+            "Neutral" => Ok(RelStatus::Neutral),
+            "Pending" => {
+                let user = load::next_field(&mut input, "status")?;
+                Ok(RelStatus::Pending(load::parse_uuid("status", user)?))
+            }
             "Blocked" => {
-                let user = Uuid::parse_str(input.next().unwrap()).unwrap();
-                Some(RelStatus::Blocked(user))
+                let user = load::next_field(&mut input, "status")?;
+                Ok(RelStatus::Blocked(load::parse_uuid("status", user)?))
             }
-            _ => None,
+            other => Err(LoadError::field(
+                "status",
+                format!("unknown status '{}'", other),
+            )),
         }
     }
 }
@@ -684,16 +2460,17 @@ struct Relationship {
     status: RelStatus,
 }
 
-impl Relationship {
-    fn to_string(&self) -> String {
-        format!(
-            "{};{};{},\n",
-            self.members[0].to_string(),
-            self.members[1].to_string(),
-            self.status.to_string()
+impl fmt::Display for Relationship {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{};{};{},",
+            self.members[0], self.members[1], self.status
         )
     }
+}
 
+impl Relationship {
     fn new(members: [Uuid; 2], status: Option<RelStatus>) -> Relationship {
         Relationship {
             members,
@@ -710,50 +2487,103 @@ impl Relationship {
         self.status
     }
 
-    fn _change_status(&mut self, status: RelStatus) {
-        self.status = status;
+    fn same_members(&self, other: &Relationship) -> bool {
+        other.members.contains(&self.members[0]) && other.members.contains(&self.members[1])
     }
 }
 
 // User Struct
 //
+const MIN_PASSWORD_LEN: usize = 8;
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct UserInfo {
     id: Uuid,
     name: String,
     email: String,
     create_time: DateTime<Utc>,
+    password: Option<String>,
+    admin: bool,
 }
 
-pub type User = Rc<RefCell<UserInfo>>;
+pub type User = Arc<RwLock<UserInfo>>;
+
+impl fmt::Display for UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{};{};{};{};{};{}",
+            self.id,
+            escape(&self.name),
+            escape(&self.email),
+            self.create_time.to_rfc3339(),
+            self.password.as_deref().unwrap_or(""),
+            if self.admin { "admin" } else { "user" }
+        )
+    }
+}
 
 impl UserInfo {
+    // The record as sent to clients, which leaves out the password hash.
+    pub fn profile(&self) -> String {
+        format!(
+            "{};{};{};{}\n",
+            self.id,
+            escape(&self.name),
+            escape(&self.email),
+            self.create_time.to_rfc3339()
+        )
+    }
+
     pub fn new(name: &str, email: &str) -> UserInfo {
         UserInfo {
             id: Uuid::new_v4(),
             name: name.to_string(),
             email: email.to_string(),
             create_time: Utc::now(),
+            password: None,
+            admin: false,
         }
     }
 
-    pub fn load(id: Uuid, name: &str, email: &str, create_time: DateTime<Utc>) -> UserInfo {
+    pub fn load(
+        id: Uuid,
+        name: &str,
+        email: &str,
+        create_time: DateTime<Utc>,
+        password: Option<String>,
+    ) -> UserInfo {
         UserInfo {
             id,
             name: name.to_string(),
             email: email.to_string(),
             create_time,
+            password,
+            admin: false,
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{};{};{};{}\n",
-            self.id.to_string(),
-            self.name,
-            self.email,
-            self.create_time.to_rfc3339()
-        )
+    pub fn set_password(&mut self, password: &str) -> Result<(), &'static str> {
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err("Password must be at least 8 characters!");
+        }
+        self.password = Some(auth::hash_password(password)?);
+        Ok(())
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        match &self.password {
+            Some(hash) => auth::verify_password(hash, password),
+            None => false,
+        }
     }
 
     pub fn change_name(&mut self, name: &str) -> Result<(), &'static str> {
@@ -792,51 +2622,106 @@ impl UserInfo {
 }
 
 // Conversation Struct
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConvInfo {
     id: Uuid,
     name: String,
     members: Vec<User>,
+    // Each member's role, keyed by user id.
+    roles: HashMap<Uuid, Role>,
     start: DateTime<Utc>,
     last_msg: DateTime<Utc>,
 }
 
-pub type Conversation = Rc<RefCell<ConvInfo>>;
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Role {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Owner => write!(f, "owner"),
+            Role::Admin => write!(f, "admin"),
+            Role::Member => write!(f, "member"),
+        }
+    }
+}
+
+impl Role {
+    fn from_str(input: &str) -> Result<Role, LoadError> {
+        match input {
+            "owner" => Ok(Role::Owner),
+            "admin" => Ok(Role::Admin),
+            "member" => Ok(Role::Member),
+            other => Err(LoadError::field(
+                "members",
+                format!("unknown role '{}'", other),
+            )),
+        }
+    }
+}
+
+pub type Conversation = Arc<RwLock<ConvInfo>>;
+
+impl fmt::Display for ConvInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let members: Vec<String> = self
+            .members
+            .iter()
+            .map(|usr| {
+                let id = usr.read().unwrap().id();
+                let role = self.roles.get(&id).copied().unwrap_or(Role::Member);
+                format!("{}:{}", id, role)
+            })
+            .collect();
+        writeln!(
+            f,
+            "{};{};{};{};{}",
+            self.id,
+            escape(&self.name),
+            members.join(","),
+            self.start.to_rfc3339(),
+            self.last_msg.to_rfc3339()
+        )
+    }
+}
 
 enum ConvSearch {
+    Id(Uuid),
     Name(String),
     Members(Vec<User>),
 }
 
 impl ConvInfo {
+    // The first member owns the conversation.
     pub fn new(name: &str, members: Vec<User>) -> ConvInfo {
         let time = Utc::now();
+        let roles = members
+            .iter()
+            .enumerate()
+            .map(|(i, m)| {
+                let role = if i == 0 { Role::Owner } else { Role::Member };
+                (m.read().unwrap().id(), role)
+            })
+            .collect();
         ConvInfo {
             id: Uuid::new_v4(),
             name: name.to_string(),
             members,
+            roles,
             start: time,
             last_msg: time,
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{};{};{};{};{}\n",
-            self.id.to_string(),
-            self.name,
-            &self.members.iter().fold(String::new(), |acc, usr| {
-                acc + "," + &usr.borrow().id().to_string()
-            })[1..],
-            self.start.to_rfc3339(),
-            self.last_msg.to_rfc3339()
-        )
-    }
-
     pub fn load(
         id: Uuid,
         name: &str,
         members: Vec<User>,
+        roles: HashMap<Uuid, Role>,
         start: DateTime<Utc>,
         last_msg: DateTime<Utc>,
     ) -> ConvInfo {
@@ -844,15 +2729,12 @@ impl ConvInfo {
             id,
             name: name.to_string(),
             members,
+            roles,
             start,
             last_msg,
         }
     }
 
-    pub fn new_msg(&mut self) {
-        self.last_msg = Utc::now()
-    }
-
     pub fn id(&self) -> Uuid {
         self.id
     }
@@ -861,7 +2743,35 @@ impl ConvInfo {
         &self.name
     }
 
+    pub fn start(&self) -> DateTime<Utc> {
+        self.start
+    }
+
+    pub fn last_msg(&self) -> DateTime<Utc> {
+        self.last_msg
+    }
+
     pub fn members(&self) -> &Vec<User> {
         &self.members
     }
+
+    pub fn has_member(&self, user: &User) -> bool {
+        let id = user.read().unwrap().id();
+        self.members.iter().any(|m| m.read().unwrap().id() == id)
+    }
+
+    // A member's role, or None if they aren't a member.
+    pub fn role(&self, user: &User) -> Option<Role> {
+        if !self.has_member(user) {
+            return None;
+        }
+        let id = user.read().unwrap().id();
+        Some(self.roles.get(&id).copied().unwrap_or(Role::Member))
+    }
+
+    fn remove_member(&mut self, user: &User) {
+        let id = user.read().unwrap().id();
+        self.members.retain(|m| m.read().unwrap().id() != id);
+        self.roles.remove(&id);
+    }
 }
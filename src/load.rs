@@ -0,0 +1,127 @@
+// Errors and reporting for loading stored records.
+//
+
+use chrono::{DateTime, Utc};
+use std::{fmt, str::Split};
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoadMode {
+    // Stop at the first bad record.
+    Strict,
+    // Skip bad records and list them in the LoadReport.
+    Lenient,
+}
+
+impl fmt::Display for LoadMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadMode::Strict => write!(f, "strict"),
+            LoadMode::Lenient => write!(f, "lenient"),
+        }
+    }
+}
+
+impl LoadMode {
+    pub(crate) fn from_str(input: &str) -> Option<LoadMode> {
+        match input {
+            "strict" => Some(LoadMode::Strict),
+            "lenient" => Some(LoadMode::Lenient),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadError {
+    pub file: String,
+    // Line numbers start at 1; 0 means the file as a whole.
+    pub line: usize,
+    pub field: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: invalid {}: {}",
+            self.file, self.line, self.field, self.reason
+        )
+    }
+}
+
+impl LoadError {
+    // An error in one field, before we know where the record came from.
+    pub fn field(field: &'static str, reason: String) -> LoadError {
+        LoadError {
+            file: String::new(),
+            line: 0,
+            field,
+            reason,
+        }
+    }
+
+    pub fn file(file: &str, reason: String) -> LoadError {
+        LoadError {
+            file: file.to_string(),
+            line: 0,
+            field: "file",
+            reason,
+        }
+    }
+
+    pub fn at(mut self, file: &str, line: usize) -> LoadError {
+        self.file = file.to_string();
+        self.line = line;
+        self
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LoadReport {
+    pub skipped: Vec<LoadError>,
+}
+
+impl LoadReport {
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty()
+    }
+
+    pub fn merge(&mut self, other: LoadReport) {
+        self.skipped.extend(other.skipped);
+    }
+
+    // Deals with one bad record according to `mode`.
+    pub fn skip(&mut self, mode: LoadMode, err: LoadError) -> Result<(), LoadError> {
+        match mode {
+            LoadMode::Strict => Err(err),
+            LoadMode::Lenient => {
+                self.skipped.push(err);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Helpers for pulling fields out of a ';' separated record.
+
+pub fn next_field<'a>(
+    fields: &mut Split<'a, char>,
+    field: &'static str,
+) -> Result<&'a str, LoadError> {
+    fields
+        .next()
+        .ok_or_else(|| LoadError::field(field, String::from("missing")))
+}
+
+pub fn parse_uuid(field: &'static str, input: &str) -> Result<Uuid, LoadError> {
+    Uuid::parse_str(input).map_err(|e| LoadError::field(field, format!("'{}': {}", input, e)))
+}
+
+pub fn parse_time(field: &'static str, input: &str) -> Result<DateTime<Utc>, LoadError> {
+    match DateTime::parse_from_rfc3339(input) {
+        Ok(t) => Ok(DateTime::from(t)),
+        Err(e) => Err(LoadError::field(field, format!("'{}': {}", input, e))),
+    }
+}
@@ -0,0 +1,75 @@
+// Logging to stdout, filtered by level.
+//
+// Use the error!, warn!, info! and debug! macros, which take format! style
+// arguments. Anything below the level set with set_log_level is left out.
+//
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+impl LogLevel {
+    pub(crate) fn from_str(input: &str) -> Option<LogLevel> {
+        match input {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Info as usize);
+
+pub fn set_log_level(level: LogLevel) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log_at!($crate::log::LogLevel::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log_at!($crate::log::LogLevel::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log_at!($crate::log::LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log_at!($crate::log::LogLevel::Debug, $($arg)*) };
+}
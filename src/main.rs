@@ -1,28 +1,112 @@
 use chat_server::*;
+use std::{
+    env,
+    net::SocketAddr,
+    process,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
 
 fn main() -> Result<(), &'static str> {
-    let mut app = App::new();
-    app.load_users("files/users")?;
-    app.load_convs("files/convs")?;
-    app.load_msgs("files/msgs")?;
-    app.load_rels("files/rels")?;
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(Startup::Run(config)) => config,
+        Ok(Startup::PrintConfig(config)) => {
+            print!("{}", config);
+            return Ok(());
+        }
+        Ok(Startup::Help(usage)) => {
+            println!("{}", usage);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    set_log_level(config.log_level);
 
-    // let me = app.get_user(Some("Curtis Jones"), None).unwrap();
-    // let conv = app.get_conv(Some("Basic"), None).unwrap();
+    let storage: Box<dyn Storage> = match config.storage {
+        StorageKind::Files => Box::new(FlatFileStorage::new(&config.data_dir)),
+        #[cfg(feature = "sqlite")]
+        StorageKind::Sqlite => {
+            let db = std::path::Path::new(&config.data_dir).join("chat.db");
+            match SqliteStorage::open(&db.to_string_lossy()) {
+                Ok(storage) => Box::new(storage),
+                Err(e) => {
+                    eprintln!("{}: {}", db.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+        // Config refuses sqlite when it isn't built in.
+        #[cfg(not(feature = "sqlite"))]
+        StorageKind::Sqlite => unreachable!(),
+    };
+    let (mut app, report) = match App::open(storage, config.load_mode) {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err("Error loading data files!");
+        }
+    };
+    for skipped in &report.skipped {
+        eprintln!("Skipped record: {}", skipped);
+    }
+    app.set_token_lifetime(config.token_lifetime);
+    if let Some(email) = &config.admin {
+        if let Err(e) = app.make_admin(email) {
+            eprintln!("Can't make {} an admin: {}", email, e);
+            process::exit(1);
+        }
+    }
+    let app = Arc::new(Mutex::new(app));
 
-    // app.send_msg(me, conv, "Hello world!")?;
+    // Every server stops together, on a signal or an admin's END.
+    let shutdown = ShutdownHandle::new(config.shutdown_grace);
+    if let Err(e) = shutdown.stop_on_signals() {
+        eprintln!("Can't handle signals: {}", e);
+    }
+    let addr = |port| SocketAddr::new(config.bind, port);
+    let tcp = match TcpServer::bind(addr(config.port), config.threads)
+        .map(|server| server.with_limits(config.max_connections, config.idle_timeout))
+        .and_then(|server| server.with_shutdown(shutdown.clone()))
+    {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Can't listen on {}: {}", addr(config.port), e);
+            process::exit(1);
+        }
+    };
+    let mut servers = Vec::new();
+    if let Some(port) = config.ws_port {
+        match WsServer::bind(addr(port), config.threads)
+            .map(|server| server.with_limits(config.max_connections, config.idle_timeout))
+            .and_then(|server| server.with_shutdown(shutdown.clone()))
+        {
+            Ok(server) => {
+                let app = Arc::clone(&app);
+                servers.push(thread::spawn(move || server.serve(app)));
+            }
+            Err(e) => eprintln!("Can't listen on {}: {}", addr(port), e),
+        }
+    }
+    if let Some(port) = config.http_port {
+        match HttpServer::bind(addr(port), config.threads)
+            .map(|server| server.with_limits(config.max_connections, config.idle_timeout))
+            .and_then(|server| server.with_shutdown(shutdown.clone()))
+        {
+            Ok(server) => {
+                let app = Arc::clone(&app);
+                servers.push(thread::spawn(move || server.serve(app)));
+            }
+            Err(e) => eprintln!("Can't listen on {}: {}", addr(port), e),
+        }
+    }
+    tcp.serve(Arc::clone(&app));
+    for server in servers {
+        let _ = server.join();
+    }
 
-    // let users = app.get_user_mult(Some("s "), Some("mail"));
-    // let convs = app.get_conv_mult(Some("bas"), None);
-
-    let curtis = app.get_user("NAME", "Curtis Jones").unwrap();
-    println!("{:#?}", curtis);
-    let sarah = app.get_user("NAME", "Sarah Parsons").unwrap();
-
-    println!("{:#?}", app.get_rel_status(curtis, sarah));
-
-    TcpServer::listen(8080, &mut app);
-    // app.add_user("Curtis Jones", "mail@curtisjones.ca")?;
-
-    app.close("files/msgs", "files/convs", "files/users", "files/rels")
+    let mut app = app.lock().unwrap_or_else(PoisonError::into_inner);
+    app.close()
 }
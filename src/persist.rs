@@ -0,0 +1,138 @@
+// Crash-safe saving of the data files.
+//
+// A save writes every file to "<file>.tmp" and fsyncs it, then bumps the
+// generation stored in the manifest, then renames the temp files over the real
+// ones. Writing the manifest is the commit point: if we crash before it, the
+// temp files are thrown away by recover(); if we crash after it, recover()
+// finishes the renames. Either way the files end up from the same generation.
+//
+
+use std::{
+    fs::{self, File},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+};
+
+const GENERATION_TAG: &str = "# Generation: ";
+
+// The manifest lives next to the data files.
+pub fn manifest_path(file: &str) -> PathBuf {
+    Path::new(file).with_file_name("manifest")
+}
+
+fn temp_path(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// A missing manifest means nothing has been saved yet.
+pub fn read_generation(manifest: &Path) -> io::Result<u64> {
+    match fs::read_to_string(manifest) {
+        Ok(gen) => gen
+            .trim()
+            .parse()
+            .map_err(|_| invalid(format!("bad generation in {}", manifest.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// The generation written in a file's header comments, if it has one.
+fn file_generation(file: &Path) -> io::Result<Option<u64>> {
+    let contents = match fs::read_to_string(file) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for line in contents.lines().take_while(|l| l.starts_with('#')) {
+        if let Some(gen) = line.strip_prefix(GENERATION_TAG) {
+            return match gen.trim().parse() {
+                Ok(gen) => Ok(Some(gen)),
+                Err(_) => Err(invalid(format!("bad generation in {}", file.display()))),
+            };
+        }
+    }
+    Ok(None)
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+// Replaces a file atomically by writing a synced temp file and renaming it.
+pub fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    write_synced(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+// Saves every (path, header, body) triple as one new generation.
+pub fn save(files: &[(&str, &str, String)]) -> io::Result<u64> {
+    let manifest = manifest_path(files[0].0);
+    let gen = read_generation(&manifest)? + 1;
+    for (path, header, body) in files {
+        let contents = format!("{}{}{}\n{}", header, GENERATION_TAG, gen, body);
+        write_synced(&temp_path(Path::new(path)), contents.as_bytes())?;
+    }
+    replace(&manifest, format!("{}\n", gen).as_bytes())?;
+    for (path, _, _) in files {
+        let path = Path::new(path);
+        fs::rename(temp_path(path), path)?;
+        sync_dir(path)?;
+    }
+    Ok(gen)
+}
+
+// Cleans up after a save that was cut short, and checks that every file is
+// from the generation in the manifest. Files that predate the manifest carry
+// no generation and are accepted as generation 0.
+// Errors come with the path of the file at fault.
+pub fn recover(files: &[&str]) -> Result<u64, (PathBuf, io::Error)> {
+    let at = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| (path, e)
+    };
+    let manifest = manifest_path(files[0]);
+    let gen = read_generation(&manifest).map_err(at(&manifest))?;
+    for path in files {
+        let path = Path::new(path);
+        let tmp = temp_path(path);
+        match file_generation(&tmp).map_err(at(&tmp))? {
+            Some(g) if g == gen => {
+                fs::rename(&tmp, path).map_err(at(path))?;
+                sync_dir(path).map_err(at(path))?;
+            }
+            Some(_) | None if tmp.exists() => fs::remove_file(&tmp).map_err(at(&tmp))?,
+            _ => {}
+        }
+        // A missing file is left to the loader, which knows whether it can
+        // do without it, as it can for a file added since the last save.
+        if !path.exists() {
+            continue;
+        }
+        let found = file_generation(path).map_err(at(path))?.unwrap_or(0);
+        if found != gen {
+            let e = invalid(format!(
+                "from generation {} but the manifest is at {}",
+                found, gen
+            ));
+            return Err((path.to_path_buf(), e));
+        }
+    }
+    Ok(gen)
+}
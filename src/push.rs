@@ -0,0 +1,132 @@
+// Pushing new messages to subscribed connections.
+//
+// SUBSCRIBE hands a connection a Feed, a bounded queue that App fills with
+// every new, edited or deleted message the subscription covers. The
+// transport drains it onto the socket from a thread of its own, so a slow
+// reader never holds up the user sending. A reader that lets its queue fill
+// up is unsubscribed instead of buffering without limit, and is told so once
+// it has read what was queued. Pushes are written in the subscriber's
+// protocol; in JSON they have a "push" field where replies have "status".
+//
+
+use crate::Protocol;
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+};
+use uuid::Uuid;
+
+// How many pushes can wait on one connection before it counts as too slow.
+pub const FEED_SIZE: usize = 256;
+
+// Sent as the last push to a subscriber that fell too far behind.
+fn too_slow(protocol: Protocol) -> String {
+    match protocol {
+        Protocol::Text => String::from("PUSH UNSUBSCRIBED TOO SLOW"),
+        Protocol::Json => json!({ "push": "unsubscribed", "reason": "TOO SLOW" }).to_string(),
+    }
+}
+
+#[derive(Debug)]
+pub struct Feed {
+    receiver: Receiver<String>,
+    // A frame to end on, once the queue has been read.
+    last: Arc<Mutex<Option<String>>>,
+}
+
+impl Feed {
+    // Waits for the next frame to push. Returns None once the subscription
+    // has ended and everything queued has been read.
+    pub fn next(&self) -> Option<String> {
+        match self.receiver.recv() {
+            Ok(frame) => Some(frame),
+            Err(_) => self.last.lock().unwrap().take(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    pub user: Uuid,
+    pub token: String,
+    // The conversations followed, or None for every one the user is in.
+    pub convs: Option<HashSet<Uuid>>,
+    pub protocol: Protocol,
+    sender: SyncSender<String>,
+    last: Arc<Mutex<Option<String>>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    next_id: u64,
+    subs: HashMap<u64, Subscriber>,
+}
+
+impl Subscriptions {
+    // Starts a subscription, returning its id and the Feed to drain.
+    pub fn add(
+        &mut self,
+        user: Uuid,
+        token: &str,
+        convs: Option<HashSet<Uuid>>,
+        protocol: Protocol,
+    ) -> (u64, Feed) {
+        let (sender, receiver) = mpsc::sync_channel(FEED_SIZE);
+        let last = Arc::new(Mutex::new(None));
+        self.next_id += 1;
+        self.subs.insert(
+            self.next_id,
+            Subscriber {
+                user,
+                token: token.to_string(),
+                convs,
+                protocol,
+                sender,
+                last: Arc::clone(&last),
+            },
+        );
+        (self.next_id, Feed { receiver, last })
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Subscriber> {
+        self.subs.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.subs.remove(&id);
+    }
+
+    // Ends every subscription made with a session token.
+    pub fn remove_token(&mut self, token: &str) {
+        self.subs.retain(|_, s| s.token != token);
+    }
+
+    // Queues `text` or `json` for every subscriber `wants` picks, dropping any
+    // whose connection has gone or whose queue is full.
+    pub fn push<F>(&mut self, text: &str, json: &str, wants: F)
+    where
+        F: Fn(&Subscriber) -> bool,
+    {
+        self.subs.retain(|_, s| {
+            if !wants(s) {
+                return true;
+            }
+            let frame = match s.protocol {
+                Protocol::Text => text,
+                Protocol::Json => json,
+            };
+            match s.sender.try_send(frame.to_string()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    *s.last.lock().unwrap() = Some(too_slow(s.protocol));
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
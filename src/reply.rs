@@ -0,0 +1,278 @@
+// Replies to commands, kept apart from how they are written out.
+//
+// App::run comes back with a Reply. Display gives the wire protocol's legacy
+// text. In JSON every reply has a status code, which follows HTTP's, and
+// either a typed payload:
+//   {"status": 200, "type": "users", "data": [{"id": ..., "name": ...}]}
+// or an error with a kind to match on and a message for people:
+//   {"status": 404, "error": {"kind": "not_found", "message": "..."}}
+// A connection picks one with PROTOCOL (TEXT|JSON); text is the default.
+//
+
+use crate::{
+    escape::escape, Conversation, Message, MsgInfo, ParseError, ReadMarker, RelStatus, Role, User,
+    DENIED,
+};
+use serde_json::{json, Value};
+use std::fmt;
+use uuid::Uuid;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Text,
+    Json,
+}
+
+// What a search was looking for, so finding nothing still has a type.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Records {
+    Users,
+    Convs,
+    Msgs,
+    Reads,
+    Unread,
+}
+
+impl Records {
+    fn as_str(self) -> &'static str {
+        match self {
+            Records::Users => "users",
+            Records::Convs => "convs",
+            Records::Msgs => "msgs",
+            Records::Reads => "reads",
+            Records::Unread => "unread",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Reply {
+    Users(Vec<User>),
+    Convs(Vec<Conversation>),
+    Msgs(Vec<Message>),
+    Rel(RelStatus),
+    Reads(Vec<ReadMarker>),
+    // The user's conversations, each with how many messages they haven't read.
+    Unread(Vec<(Conversation, usize)>),
+    LoggedIn(Uuid, String),
+    // None is every conversation the user is in.
+    Subscribed(Option<Uuid>),
+    // Done, with nothing more to say than this.
+    Done(&'static str),
+    // A search that found nothing.
+    Empty(Records, &'static str),
+    // An id given in the command that doesn't name anything.
+    NotFound(&'static str),
+    // Not logged in, or with a token that has been ended.
+    Unauthorized(&'static str),
+    Denied,
+    Error(&'static str),
+    BadRequest(ParseError),
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reply::Users(users) => users
+                .iter()
+                .try_for_each(|u| write!(f, "{}", u.read().unwrap().profile())),
+            Reply::Convs(convs) => convs
+                .iter()
+                .try_for_each(|c| write!(f, "{}", c.read().unwrap())),
+            Reply::Msgs(msgs) => msgs
+                .iter()
+                .try_for_each(|m| write!(f, "{}", m.read().unwrap())),
+            Reply::Rel(status) => write!(f, "{}", status),
+            Reply::Reads(reads) => reads.iter().try_for_each(|r| write!(f, "{}", r)),
+            Reply::Unread(convs) => convs.iter().try_for_each(|(c, n)| {
+                let c = c.read().unwrap();
+                writeln!(f, "{};{};{}", c.id(), escape(c.name()), n)
+            }),
+            Reply::LoggedIn(id, token) => write!(f, "LOGGED IN {} {}", id, token),
+            Reply::Subscribed(Some(conv)) => write!(f, "SUBSCRIBED {}", conv),
+            Reply::Subscribed(None) => write!(f, "SUBSCRIBED ALL"),
+            Reply::Done(s) | Reply::Empty(_, s) | Reply::NotFound(s) | Reply::Unauthorized(s) => {
+                write!(f, "{}", s)
+            }
+            Reply::Denied => write!(f, "{}", DENIED),
+            Reply::Error(e) => write!(f, "ERROR: {}", e),
+            Reply::BadRequest(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Reply {
+    // The reply for an error from an App method, keeping denials distinct.
+    pub fn from_err(e: &'static str) -> Reply {
+        if e == DENIED {
+            Reply::Denied
+        } else {
+            Reply::Error(e)
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error_kind().is_none()
+    }
+
+    // The status code, as HTTP would have it.
+    pub fn status(&self) -> u16 {
+        match self {
+            Reply::NotFound(_) => 404,
+            Reply::Unauthorized(_) => 401,
+            Reply::Denied => 403,
+            Reply::Error(_) | Reply::BadRequest(_) => 400,
+            _ => 200,
+        }
+    }
+
+    // What went wrong, for clients to match on instead of the message.
+    pub fn error_kind(&self) -> Option<&'static str> {
+        match self {
+            Reply::NotFound(_) => Some("not_found"),
+            Reply::Unauthorized(_) => Some("unauthorized"),
+            Reply::Denied => Some("denied"),
+            Reply::Error(_) => Some("invalid"),
+            Reply::BadRequest(_) => Some("bad_request"),
+            _ => None,
+        }
+    }
+
+    // The name of the payload's type.
+    fn data_type(&self) -> &'static str {
+        match self {
+            Reply::Users(_) => "users",
+            Reply::Convs(_) => "convs",
+            Reply::Msgs(_) => "msgs",
+            Reply::Rel(_) => "rel",
+            Reply::Reads(_) => "reads",
+            Reply::Unread(_) => "unread",
+            Reply::LoggedIn(..) => "session",
+            Reply::Subscribed(_) => "subscription",
+            Reply::Empty(records, _) => records.as_str(),
+            _ => "done",
+        }
+    }
+
+    // The payload on its own, or the error object for an error.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Reply::Users(users) => users.iter().map(user_json).collect(),
+            Reply::Convs(convs) => convs.iter().map(conv_json).collect(),
+            Reply::Msgs(msgs) => msgs.iter().map(msg_json).collect(),
+            Reply::Rel(status) => rel_json(status),
+            Reply::Reads(reads) => reads.iter().map(read_json).collect(),
+            Reply::Unread(convs) => convs
+                .iter()
+                .map(|(c, n)| {
+                    let mut conv = conv_json(c);
+                    conv["unread"] = json!(n);
+                    conv
+                })
+                .collect(),
+            Reply::LoggedIn(id, token) => json!({ "id": id.to_string(), "token": token }),
+            Reply::Subscribed(conv) => json!({ "conv": conv.map(|c| c.to_string()) }),
+            Reply::Done(s) => json!({ "message": s }),
+            Reply::Empty(..) => json!([]),
+            _ => json!({
+                "error": error_json(self.error_kind().unwrap_or("invalid"), &self.to_string())
+            }),
+        }
+    }
+
+    // The whole reply as sent to a JSON connection.
+    pub fn to_response(&self) -> Value {
+        if let Some(kind) = self.error_kind() {
+            return json!({
+                "status": self.status(),
+                "error": error_json(kind, &self.to_string()),
+            });
+        }
+        json!({
+            "status": self.status(),
+            "type": self.data_type(),
+            "data": self.to_json(),
+        })
+    }
+
+    pub fn format(&self, protocol: Protocol) -> String {
+        match protocol {
+            Protocol::Text => self.to_string(),
+            Protocol::Json => self.to_response().to_string(),
+        }
+    }
+}
+
+pub fn error_json(kind: &str, message: &str) -> Value {
+    json!({ "kind": kind, "message": message })
+}
+
+pub fn user_json(user: &User) -> Value {
+    let user = user.read().unwrap();
+    json!({
+        "id": user.id().to_string(),
+        "name": user.name(),
+        "email": user.email(),
+        "created": user.time().to_rfc3339(),
+    })
+}
+
+pub fn conv_json(conv: &Conversation) -> Value {
+    let conv = conv.read().unwrap();
+    let members: Vec<Value> = conv
+        .members()
+        .iter()
+        .map(|m| {
+            let role = conv.role(m).unwrap_or(Role::Member);
+            json!({ "id": m.read().unwrap().id().to_string(), "role": role.to_string() })
+        })
+        .collect();
+    json!({
+        "id": conv.id().to_string(),
+        "name": conv.name(),
+        "members": members,
+        "created": conv.start().to_rfc3339(),
+        "last_msg": conv.last_msg().to_rfc3339(),
+    })
+}
+
+pub fn msg_json(msg: &Message) -> Value {
+    msg_info_json(&msg.read().unwrap())
+}
+
+pub fn msg_info_json(msg: &MsgInfo) -> Value {
+    let history: Vec<Value> = msg
+        .history
+        .iter()
+        .map(|v| json!({ "text": v.text, "time_stamp": v.time_stamp.to_rfc3339() }))
+        .collect();
+    json!({
+        "id": msg.id.to_string(),
+        "text": msg.text,
+        "time_stamp": msg.time_stamp.to_rfc3339(),
+        "user": msg.user.read().unwrap().id().to_string(),
+        "conv": msg.conv.read().unwrap().id().to_string(),
+        "kind": if msg.system { "system" } else { "chat" },
+        "edited": msg.edited.map(|t| t.to_rfc3339()),
+        "deleted": msg.deleted.map(|t| t.to_rfc3339()),
+        "history": history,
+    })
+}
+
+pub fn read_json(read: &ReadMarker) -> Value {
+    json!({
+        "user": read.user.to_string(),
+        "conv": read.conv.to_string(),
+        "msg": read.msg.to_string(),
+        "time": read.time.to_rfc3339(),
+    })
+}
+
+pub fn rel_json(status: &RelStatus) -> Value {
+    match status {
+        RelStatus::Pending(by) => json!({ "status": "Pending", "by": by.to_string() }),
+        RelStatus::Blocked(by) => json!({ "status": "Blocked", "by": by.to_string() }),
+        status => json!({ "status": status.to_string() }),
+    }
+}
@@ -0,0 +1,167 @@
+// Stopping the servers cleanly.
+//
+// Each server holds a ShutdownHandle, and servers given the same one stop
+// together. stop(), called on SIGINT or SIGTERM or for an admin's END, makes
+// the servers stop accepting and closes the read side of every open
+// connection, so each connection finishes the request it is on, writes the
+// reply and hangs up. A server then gives its connections the grace period to
+// get there before cutting them off, and serve returns once all of its
+// connection threads and workers have been joined.
+//
+
+use crate::ThreadPool;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How long in-flight requests get to finish once the servers are stopping.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    stopping: AtomicBool,
+    grace: Duration,
+    // Where the servers listen, so their accept loops can be woken.
+    listeners: Mutex<Vec<SocketAddr>>,
+    conns: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+}
+
+// An open connection, forgotten again when dropped.
+#[derive(Debug)]
+pub(crate) struct Conn {
+    handle: ShutdownHandle,
+    id: u64,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.handle.inner.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new(grace: Duration) -> ShutdownHandle {
+        ShutdownHandle {
+            inner: Arc::new(Inner {
+                stopping: AtomicBool::new(false),
+                grace,
+                listeners: Mutex::new(Vec::new()),
+                conns: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.inner.stopping.load(Ordering::SeqCst)
+    }
+
+    pub fn stop(&self) {
+        if self.inner.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Shutting down.");
+        for addr in self.inner.listeners.lock().unwrap().iter() {
+            let _ = TcpStream::connect(wake_addr(*addr));
+        }
+        for conn in self.inner.conns.lock().unwrap().values() {
+            let _ = conn.shutdown(Shutdown::Read);
+        }
+    }
+
+    // Stops everything on the first SIGINT or SIGTERM.
+    pub fn stop_on_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Got signal {}.", signal);
+                handle.stop();
+            }
+        });
+        Ok(())
+    }
+
+    pub(crate) fn listen(&self, addr: SocketAddr) {
+        self.inner.listeners.lock().unwrap().push(addr);
+    }
+
+    // Keeps track of a new connection, or returns None if it came in too late.
+    pub(crate) fn track(&self, stream: &TcpStream) -> Option<Conn> {
+        // Checked under the lock, so stop() either sees the connection or
+        // the connection sees the flag.
+        let mut conns = self.inner.conns.lock().unwrap();
+        if self.is_stopping() {
+            return None;
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        match stream.try_clone() {
+            Ok(s) => conns.insert(id, s),
+            Err(e) => {
+                warn!("{}", e);
+                return None;
+            }
+        };
+        Some(Conn {
+            handle: self.clone(),
+            id,
+        })
+    }
+
+    // Waits out the grace period for a stopped server's connections, then
+    // cuts off whatever they are still serving, and joins them and the
+    // workers running their requests.
+    pub(crate) fn drain(&self, pool: Arc<ThreadPool>, conns: Vec<JoinHandle<()>>) {
+        let deadline = Instant::now() + self.inner.grace;
+        while !conns.iter().all(JoinHandle::is_finished) {
+            if Instant::now() >= deadline {
+                warn!(
+                    "Requests still running after {:?}; closing.",
+                    self.inner.grace
+                );
+                for conn in self.inner.conns.lock().unwrap().values() {
+                    let _ = conn.shutdown(Shutdown::Both);
+                }
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        for conn in conns {
+            let _ = conn.join();
+        }
+        // The connections held the other references, so this joins the
+        // workers.
+        drop(pool);
+    }
+}
+
+// Connecting to an unspecified address means the same host.
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
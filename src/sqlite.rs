@@ -0,0 +1,185 @@
+// SQLite storage backend.
+//
+// A write-through copy of the App's records. The App holds everything in
+// memory and answers every query from there, so the tables are only read
+// once, at startup. Each keeps the record in the flat file format next to
+// its key, so a change is a single upsert instead of a rewrite of the whole
+// data set.
+//
+
+use crate::{
+    journal::Entry, App, ConvInfo, LoadError, LoadMode, LoadReport, MsgInfo, ReadMarker, RelStatus,
+    Relationship, Storage, UserInfo,
+};
+use chrono::SecondsFormat;
+use rusqlite::{params, Connection};
+use std::fmt;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    record TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS convs (
+    id TEXT PRIMARY KEY,
+    record TEXT NOT NULL
+);
+-- time_stamp is fixed width so it sorts as text, for loading in order.
+CREATE TABLE IF NOT EXISTS msgs (
+    id TEXT PRIMARY KEY,
+    time_stamp TEXT NOT NULL,
+    record TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS rels (
+    mem1 TEXT NOT NULL,
+    mem2 TEXT NOT NULL,
+    record TEXT NOT NULL,
+    PRIMARY KEY (mem1, mem2)
+);
+CREATE TABLE IF NOT EXISTS reads (
+    user TEXT NOT NULL,
+    conv TEXT NOT NULL,
+    record TEXT NOT NULL,
+    PRIMARY KEY (user, conv)
+);
+";
+
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+fn sql_err(e: rusqlite::Error) -> &'static str {
+    error!("{}", e);
+    "Error talking to the database!"
+}
+
+// A record in the flat file format, without the line break.
+fn record<T: fmt::Display>(r: &T) -> String {
+    r.to_string().trim_end_matches('\n').to_string()
+}
+
+impl SqliteStorage {
+    // Opens the database at `path`, creating it if needed.
+    pub fn open(path: &str) -> Result<SqliteStorage, &'static str> {
+        let conn = Connection::open(path).map_err(sql_err)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(sql_err)?;
+        conn.execute_batch(SCHEMA).map_err(sql_err)?;
+        Ok(SqliteStorage { conn })
+    }
+
+    // The rowid and record of every row `query` returns.
+    fn records(&self, query: &str) -> rusqlite::Result<Vec<(i64, String)>> {
+        let mut stmt = self.conn.prepare(query)?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        rows
+    }
+
+    // Applies every record in `table`, reporting bad ones by table and rowid.
+    fn load_table<F>(
+        &self,
+        app: &mut App,
+        mode: LoadMode,
+        table: &str,
+        order: &str,
+        entry: F,
+    ) -> Result<LoadReport, LoadError>
+    where
+        F: Fn(String) -> Entry,
+    {
+        let query = format!("SELECT rowid, record FROM {} ORDER BY {}", table, order);
+        let records = self
+            .records(&query)
+            .map_err(|e| LoadError::file(table, e.to_string()))?;
+        let mut report = LoadReport::default();
+        for (rowid, record) in records {
+            if let Err(e) = app.apply(entry(record)) {
+                report.skip(mode, e.at(table, rowid as usize))?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl Storage for SqliteStorage {
+    // Line numbers in the report are rowids.
+    fn load(&mut self, app: &mut App, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        let mut report = self.load_table(app, mode, "users", "rowid", Entry::User)?;
+        report.merge(self.load_table(app, mode, "convs", "rowid", Entry::Conv)?);
+        report.merge(self.load_table(app, mode, "msgs", "time_stamp, rowid", Entry::Msg)?);
+        report.merge(self.load_table(app, mode, "rels", "rowid", Entry::Rel)?);
+        report.merge(self.load_table(app, mode, "reads", "rowid", Entry::Read)?);
+        Ok(report)
+    }
+
+    fn put_user(&mut self, user: &UserInfo) -> Result<(), &'static str> {
+        self.conn
+            .execute(
+                "INSERT INTO users (id, record) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET record = excluded.record",
+                params![user.id().to_string(), record(user)],
+            )
+            .map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn put_conv(&mut self, conv: &ConvInfo) -> Result<(), &'static str> {
+        self.conn
+            .execute(
+                "INSERT INTO convs (id, record) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET record = excluded.record",
+                params![conv.id().to_string(), record(conv)],
+            )
+            .map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn put_msg(&mut self, msg: &MsgInfo) -> Result<(), &'static str> {
+        self.conn
+            .execute(
+                "INSERT INTO msgs (id, time_stamp, record) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET record = excluded.record",
+                params![
+                    msg.id.to_string(),
+                    msg.time_stamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+                    record(msg)
+                ],
+            )
+            .map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn put_rel(&mut self, members: [Uuid; 2], status: RelStatus) -> Result<(), &'static str> {
+        let mut key = members;
+        key.sort();
+        let rel = Relationship::new(members, Some(status));
+        self.conn
+            .execute(
+                "INSERT INTO rels (mem1, mem2, record) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (mem1, mem2) DO UPDATE SET record = excluded.record",
+                params![key[0].to_string(), key[1].to_string(), record(&rel)],
+            )
+            .map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn put_read(&mut self, read: &ReadMarker) -> Result<(), &'static str> {
+        self.conn
+            .execute(
+                "INSERT INTO reads (user, conv, record) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (user, conv) DO UPDATE SET record = excluded.record",
+                params![read.user.to_string(), read.conv.to_string(), record(read)],
+            )
+            .map_err(sql_err)?;
+        Ok(())
+    }
+
+    // Every put is committed as it happens.
+    fn flush(&mut self, _app: &App) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+// Storage backends for App.
+//
+// A backend loads everything when the app starts, is handed each change as it
+// happens, and is flushed on shutdown. Records are passed around in the same
+// ';' separated format as the flat files, so every backend stores the same
+// fields.
+//
+
+use crate::{
+    journal::{Entry, Journal},
+    persist, App, ConvInfo, LoadError, LoadMode, LoadReport, MsgInfo, ReadMarker, RelStatus,
+    Relationship, UserInfo,
+};
+use std::{fmt, path::Path};
+use uuid::Uuid;
+
+pub trait Storage: fmt::Debug + Send {
+    // Reads everything stored into the app. Bad records stop the load or are
+    // skipped into the report, depending on `mode`.
+    fn load(&mut self, app: &mut App, mode: LoadMode) -> Result<LoadReport, LoadError>;
+
+    // Adds or replaces a record, keyed by its id.
+    fn put_user(&mut self, user: &UserInfo) -> Result<(), &'static str>;
+    fn put_conv(&mut self, conv: &ConvInfo) -> Result<(), &'static str>;
+    fn put_msg(&mut self, msg: &MsgInfo) -> Result<(), &'static str>;
+    fn put_rel(&mut self, members: [Uuid; 2], status: RelStatus) -> Result<(), &'static str>;
+    // Keyed by the user and conversation.
+    fn put_read(&mut self, read: &ReadMarker) -> Result<(), &'static str>;
+
+    // Makes sure everything put so far is in its final place.
+    fn flush(&mut self, app: &App) -> Result<(), &'static str>;
+}
+
+// The msgs, convs, users, rels and reads files, plus a journal of changes since
+// they were last saved.
+#[derive(Debug)]
+pub struct FlatFileStorage {
+    msg_file: String,
+    conv_file: String,
+    user_file: String,
+    rel_file: String,
+    read_file: String,
+    journal_file: String,
+    journal: Option<Journal>,
+}
+
+impl FlatFileStorage {
+    // Uses the files named msgs, convs, users, rels, reads and journal in
+    // `dir`.
+    pub fn new(dir: &str) -> FlatFileStorage {
+        let file = |name| Path::new(dir).join(name).to_string_lossy().into_owned();
+        FlatFileStorage {
+            msg_file: file("msgs"),
+            conv_file: file("convs"),
+            user_file: file("users"),
+            rel_file: file("rels"),
+            read_file: file("reads"),
+            journal_file: file("journal"),
+            journal: None,
+        }
+    }
+
+    fn append(&mut self, entry: Entry) -> Result<(), &'static str> {
+        if let Some(journal) = self.journal.as_mut() {
+            if let Err(e) = journal.append(&entry) {
+                error!("{}", e);
+                return Err("Error writing journal!");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FlatFileStorage {
+    fn load(&mut self, app: &mut App, mode: LoadMode) -> Result<LoadReport, LoadError> {
+        App::recover(
+            &self.msg_file,
+            &self.conv_file,
+            &self.user_file,
+            &self.rel_file,
+            &self.read_file,
+        )?;
+        let mut report = app.load_users(&self.user_file, mode)?;
+        report.merge(app.load_convs(&self.conv_file, mode)?);
+        report.merge(app.load_msgs(&self.msg_file, mode)?);
+        report.merge(app.load_rels(&self.rel_file, mode)?);
+        report.merge(app.load_reads(&self.read_file, mode)?);
+
+        let manifest = persist::manifest_path(&self.msg_file);
+        let gen = persist::read_generation(&manifest)
+            .map_err(|e| LoadError::file(&manifest.to_string_lossy(), e.to_string()))?;
+        let (journal, entries) = Journal::open(Path::new(&self.journal_file), gen)
+            .map_err(|e| LoadError::file(&self.journal_file, e.to_string()))?;
+        if !entries.is_empty() {
+            info!("Replaying {} journal entries.", entries.len());
+        }
+        for (line, entry) in entries {
+            if let Err(e) = app.apply(entry) {
+                report.skip(mode, e.at(&self.journal_file, line))?;
+            }
+        }
+        self.journal = Some(journal);
+        Ok(report)
+    }
+
+    fn put_user(&mut self, user: &UserInfo) -> Result<(), &'static str> {
+        self.append(Entry::User(user.to_string()))
+    }
+
+    fn put_conv(&mut self, conv: &ConvInfo) -> Result<(), &'static str> {
+        self.append(Entry::Conv(conv.to_string()))
+    }
+
+    fn put_msg(&mut self, msg: &MsgInfo) -> Result<(), &'static str> {
+        self.append(Entry::Msg(msg.to_string()))
+    }
+
+    fn put_rel(&mut self, members: [Uuid; 2], status: RelStatus) -> Result<(), &'static str> {
+        let rel = Relationship::new(members, Some(status));
+        self.append(Entry::Rel(rel.to_string()))
+    }
+
+    fn put_read(&mut self, read: &ReadMarker) -> Result<(), &'static str> {
+        self.append(Entry::Read(read.to_string()))
+    }
+
+    // Folds the journal into a fresh save of the data files.
+    fn flush(&mut self, app: &App) -> Result<(), &'static str> {
+        let gen = app.save(
+            &self.msg_file,
+            &self.conv_file,
+            &self.user_file,
+            &self.rel_file,
+            &self.read_file,
+        )?;
+        if let Some(journal) = self.journal.as_mut() {
+            if let Err(e) = journal.reset(gen) {
+                error!("{}", e);
+                return Err("Error resetting journal!");
+            }
+        }
+        Ok(())
+    }
+}
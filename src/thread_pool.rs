@@ -1,51 +1,273 @@
 // Simple thread pool struct to use.
 //
+// A fixed number of workers run jobs from a bounded queue. execute waits for
+// room when the queue is full and try_execute turns the job away instead;
+// both hand back a JobHandle for the job's result. A job that panics is
+// caught and reported through its handle, and its worker carries on. A worker
+// that dies anyway is replaced. Dropping the pool stops it taking jobs and
+// joins every worker once the jobs already queued have run.
+//
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How many jobs can wait for a worker, unless the pool is given a size.
+pub const QUEUE_SIZE: usize = 1024;
 
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
+type Job = Box<dyn FnOnce() + Send + 'static>;
 
 #[derive(Debug)]
 pub struct ThreadPool {
-    threads: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    // None once the pool has stopped taking jobs.
+    sender: Option<SyncSender<Job>>,
+    shared: Arc<Shared>,
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+// What the workers share with the pool.
+#[derive(Debug, Default)]
+struct Shared {
+    // One slot per worker, swapped out when a worker is replaced.
+    threads: Mutex<Vec<Option<JoinHandle<()>>>>,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    completed: AtomicUsize,
+    panicked: AtomicUsize,
+}
+
+// A snapshot of what the pool is doing.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct PoolStats {
+    pub threads: usize,
+    pub active: usize,
+    pub idle: usize,
+    // Jobs waiting for a worker, counting any waiting for room in the queue.
+    pub queued: usize,
+    // Jobs run, including the ones that panicked.
+    pub completed: usize,
+    pub panicked: usize,
+}
+
+// Waits on the result of a job given to the pool.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    receiver: Receiver<Result<T, ()>>,
+}
+
+impl<T> JobHandle<T> {
+    // Blocks until the job has run.
+    pub fn join(self) -> Result<T, &'static str> {
+        match self.receiver.recv() {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(())) => Err("Job panicked!"),
+            Err(_) => Err("Job was dropped before it ran!"),
+        }
+    }
+}
 
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        let mut threads = Vec::new();
-        let (sender, receiver) = mpsc::channel();
+    pub fn new(size: usize) -> Result<ThreadPool, &'static str> {
+        ThreadPool::with_queue(size, QUEUE_SIZE)
+    }
+
+    // A pool with `size` workers and room for `queue` jobs waiting on them.
+    pub fn with_queue(size: usize, queue: usize) -> Result<ThreadPool, &'static str> {
+        if size == 0 {
+            return Err("Thread pool needs at least one thread!");
+        }
+        let (sender, receiver) = mpsc::sync_channel(queue);
         let receiver = Arc::new(Mutex::new(receiver));
-        for i in 0..size {
-            threads.push(Worker::new(i, Arc::clone(&receiver)));
+        let shared = Arc::new(Shared::default());
+        {
+            let mut threads = shared.threads.lock().unwrap();
+            for id in 0..size {
+                let thread = spawn_worker(id, Arc::clone(&receiver), Arc::clone(&shared));
+                threads.push(Some(thread));
+            }
         }
-        ThreadPool { threads, sender}
+        Ok(ThreadPool {
+            sender: Some(sender),
+            shared,
+        })
     }
-    pub fn execute<F>(&self, f: F)
+
+    // Queues a job, waiting for room if the queue is full.
+    pub fn execute<F, T>(&self, f: F) -> Result<JobHandle<T>, &'static str>
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(job).unwrap();
+        let sender = self.sender.as_ref().ok_or(STOPPED)?;
+        let (job, handle) = job(f);
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        if sender.send(job).is_err() {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(STOPPED);
+        }
+        Ok(handle)
     }
-}
 
-#[derive(Debug)]
-struct Worker {
-    id: usize,
-    thread: thread::JoinHandle<()>,
+    // Queues a job, unless the queue is full.
+    pub fn try_execute<F, T>(&self, f: F) -> Result<JobHandle<T>, &'static str>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let sender = self.sender.as_ref().ok_or(STOPPED)?;
+        let (job, handle) = job(f);
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        match sender.try_send(job) {
+            Ok(()) => Ok(handle),
+            Err(e) => {
+                self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+                match e {
+                    TrySendError::Full(_) => Err("Thread pool queue is full!"),
+                    TrySendError::Disconnected(_) => Err(STOPPED),
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let threads = self.shared.threads.lock().unwrap().len();
+        let active = self.shared.active.load(Ordering::SeqCst);
+        PoolStats {
+            threads,
+            active,
+            idle: threads.saturating_sub(active),
+            queued: self.shared.queued.load(Ordering::SeqCst),
+            completed: self.shared.completed.load(Ordering::SeqCst),
+            panicked: self.shared.panicked.load(Ordering::SeqCst),
+        }
+    }
+
+    // Stops taking jobs and waits up to `timeout` for the workers to finish
+    // the ones they have. Returns whether they all did.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        self.sender = None;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let finished = self
+                .shared
+                .threads
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|t| t.as_ref().is_none_or(JoinHandle::is_finished));
+            if finished {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
 }
 
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            println!("Worker {} got a job; executing.", id);
-            job();
-        });
-        Worker { id, thread }
+const STOPPED: &str = "Thread pool has stopped!";
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Workers stop asking for jobs once the channel is closed.
+        self.sender = None;
+        let size = self.shared.threads.lock().unwrap().len();
+        for id in 0..size {
+            // Taken again after joining, in case the worker was replaced.
+            loop {
+                let thread = self.shared.threads.lock().unwrap()[id].take();
+                match thread {
+                    Some(thread) => {
+                        let _ = thread.join();
+                    }
+                    None => break,
+                }
+            }
+        }
     }
 }
 
+// Wraps `f` to send its result, or that it panicked, to the handle.
+fn job<F, T>(f: F) -> (Job, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let job = Box::new(move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => {
+            let _ = sender.send(Ok(value));
+        }
+        Err(e) => {
+            let _ = sender.send(Err(()));
+            // On to the worker, which counts it.
+            panic::resume_unwind(e);
+        }
+    });
+    (job, JobHandle { receiver })
+}
+
+fn spawn_worker(
+    id: usize,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    shared: Arc<Shared>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let _sentinel = Sentinel {
+            id,
+            receiver: Arc::clone(&receiver),
+            shared: Arc::clone(&shared),
+        };
+        loop {
+            // The lock is let go at the end of this statement, not held
+            // while the job runs. Nothing panics while holding it, but a
+            // poisoned lock would otherwise take every replacement down too.
+            let job = receiver
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .recv();
+            let job = match job {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+            shared.queued.fetch_sub(1, Ordering::SeqCst);
+            shared.active.fetch_add(1, Ordering::SeqCst);
+            debug!("Worker {} got a job; executing.", id);
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("Worker {} caught a panicking job.", id);
+                shared.panicked.fetch_add(1, Ordering::SeqCst);
+            }
+            shared.active.fetch_sub(1, Ordering::SeqCst);
+            shared.completed.fetch_add(1, Ordering::SeqCst);
+        }
+    })
+}
+
+// Starts a new worker in place of one dying of a panic.
+struct Sentinel {
+    id: usize,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    shared: Arc<Shared>,
+}
 
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        error!("Worker {} died; starting another.", self.id);
+        let thread = spawn_worker(
+            self.id,
+            Arc::clone(&self.receiver),
+            Arc::clone(&self.shared),
+        );
+        self.shared.threads.lock().unwrap()[self.id] = Some(thread);
+    }
+}
@@ -0,0 +1,466 @@
+// WebSocket transport, for clients that can't open a raw TCP socket.
+//
+// A connection starts as an HTTP/1.1 GET asking to upgrade (RFC 6455). After
+// the handshake each text message is one command, answered with one text
+// message, and pushes arrive as text messages of their own. Pings are
+// answered with pongs, and either side may end things with a close frame.
+//
+
+use crate::{
+    http::{HttpError, Request},
+    lock, push_feed, run_request, set_idle_timeout, App, Command, Listener, Session,
+    ShutdownHandle, ThreadPool, MAX_FRAME, WRITE_TIMEOUT,
+};
+use base64ct::{Base64, Encoding};
+use std::{
+    fmt,
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// Appended to the client's key before hashing, as set by the RFC.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const LINGER: Duration = Duration::from_secs(1);
+
+// Close codes we send.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+// Whether a peer may send `code` in a close frame. 1005, 1006 and 1015 only
+// describe a close locally, and the rest of 1000-2999 is unassigned.
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug)]
+pub enum WsError {
+    Io(io::Error),
+    Handshake(&'static str),
+    Protocol(&'static str),
+    TooLong,
+    InvalidUtf8,
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WsError::Io(e) => write!(f, "IO ERROR: {}", e),
+            WsError::Handshake(e) => write!(f, "BAD HANDSHAKE: {}", e),
+            WsError::Protocol(e) => write!(f, "PROTOCOL ERROR: {}", e),
+            WsError::TooLong => write!(f, "MESSAGE LONGER THAN {} BYTES", MAX_FRAME),
+            WsError::InvalidUtf8 => write!(f, "MESSAGE IS NOT VALID UTF-8"),
+        }
+    }
+}
+
+impl From<io::Error> for WsError {
+    fn from(e: io::Error) -> WsError {
+        WsError::Io(e)
+    }
+}
+
+impl WsError {
+    // The close code to send the peer, if the connection is still usable.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WsError::Io(_) | WsError::Handshake(_) => None,
+            WsError::Protocol(_) => Some(CLOSE_PROTOCOL),
+            WsError::TooLong => Some(CLOSE_TOO_BIG),
+            WsError::InvalidUtf8 => Some(CLOSE_INVALID_DATA),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsFrame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    // Whether the payload arrived masked. Clients must mask, servers mustn't.
+    pub masked: bool,
+    pub payload: Vec<u8>,
+}
+
+impl WsFrame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> WsFrame {
+        WsFrame {
+            fin: true,
+            opcode,
+            masked: false,
+            payload,
+        }
+    }
+
+    pub fn text(text: &str) -> WsFrame {
+        WsFrame::new(Opcode::Text, text.as_bytes().to_vec())
+    }
+
+    pub fn close(code: u16, reason: &str) -> WsFrame {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        WsFrame::new(Opcode::Close, payload)
+    }
+
+    // The status code of a close frame, if it gave one.
+    pub fn close_code(&self) -> Option<u16> {
+        match self.payload.as_slice() {
+            [hi, lo, ..] if self.opcode == Opcode::Close => Some(u16::from_be_bytes([*hi, *lo])),
+            _ => None,
+        }
+    }
+
+    // Reads one frame, unmasking it. Returns None once the stream is closed.
+    pub fn read<R: Read>(stream: &mut R) -> Result<Option<WsFrame>, WsError> {
+        let mut head = [0; 2];
+        match stream.read(&mut head[..1])? {
+            0 => return Ok(None),
+            _ => stream.read_exact(&mut head[1..])?,
+        }
+        if head[0] & 0x70 != 0 {
+            return Err(WsError::Protocol("reserved bits set"));
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = match Opcode::from_bits(head[0] & 0x0F) {
+            Some(op) => op,
+            None => return Err(WsError::Protocol("unknown opcode")),
+        };
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if opcode.is_control() && (len > 125 || !fin) {
+            return Err(WsError::Protocol("control frames must be short and whole"));
+        }
+        if len > MAX_FRAME as u64 {
+            return Err(WsError::TooLong);
+        }
+        let mut mask = [0; 4];
+        if masked {
+            stream.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+        Ok(Some(WsFrame {
+            fin,
+            opcode,
+            masked,
+            payload,
+        }))
+    }
+
+    // Writes the frame in one go, masked with `mask` if given.
+    pub fn write<W: Write>(&self, stream: &mut W, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(self.payload.len() + 14);
+        buf.push(if self.fin { 0x80 } else { 0 } | self.opcode.bits());
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            len if len < 126 => buf.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.push(mask_bit | 126);
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                buf.push(mask_bit | 127);
+                buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = buf.len();
+        match mask {
+            Some(mask) => {
+                buf.extend_from_slice(&mask);
+                buf.extend_from_slice(&self.payload);
+                apply_mask(&mut buf[start + 4..], mask);
+            }
+            None => buf.extend_from_slice(&self.payload),
+        }
+        stream.write_all(&buf)?;
+        stream.flush()
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+// Reads the client's upgrade request, returning its Sec-WebSocket-Key.
+fn read_handshake<R: BufRead>(stream: &mut R) -> Result<String, WsError> {
+    let req = match Request::read_head(stream) {
+        Ok(Some(req)) => req,
+        Ok(None) => return Err(WsError::Handshake("request ended early")),
+        Err(HttpError::Io(e)) => return Err(WsError::Io(e)),
+        Err(HttpError::BadRequest(e)) => return Err(WsError::Handshake(e)),
+        Err(HttpError::TooLarge) => return Err(WsError::Handshake("request too large")),
+    };
+    if req.method != "GET" || req.version != "HTTP/1.1" {
+        return Err(WsError::Handshake("not an HTTP/1.1 GET"));
+    }
+    let upgrade = req
+        .header("upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    if !upgrade || !req.header_has("connection", "upgrade") {
+        return Err(WsError::Handshake("not a websocket upgrade"));
+    }
+    if req.header("sec-websocket-version") != Some("13") {
+        return Err(WsError::Handshake("unsupported websocket version"));
+    }
+    match req.header("sec-websocket-key") {
+        Some(key) => Ok(key.to_string()),
+        None => Err(WsError::Handshake("no Sec-WebSocket-Key")),
+    }
+}
+
+#[derive(Debug)]
+pub struct WsServer {
+    listener: Listener,
+}
+
+impl WsServer {
+    pub fn bind(addr: SocketAddr, threads: usize) -> io::Result<WsServer> {
+        Ok(WsServer {
+            listener: Listener::bind(addr, threads)?,
+        })
+    }
+
+    pub fn with_shutdown(mut self, shutdown: ShutdownHandle) -> io::Result<WsServer> {
+        self.listener.set_shutdown(shutdown)?;
+        Ok(self)
+    }
+
+    // Like TcpServer::with_limits.
+    pub fn with_limits(mut self, max_conns: usize, idle: Duration) -> WsServer {
+        self.listener.set_limits(max_conns, idle);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.listener.shutdown_handle()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key.
+    pub fn accept_key(key: &str) -> String {
+        let mut sha = sha1_smol::Sha1::new();
+        sha.update(key.trim().as_bytes());
+        sha.update(ACCEPT_GUID.as_bytes());
+        Base64::encode_string(&sha.digest().bytes())
+    }
+
+    // Accepts connections until shut down, like TcpServer::serve.
+    pub fn serve(self, app: Arc<Mutex<App>>) {
+        let shutdown = self.shutdown_handle();
+        self.listener
+            .serve(move |stream, pool| WsServer::handle(stream, pool, &app, &shutdown));
+    }
+
+    fn handle(
+        stream: TcpStream,
+        pool: &ThreadPool,
+        app: &Arc<Mutex<App>>,
+        shutdown: &ShutdownHandle,
+    ) {
+        let writer = match stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(s) => Arc::new(Mutex::new(s)),
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        // As set by the listener; lifted while subscribed.
+        let idle = stream.read_timeout().unwrap_or(None);
+        let mut reader = BufReader::new(stream);
+        let key = match read_handshake(&mut reader) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("{}", e);
+                let resp = format!(
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\n\r\n{}",
+                    e.to_string().len(),
+                    e
+                );
+                let _ = lock(&writer).write_all(resp.as_bytes());
+                return;
+            }
+        };
+        let resp = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            WsServer::accept_key(&key)
+        );
+        if let Err(e) = lock(&writer).write_all(resp.as_bytes()) {
+            warn!("{}", e);
+            return;
+        }
+
+        let send = |frame: WsFrame| frame.write(&mut *lock(&writer), None);
+        let session = Arc::new(Mutex::new(Session::new()));
+        loop {
+            let req = match WsServer::read_message(&mut reader, &send) {
+                Ok(Some(req)) => req,
+                Ok(None) => {
+                    // Either the client closed or the shutdown closed our
+                    // side; a second close frame is harmless in the former.
+                    if shutdown.is_stopping() {
+                        let _ = send(WsFrame::close(CLOSE_GOING_AWAY, "server shutting down"));
+                    }
+                    break;
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    if let Some(code) = e.close_code() {
+                        let _ = send(WsFrame::close(code, &e.to_string()));
+                        WsServer::linger(&mut reader);
+                    }
+                    break;
+                }
+            };
+            info!("Request: {}", Command::redact(&req));
+            let resp = run_request(pool, app, &session, req);
+            if let Err(e) = send(WsFrame::text(&resp)) {
+                warn!("{}", e);
+                break;
+            }
+            let mut session = lock(&session);
+            if session.ending() {
+                shutdown.stop();
+            }
+            if let Some(feed) = session.take_feed() {
+                push_feed(feed, Arc::clone(&writer), |stream, frame| {
+                    WsFrame::text(frame).write(stream, None)
+                });
+            }
+            set_idle_timeout(reader.get_ref(), &session, idle);
+        }
+        lock(app).unsubscribe(&mut lock(&session));
+    }
+
+    // Throws away whatever the client still sends, for a moment, so closing
+    // with unread input doesn't reset the connection before it reads our close.
+    fn linger(reader: &mut BufReader<TcpStream>) {
+        if reader.get_ref().set_read_timeout(Some(LINGER)).is_ok() {
+            let _ = io::copy(reader, &mut io::sink());
+        }
+    }
+
+    // Reads frames until a whole text message has arrived, answering pings
+    // and close frames along the way. Returns None once the client has closed.
+    fn read_message<R, F>(reader: &mut R, send: &F) -> Result<Option<String>, WsError>
+    where
+        R: Read,
+        F: Fn(WsFrame) -> io::Result<()>,
+    {
+        let mut message: Option<Vec<u8>> = None;
+        loop {
+            let frame = match WsFrame::read(reader)? {
+                Some(f) => f,
+                None => return Ok(None),
+            };
+            if !frame.masked {
+                return Err(WsError::Protocol("client frames must be masked"));
+            }
+            match frame.opcode {
+                Opcode::Ping => send(WsFrame::new(Opcode::Pong, frame.payload))?,
+                Opcode::Pong => {}
+                Opcode::Close => {
+                    // Their code isn't echoed back. A close the client
+                    // shouldn't have sent is a protocol error, and anything
+                    // else ends normally.
+                    let code = match (frame.payload.len(), frame.close_code()) {
+                        (0, _) => CLOSE_NORMAL,
+                        (_, Some(code)) if valid_close_code(code) => CLOSE_NORMAL,
+                        _ => CLOSE_PROTOCOL,
+                    };
+                    send(WsFrame::close(code, ""))?;
+                    return Ok(None);
+                }
+                Opcode::Binary => {
+                    let _ = send(WsFrame::close(CLOSE_UNSUPPORTED, "text only"));
+                    return Ok(None);
+                }
+                Opcode::Text if message.is_some() => {
+                    return Err(WsError::Protocol("expected a continuation frame"))
+                }
+                Opcode::Continuation if message.is_none() => {
+                    return Err(WsError::Protocol("nothing to continue"))
+                }
+                Opcode::Text | Opcode::Continuation => {
+                    let buf = message.get_or_insert_with(Vec::new);
+                    if buf.len() + frame.payload.len() > MAX_FRAME {
+                        return Err(WsError::TooLong);
+                    }
+                    buf.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return match String::from_utf8(message.take().unwrap()) {
+                            Ok(s) => Ok(Some(s)),
+                            Err(_) => Err(WsError::InvalidUtf8),
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
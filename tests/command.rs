@@ -0,0 +1,1019 @@
+// Tests for the command grammar.
+//
+
+use chat_server::*;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+#[test]
+fn parses_get_user() {
+    assert_eq!(
+        Command::parse("GET USER NAME Curtis Jones"),
+        Ok(Command::GetUser {
+            field: UserField::Name,
+            search: String::from("Curtis Jones"),
+            mult: false,
+        })
+    );
+    assert_eq!(
+        Command::parse("GET USER MULT EMAIL mail"),
+        Ok(Command::GetUser {
+            field: UserField::Email,
+            search: String::from("mail"),
+            mult: true,
+        })
+    );
+}
+
+#[test]
+fn parses_get_conv() {
+    let a = Uuid::new_v4();
+    let b = Uuid::new_v4();
+    assert_eq!(
+        Command::parse(&format!("GET CONV MULT MEMBERS {},{},", a, b)),
+        Ok(Command::GetConv {
+            query: ConvQuery::Members(vec![a, b]),
+            mult: true,
+        })
+    );
+    assert_eq!(
+        Command::parse("GET CONV NAME Basic"),
+        Ok(Command::GetConv {
+            query: ConvQuery::Name(String::from("Basic")),
+            mult: false,
+        })
+    );
+}
+
+#[test]
+fn rejects_bad_commands() {
+    assert_eq!(Command::parse("  "), Err(ParseError::Empty));
+    assert_eq!(
+        Command::parse("PUT USER"),
+        Err(ParseError::UnknownVerb(String::from("PUT")))
+    );
+    assert_eq!(
+        Command::parse("GET THING"),
+        Err(ParseError::UnknownTarget(String::from("THING")))
+    );
+    assert_eq!(Command::parse("GET"), Err(ParseError::Missing("TARGET")));
+    assert_eq!(
+        Command::parse("GET USER MULT ID 1"),
+        Err(ParseError::InvalidOption(String::from("ID")))
+    );
+    assert_eq!(
+        Command::parse("GET USER NAME"),
+        Err(ParseError::Missing("SEARCH TERM"))
+    );
+    assert_eq!(
+        Command::parse("GET CONV MEMBERS nope"),
+        Err(ParseError::InvalidId(String::from("nope")))
+    );
+}
+
+#[test]
+fn bad_requests_do_not_panic() {
+    let mut app = App::new();
+    for req in &[
+        "",
+        "GET",
+        "GET MSG",
+        "GET CONV MULT",
+        "\0\0\0",
+        "GET USER ID",
+    ] {
+        app.execute(&mut Session::new(), req.to_string());
+    }
+}
+
+#[test]
+fn parses_get_rel_and_get_msg() {
+    let a = Uuid::new_v4();
+    let b = Uuid::new_v4();
+    assert_eq!(
+        Command::parse(&format!("GET REL {} {}", a, b)),
+        Ok(Command::GetRel(a, b))
+    );
+    assert_eq!(
+        Command::parse(&format!("GET MSG CONV {}", a)),
+        Ok(Command::GetMsg(MsgQuery::Conv {
+            conv: a,
+            newest: 50,
+        }))
+    );
+    assert_eq!(
+        Command::parse(&format!("GET MSG CONV {} 5", a)),
+        Ok(Command::GetMsg(MsgQuery::Conv { conv: a, newest: 5 }))
+    );
+    assert_eq!(
+        Command::parse(&format!("GET MSG USER {}", b)),
+        Ok(Command::GetMsg(MsgQuery::Author {
+            user: b,
+            cursor: None,
+            limit: 50,
+        }))
+    );
+    assert_eq!(
+        Command::parse(&format!("GET MSG USER {} AFTER {} 10", b, a)),
+        Ok(Command::GetMsg(MsgQuery::Author {
+            user: b,
+            cursor: Some(Cursor::After(Position::Msg(a))),
+            limit: 10,
+        }))
+    );
+    assert!(matches!(
+        Command::parse("GET MSG TIME 2020-05-12T04:59:57+00:00 2020-06-12T04:59:57+00:00"),
+        Ok(Command::GetMsg(MsgQuery::Time {
+            cursor: None,
+            limit: 50,
+            ..
+        }))
+    ));
+    assert!(matches!(
+        Command::parse(&format!(
+            "GET MSG TIME 2020-05-12T04:59:57Z 2020-06-12T04:59:57Z BEFORE {} 5",
+            a
+        )),
+        Ok(Command::GetMsg(MsgQuery::Time {
+            cursor: Some(Cursor::Before(Position::Msg(_))),
+            limit: 5,
+            ..
+        }))
+    ));
+    assert_eq!(
+        Command::parse("GET MSG TIME yesterday today"),
+        Err(ParseError::InvalidTime(String::from("yesterday")))
+    );
+    assert_eq!(
+        Command::parse(&format!("GET MSG CONV {} many", a)),
+        Err(ParseError::InvalidNumber(String::from("many")))
+    );
+    assert_eq!(
+        Command::parse(&format!("GET MSG CONV {} BEFORE {} 10", a, b)),
+        Ok(Command::GetMsg(MsgQuery::Page {
+            conv: a,
+            cursor: Cursor::Before(Position::Msg(b)),
+            limit: 10,
+        }))
+    );
+    assert!(matches!(
+        Command::parse(&format!("GET MSG CONV {} AFTER 2020-05-12T04:59:57Z", a)),
+        Ok(Command::GetMsg(MsgQuery::Page {
+            cursor: Cursor::After(Position::Time(_)),
+            limit: 50,
+            ..
+        }))
+    ));
+    assert_eq!(
+        Command::parse(&format!("GET MSG CONV {} AFTER yesterday", a)),
+        Err(ParseError::InvalidCursor(String::from("yesterday")))
+    );
+}
+
+// Pulls the id out of the first record of a response.
+fn first_id(resp: &str) -> &str {
+    resp.split(';').next().unwrap()
+}
+
+fn login(app: &mut App, email: &str) -> Session {
+    let mut session = Session::new();
+    let resp = app.execute(&mut session, format!("LOGIN {} password", email));
+    assert!(resp.starts_with("LOGGED IN"), "{}", resp);
+    session
+}
+
+#[test]
+fn executes_get_rel_and_get_msg() {
+    let mut app = App::new();
+    app.register("Curtis Jones", "curtis@mail.ca", "password")
+        .unwrap();
+    app.add_user("Sarah Parsons", "sarah@mail.ca").unwrap();
+    let mut me = login(&mut app, "curtis@mail.ca");
+    let curtis = app.get_user("NAME", "Curtis").unwrap();
+    let sarah = app.get_user("NAME", "Sarah").unwrap();
+    let curtis_id = curtis.read().unwrap().id();
+    let sarah_id = sarah.read().unwrap().id();
+    let basic = app
+        .add_conv("Basic", vec![User::clone(&curtis), User::clone(&sarah)])
+        .unwrap();
+    let conv = basic.read().unwrap().id();
+
+    assert_eq!(
+        app.execute(&mut me, format!("GET REL {} {}", curtis_id, sarah_id)),
+        "Neutral"
+    );
+
+    for text in &["one", "two", "three"] {
+        let resp = app.execute(&mut me, format!("GET MSG CONV {} 1", conv));
+        assert!(!resp.contains(text), "{}", resp);
+        let msg = app
+            .send_msg(User::clone(&curtis), Conversation::clone(&basic), text)
+            .unwrap();
+        // The same time a replay of the message would give the conversation.
+        assert_eq!(
+            basic.read().unwrap().last_msg(),
+            msg.read().unwrap().time_stamp
+        );
+    }
+
+    let newest = app.execute(&mut me, format!("GET MSG CONV {} 2", conv));
+    assert_eq!(newest.lines().count(), 2);
+    assert!(newest.contains(";two;") && newest.contains(";three;"));
+
+    let msg = app.execute(&mut me, format!("GET MSG ID {}", first_id(&newest)));
+    assert!(msg.contains(";two;"), "{}", msg);
+
+    let by_curtis = app.execute(&mut me, format!("GET MSG USER {}", curtis_id));
+    assert_eq!(by_curtis.lines().count(), 3);
+    assert_eq!(
+        app.execute(&mut me, format!("GET MSG USER {}", sarah_id)),
+        "NO MSGS FOUND"
+    );
+}
+
+#[test]
+fn executes_write_commands() {
+    let mut app = App::new();
+    let mut anon = Session::new();
+    let curtis = app.execute(
+        &mut anon,
+        String::from("ADD USER curtis@mail.ca password Curtis Jones"),
+    );
+    assert!(
+        curtis.contains(";Curtis Jones;curtis@mail.ca;"),
+        "{}",
+        curtis
+    );
+    let sarah = app.execute(
+        &mut anon,
+        String::from("ADD USER sarah@mail.ca password Sarah Parsons"),
+    );
+    app.execute(
+        &mut anon,
+        String::from("ADD USER abby@mail.ca password Abby-gail Jones"),
+    );
+    assert!(app
+        .execute(
+            &mut anon,
+            String::from("ADD USER curtis@mail.ca password Curtis Jones")
+        )
+        .starts_with("ERROR"));
+    assert!(app
+        .execute(
+            &mut anon,
+            String::from("ADD USER not-an-email password Nobody")
+        )
+        .starts_with("ERROR"));
+    assert!(app
+        .execute(
+            &mut anon,
+            String::from("ADD USER short@mail.ca pass Shorty")
+        )
+        .starts_with("ERROR"));
+
+    let (curtis, sarah) = (first_id(&curtis), first_id(&sarah));
+    let mut me = login(&mut app, "curtis@mail.ca");
+    let mut abby_session = login(&mut app, "abby@mail.ca");
+    // The caller is always a member of a conversation they start.
+    let conv = app.execute(&mut me, format!("ADD CONV {} Basic Chat", sarah));
+    assert!(conv.contains(";Basic Chat;"), "{}", conv);
+    assert!(conv.contains(curtis), "{}", conv);
+    let conv = first_id(&conv).to_string();
+    assert!(app
+        .execute(&mut me, format!("ADD CONV {},{} Twice", sarah, sarah))
+        .starts_with("ERROR"));
+
+    let msg = app.execute(&mut me, format!("SEND MSG {} Hello there; friend", conv));
+    assert!(msg.contains(";Hello there\\s friend;"), "{}", msg);
+    assert!(msg.contains(curtis), "{}", msg);
+    assert_eq!(
+        app.execute(&mut abby_session, format!("SEND MSG {} Let me in", conv)),
+        "PERMISSION DENIED"
+    );
+    assert_eq!(
+        app.execute(&mut me, format!("SEND MSG {} Hi", Uuid::new_v4())),
+        "INVALID CONV PROVIDED"
+    );
+    assert_eq!(
+        app.execute(&mut me, format!("GET MSG CONV {}", conv))
+            .lines()
+            .count(),
+        1
+    );
+    assert_eq!(
+        app.execute(&mut anon, format!("GET MSG CONV {}", conv)),
+        "NOT LOGGED IN"
+    );
+}
+
+#[test]
+fn parses_login_commands() {
+    assert_eq!(
+        Command::parse("LOGIN me@mail.ca hunter22"),
+        Ok(Command::Login {
+            email: String::from("me@mail.ca"),
+            password: String::from("hunter22"),
+        })
+    );
+    assert_eq!(
+        Command::parse("LOGIN TOKEN abc"),
+        Ok(Command::LoginToken(String::from("abc")))
+    );
+    assert_eq!(Command::parse("LOGOUT"), Ok(Command::Logout));
+    assert_eq!(
+        Command::parse("LOGIN me@mail.ca"),
+        Err(ParseError::Missing("PASSWORD"))
+    );
+    assert_eq!(
+        Command::redact("LOGIN me@mail.ca hunter22"),
+        "LOGIN me@mail.ca ..."
+    );
+    assert_eq!(
+        Command::redact("ADD USER me@mail.ca hunter22 Me"),
+        "ADD USER me@mail.ca ..."
+    );
+}
+
+#[test]
+fn shared_app_registers_and_logs_in() {
+    let app = std::sync::Mutex::new(App::new());
+    let mut session = Session::new();
+    let mut run = |req: &str| App::execute_shared(&app, &mut session, req.to_string());
+
+    let added = run("ADD USER dan@mail.ca password Dan Smith");
+    assert!(added.contains(";Dan Smith;"), "{}", added);
+    assert!(run("ADD USER DAN@mail.ca password Dan").contains("already exists"));
+    assert!(run("ADD USER sam@mail.ca short Sam").contains("at least 8"));
+    assert!(run("LOGIN dan@mail.ca wrongpass").contains("Wrong email or password"));
+    assert!(run("LOGIN nobody@mail.ca password").contains("Wrong email or password"));
+    assert!(run("LOGIN dan@mail.ca password").starts_with("LOGGED IN"));
+    assert_eq!(run("LOGOUT"), "LOGGED OUT");
+}
+
+#[test]
+fn tokens_expire_and_are_capped_per_user() {
+    let mut app = App::new();
+    app.register("Dan Smith", "dan@mail.ca", "password")
+        .unwrap();
+    let token_of = |resp: &str| resp.rsplit(' ').next().unwrap().to_string();
+
+    app.set_token_lifetime(std::time::Duration::from_millis(200));
+    let mut me = login(&mut app, "dan@mail.ca");
+    let token = token_of(&app.execute(
+        &mut Session::new(),
+        String::from("LOGIN dan@mail.ca password"),
+    ));
+    let resume = format!("LOGIN TOKEN {}", token);
+    assert!(app
+        .execute(&mut Session::new(), resume.clone())
+        .starts_with("LOGGED IN"));
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert!(app
+        .execute(&mut Session::new(), resume)
+        .contains("INVALID TOKEN"));
+    assert!(app
+        .execute(&mut me, String::from("GET USER NAME Dan"))
+        .contains("NOT LOGGED IN"));
+
+    app.set_token_lifetime(TOKEN_LIFETIME);
+    let tokens: Vec<String> = (0..=MAX_TOKENS)
+        .map(|_| {
+            token_of(&app.execute(
+                &mut Session::new(),
+                String::from("LOGIN dan@mail.ca password"),
+            ))
+        })
+        .collect();
+    let resume = |token: &String| format!("LOGIN TOKEN {}", token);
+    assert!(app
+        .execute(&mut Session::new(), resume(&tokens[0]))
+        .contains("INVALID TOKEN"));
+    for token in &tokens[1..] {
+        assert!(app
+            .execute(&mut Session::new(), resume(token))
+            .starts_with("LOGGED IN"));
+    }
+}
+
+#[test]
+fn only_members_and_admins_read_conversations() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+        ("Abby-gail Jones", "abby@mail.ca"),
+        ("Admin", "admin@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let admin = app.get_user("NAME", "Admin").unwrap();
+    app.set_admin(&admin, true).unwrap();
+    let sarah = app.get_user("NAME", "Sarah").unwrap();
+    let sarah_id = sarah.read().unwrap().id();
+
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut abby = login(&mut app, "abby@mail.ca");
+    let mut admin = login(&mut app, "admin@mail.ca");
+    let conv = app.execute(&mut curtis, format!("ADD CONV {} Secret", sarah_id));
+    let conv = first_id(&conv).to_string();
+    let msg = app.execute(&mut curtis, format!("SEND MSG {} psst", conv));
+    let msg = first_id(&msg).to_string();
+
+    let curtis_id = app.execute(&mut curtis, String::from("GET USER NAME Curtis"));
+    let curtis_id = first_id(&curtis_id).to_string();
+    // Asking for it by id is denied, but searches just find nothing, so they
+    // don't give away that it's there.
+    let requests = [
+        (String::from("GET CONV NAME Secret"), "NO CONVS FOUND"),
+        (format!("GET CONV MEMBERS {}", sarah_id), "NO CONVS FOUND"),
+        (format!("GET MSG CONV {}", conv), "PERMISSION DENIED"),
+        (format!("GET MSG ID {}", msg), "PERMISSION DENIED"),
+        (format!("GET MSG USER {}", curtis_id), "NO MSGS FOUND"),
+    ];
+    for (req, hidden) in &requests {
+        let resp = app.execute(&mut curtis, req.clone());
+        assert!(
+            resp.contains(&conv) || resp.contains("psst"),
+            "{}: {}",
+            req,
+            resp
+        );
+        assert_eq!(app.execute(&mut abby, req.clone()), *hidden, "{}", req);
+        let resp = app.execute(&mut admin, req.clone());
+        assert!(
+            resp.contains(&conv) || resp.contains("psst"),
+            "{}: {}",
+            req,
+            resp
+        );
+    }
+
+    // Only the pair and admins see a relationship.
+    let rel = format!("GET REL {} {}", curtis_id, sarah_id);
+    assert_eq!(app.execute(&mut curtis, rel.clone()), "Neutral");
+    assert_eq!(app.execute(&mut abby, rel.clone()), "PERMISSION DENIED");
+    assert_eq!(app.execute(&mut admin, rel), "Neutral");
+
+    // Searches only return what the caller may see.
+    app.execute(&mut abby, format!("ADD CONV {} Open", sarah_id));
+    let all = app.execute(&mut abby, String::from("GET CONV MULT NAME e"));
+    assert_eq!(all.lines().count(), 1, "{}", all);
+    assert!(all.contains(";Open;"), "{}", all);
+    assert_eq!(
+        app.execute(&mut admin, String::from("GET CONV MULT NAME e"))
+            .lines()
+            .count(),
+        2
+    );
+    assert_eq!(
+        app.execute(&mut abby, String::from("GET CONV NAME Nothing")),
+        "NO CONVS FOUND"
+    );
+
+    // Admins read everything but only members post.
+    assert_eq!(
+        app.execute(&mut admin, format!("SEND MSG {} hi", conv)),
+        "PERMISSION DENIED"
+    );
+}
+
+#[test]
+fn blocked_users_cannot_reach_the_blocker() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+        ("Abby-gail Jones", "abby@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let id = |app: &App, name| app.get_user("NAME", name).unwrap().read().unwrap().id();
+    let (curtis_id, sarah_id) = (id(&app, "Curtis"), id(&app, "Sarah"));
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    let mut abby = login(&mut app, "abby@mail.ca");
+    let conv = app.execute(&mut curtis, format!("ADD CONV {} Basic", sarah_id));
+    let conv = first_id(&conv).to_string();
+
+    assert_eq!(
+        app.execute(&mut sarah, format!("BLOCK {}", curtis_id)),
+        format!("Blocked,{}", sarah_id)
+    );
+    assert_eq!(
+        app.execute(&mut sarah, format!("GET REL {} {}", sarah_id, curtis_id)),
+        format!("Blocked,{}", sarah_id)
+    );
+
+    // Curtis can't find, message or start a conversation with Sarah.
+    assert_eq!(
+        app.execute(&mut curtis, String::from("GET USER NAME Sarah")),
+        "NO USER FOUND"
+    );
+    assert_eq!(
+        app.execute(&mut curtis, format!("GET USER ID {}", sarah_id)),
+        "NO USER FOUND"
+    );
+    assert_eq!(
+        app.execute(&mut curtis, String::from("GET USER MULT EMAIL @mail.ca"))
+            .lines()
+            .count(),
+        2
+    );
+    assert!(app
+        .execute(&mut curtis, format!("SEND MSG {} hello?", conv))
+        .starts_with("ERROR"));
+    assert!(app
+        .execute(&mut curtis, format!("ADD CONV {} Again", sarah_id))
+        .starts_with("ERROR"));
+    assert!(app
+        .execute(
+            &mut abby,
+            format!("ADD CONV {},{} Group", curtis_id, sarah_id)
+        )
+        .starts_with("ERROR"));
+
+    // Sarah still can, and only she can lift the block.
+    assert!(app
+        .execute(&mut sarah, String::from("GET USER NAME Curtis"))
+        .contains("curtis@mail.ca"));
+    assert!(app
+        .execute(&mut sarah, format!("SEND MSG {} bye", conv))
+        .contains(";bye;"));
+    assert!(app
+        .execute(&mut curtis, format!("UNBLOCK {}", sarah_id))
+        .starts_with("ERROR"));
+    assert!(app
+        .execute(&mut curtis, format!("BLOCK {}", sarah_id))
+        .starts_with("ERROR"));
+    assert_eq!(
+        app.execute(&mut sarah, format!("UNBLOCK {}", curtis_id)),
+        "Neutral"
+    );
+    assert!(app
+        .execute(&mut curtis, String::from("GET USER NAME Sarah"))
+        .contains("sarah@mail.ca"));
+    assert!(app
+        .execute(&mut curtis, format!("SEND MSG {} hello!", conv))
+        .contains(";hello!;"));
+}
+
+#[test]
+fn friend_requests_follow_the_workflow() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let id = |app: &App, name| app.get_user("NAME", name).unwrap().read().unwrap().id();
+    let (curtis_id, sarah_id) = (id(&app, "Curtis"), id(&app, "Sarah"));
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    let step = |app: &mut App, session: &mut Session, action: &str, other| {
+        app.execute(session, format!("FRIEND {} {}", action, other))
+    };
+
+    assert!(step(&mut app, &mut curtis, "ACCEPT", sarah_id).starts_with("ERROR"));
+    assert_eq!(
+        step(&mut app, &mut curtis, "REQUEST", sarah_id),
+        format!("Pending,{}", curtis_id)
+    );
+    assert!(step(&mut app, &mut curtis, "REQUEST", sarah_id).starts_with("ERROR"));
+    assert!(step(&mut app, &mut curtis, "ACCEPT", sarah_id).starts_with("ERROR"));
+    assert!(step(&mut app, &mut sarah, "REQUEST", curtis_id).starts_with("ERROR"));
+    assert!(step(&mut app, &mut sarah, "CANCEL", curtis_id).starts_with("ERROR"));
+    assert_eq!(step(&mut app, &mut sarah, "DECLINE", curtis_id), "Neutral");
+
+    step(&mut app, &mut sarah, "REQUEST", curtis_id);
+    assert_eq!(step(&mut app, &mut sarah, "CANCEL", curtis_id), "Neutral");
+
+    step(&mut app, &mut sarah, "REQUEST", curtis_id);
+    assert_eq!(step(&mut app, &mut curtis, "ACCEPT", sarah_id), "Friends");
+    assert!(step(&mut app, &mut curtis, "DECLINE", sarah_id).starts_with("ERROR"));
+    assert_eq!(
+        step(&mut app, &mut sarah, "PROMOTE", curtis_id),
+        "BestFriends"
+    );
+    assert!(step(&mut app, &mut curtis, "PROMOTE", sarah_id).starts_with("ERROR"));
+    assert_eq!(
+        app.execute(&mut curtis, format!("GET REL {} {}", curtis_id, sarah_id)),
+        "BestFriends"
+    );
+    assert_eq!(step(&mut app, &mut curtis, "REMOVE", sarah_id), "Neutral");
+
+    app.execute(&mut curtis, format!("BLOCK {}", sarah_id));
+    assert!(step(&mut app, &mut sarah, "REQUEST", curtis_id).starts_with("ERROR"));
+    assert!(step(&mut app, &mut curtis, "REQUEST", curtis_id).starts_with("ERROR"));
+    assert_eq!(
+        Command::parse(&format!("FRIEND HUG {}", sarah_id)),
+        Err(ParseError::InvalidOption(String::from("HUG")))
+    );
+}
+
+#[test]
+fn members_are_managed_by_role() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+        ("Abby-gail Jones", "abby@mail.ca"),
+        ("Dan Smith", "dan@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let id = |app: &App, name| app.get_user("NAME", name).unwrap().read().unwrap().id();
+    let (curtis_id, sarah_id) = (id(&app, "Curtis"), id(&app, "Sarah"));
+    let (abby_id, dan_id) = (id(&app, "Abby"), id(&app, "Dan"));
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    let mut dan = login(&mut app, "dan@mail.ca");
+
+    let conv = app.execute(&mut curtis, format!("ADD CONV {} Team", sarah_id));
+    assert!(conv.contains(&format!("{}:owner,{}:member", curtis_id, sarah_id)));
+    let conv = first_id(&conv).to_string();
+
+    let add = |app: &mut App, session: &mut Session, user| {
+        app.execute(session, format!("ADD MEMBER {} {}", conv, user))
+    };
+    assert_eq!(add(&mut app, &mut sarah, abby_id), "PERMISSION DENIED");
+    assert!(add(&mut app, &mut curtis, abby_id).contains(&format!("{}:member", abby_id)));
+    assert!(add(&mut app, &mut curtis, abby_id).starts_with("ERROR"));
+    let resp = app.execute(&mut curtis, format!("SET ROLE {} {} admin", conv, sarah_id));
+    assert!(resp.contains(&format!("{}:admin", sarah_id)), "{}", resp);
+
+    // Admins add and remove plain members, but not the owner.
+    add(&mut app, &mut sarah, dan_id);
+    let resp = app.execute(&mut sarah, format!("REMOVE MEMBER {} {}", conv, abby_id));
+    assert!(!resp.contains(&abby_id.to_string()), "{}", resp);
+    assert_eq!(
+        app.execute(&mut sarah, format!("REMOVE MEMBER {} {}", conv, curtis_id)),
+        "PERMISSION DENIED"
+    );
+    assert_eq!(
+        app.execute(&mut sarah, format!("SET OWNER {} {}", conv, sarah_id)),
+        "PERMISSION DENIED"
+    );
+
+    assert_eq!(
+        app.execute(&mut dan, format!("LEAVE CONV {}", conv)),
+        "LEFT CONV"
+    );
+    assert_eq!(
+        app.execute(&mut dan, format!("GET MSG CONV {}", conv)),
+        "PERMISSION DENIED"
+    );
+
+    // The owner has to hand the conversation over before leaving.
+    assert!(app
+        .execute(&mut curtis, format!("LEAVE CONV {}", conv))
+        .starts_with("ERROR"));
+    let resp = app.execute(&mut curtis, format!("SET OWNER {} {}", conv, sarah_id));
+    assert!(resp.contains(&format!("{}:admin", curtis_id)), "{}", resp);
+    assert!(resp.contains(&format!("{}:owner", sarah_id)), "{}", resp);
+    assert_eq!(
+        app.execute(&mut curtis, format!("LEAVE CONV {}", conv)),
+        "LEFT CONV"
+    );
+
+    let history = app.execute(&mut sarah, format!("GET MSG CONV {}", conv));
+    let notes: Vec<&str> = history
+        .lines()
+        .filter(|l| l.ends_with(";system"))
+        .map(|l| l.split(';').nth(1).unwrap())
+        .collect();
+    assert_eq!(
+        notes,
+        vec![
+            "Curtis Jones added Abby-gail Jones",
+            "Curtis Jones made Sarah Parsons an admin",
+            "Sarah Parsons added Dan Smith",
+            "Sarah Parsons removed Abby-gail Jones",
+            "Dan Smith left",
+            "Curtis Jones made Sarah Parsons the owner",
+            "Curtis Jones left",
+        ]
+    );
+    let record = app.execute(&mut sarah, String::from("GET CONV NAME Team"));
+    let times: Vec<&str> = record.trim_end().rsplitn(3, ';').take(2).collect();
+    assert_ne!(times[0], times[1], "{}", record);
+}
+
+#[test]
+fn subscribers_are_pushed_new_messages() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+        ("Abby-gail Jones", "abby@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let id = |app: &App, name| app.get_user("NAME", name).unwrap().read().unwrap().id();
+    let (sarah_id, abby_id) = (id(&app, "Sarah"), id(&app, "Abby"));
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    let mut abby = login(&mut app, "abby@mail.ca");
+    let conv = app.execute(&mut curtis, format!("ADD CONV {} Chat", sarah_id));
+    let conv = first_id(&conv).to_string();
+
+    assert_eq!(
+        Command::parse("SUBSCRIBE ALL"),
+        Ok(Command::Subscribe(None))
+    );
+    assert_eq!(Command::parse("UNSUBSCRIBE"), Ok(Command::Unsubscribe));
+    assert_eq!(
+        app.execute(&mut abby, format!("SUBSCRIBE {}", conv)),
+        "PERMISSION DENIED"
+    );
+    assert_eq!(
+        app.execute(&mut Session::new(), String::from("SUBSCRIBE ALL")),
+        "NOT LOGGED IN"
+    );
+    assert_eq!(
+        app.execute(&mut sarah, format!("SUBSCRIBE {}", conv)),
+        format!("SUBSCRIBED {}", conv)
+    );
+    app.execute(&mut abby, String::from("SUBSCRIBE ALL"));
+    let sarah_feed = sarah.take_feed().unwrap();
+    let abby_feed = abby.take_feed().unwrap();
+
+    app.execute(&mut curtis, format!("SEND MSG {} hello!", conv));
+    app.execute(&mut curtis, format!("ADD MEMBER {} {}", conv, abby_id));
+    app.execute(&mut curtis, format!("SEND MSG {} welcome", conv));
+    assert!(sarah_feed.next().unwrap().contains(";hello!;"));
+    assert!(sarah_feed.next().unwrap().ends_with(";system\n"));
+    assert!(sarah_feed.next().unwrap().starts_with("PUSH MSG "));
+    // Abby only hears from the conversation once she is in it.
+    assert!(abby_feed.next().unwrap().contains("added Abby-gail Jones"));
+    assert!(abby_feed.next().unwrap().contains(";welcome;"));
+    assert_eq!(
+        app.execute(&mut abby, String::from("UNSUBSCRIBE")),
+        "UNSUBSCRIBED"
+    );
+    assert_eq!(abby_feed.next(), None);
+
+    // A subscriber that falls behind is dropped instead of queueing forever.
+    for i in 0..FEED_SIZE + 10 {
+        app.execute(&mut curtis, format!("SEND MSG {} flood {}", conv, i));
+    }
+    let queued: Vec<String> = std::iter::from_fn(|| sarah_feed.next()).collect();
+    assert_eq!(queued.len(), FEED_SIZE + 1);
+    assert!(queued[FEED_SIZE - 1].contains(&format!(";flood {};", FEED_SIZE - 1)));
+    assert_eq!(queued[FEED_SIZE], "PUSH UNSUBSCRIBED TOO SLOW");
+}
+
+#[test]
+fn replies_in_json_when_asked() {
+    let mut app = App::new();
+    app.register("Curtis Jones", "curtis@mail.ca", "password")
+        .unwrap();
+    app.register("Sarah Parsons", "sarah@mail.ca", "password")
+        .unwrap();
+    let sarah_id = app.get_user("NAME", "Sarah").unwrap().read().unwrap().id();
+    let mut session = Session::new();
+    let run = |app: &mut App, session: &mut Session, req: &str| -> Value {
+        serde_json::from_str(&app.execute(session, req.to_string())).unwrap()
+    };
+
+    assert_eq!(
+        Command::parse("PROTOCOL JSON"),
+        Ok(Command::Protocol(Protocol::Json))
+    );
+    assert_eq!(
+        run(&mut app, &mut session, "PROTOCOL JSON"),
+        json!({ "status": 200, "type": "done", "data": { "message": "PROTOCOL JSON" } })
+    );
+    assert_eq!(
+        run(&mut app, &mut session, "GET USER NAME Sarah"),
+        json!({ "status": 401, "error": { "kind": "unauthorized", "message": "NOT LOGGED IN" } })
+    );
+    let resp = run(&mut app, &mut session, "LOGIN curtis@mail.ca password");
+    assert_eq!(resp["type"], "session");
+    assert_eq!(resp["data"]["token"], session.token().unwrap());
+
+    let resp = run(&mut app, &mut session, "GET USER NAME Sarah");
+    assert_eq!(resp["status"], 200);
+    assert_eq!(resp["type"], "users");
+    assert_eq!(resp["data"][0]["id"], sarah_id.to_string());
+    assert_eq!(resp["data"][0]["email"], "sarah@mail.ca");
+    assert_eq!(
+        run(&mut app, &mut session, "GET USER MULT NAME nobody"),
+        json!({ "status": 200, "type": "users", "data": [] })
+    );
+    assert_eq!(
+        run(&mut app, &mut session, "GET WHATEVER"),
+        json!({
+            "status": 400,
+            "error": { "kind": "bad_request", "message": "UNKNOWN TARGET 'WHATEVER'" }
+        })
+    );
+
+    let resp = run(
+        &mut app,
+        &mut session,
+        &format!("ADD CONV {} Chat", sarah_id),
+    );
+    assert_eq!(resp["type"], "convs");
+    assert_eq!(resp["data"][0]["members"][1]["role"], "member");
+    let conv = resp["data"][0]["id"].as_str().unwrap().to_string();
+    assert_eq!(
+        run(&mut app, &mut session, &format!("SUBSCRIBE {}", conv)),
+        json!({ "status": 200, "type": "subscription", "data": { "conv": conv } })
+    );
+    let feed = session.take_feed().unwrap();
+    let resp = run(&mut app, &mut session, &format!("SEND MSG {} hi", conv));
+    assert_eq!(resp["data"][0]["kind"], "chat");
+    let push: Value = serde_json::from_str(&feed.next().unwrap()).unwrap();
+    assert_eq!(push["push"], "msg");
+    assert_eq!(push["data"], resp["data"][0]);
+
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    app.execute(&mut sarah, format!("LEAVE CONV {}", conv));
+    let push: Value = serde_json::from_str(&feed.next().unwrap()).unwrap();
+    assert_eq!(push["data"]["kind"], "system");
+    let resp = run(&mut app, &mut sarah, "PROTOCOL JSON");
+    assert_eq!(resp["status"], 200);
+    let resp = run(&mut app, &mut sarah, &format!("GET MSG CONV {}", conv));
+    assert_eq!(resp["status"], 403);
+    assert_eq!(resp["error"]["kind"], "denied");
+
+    // Legacy clients can switch back to the text records.
+    assert_eq!(
+        app.execute(&mut session, String::from("PROTOCOL TEXT")),
+        "PROTOCOL TEXT"
+    );
+    assert_eq!(
+        app.execute(&mut session, String::from("GET USER MULT NAME nobody")),
+        "NO USERS FOUND"
+    );
+    app.execute(&mut session, format!("SEND MSG {} again", conv));
+    assert!(feed.next().unwrap().starts_with("PUSH MSG "));
+}
+
+#[test]
+fn messages_are_edited_and_deleted_by_their_author_or_conv_admins() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+        ("Abby-gail Jones", "abby@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let id = |app: &App, name| app.get_user("NAME", name).unwrap().read().unwrap().id();
+    let (sarah_id, abby_id) = (id(&app, "Sarah"), id(&app, "Abby"));
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    let mut abby = login(&mut app, "abby@mail.ca");
+    let conv = app.execute(&mut curtis, format!("ADD CONV {} Chat", sarah_id));
+    let conv = first_id(&conv).to_string();
+    app.execute(&mut curtis, format!("ADD MEMBER {} {}", conv, abby_id));
+    let added = app.execute(&mut curtis, format!("GET MSG CONV {}", conv));
+    let msg = app.execute(&mut sarah, format!("SEND MSG {} helo; all", conv));
+    let msg = first_id(&msg).to_string();
+    app.execute(&mut sarah, format!("SUBSCRIBE {}", conv));
+    let feed = sarah.take_feed().unwrap();
+
+    assert_eq!(
+        Command::parse(&format!("EDIT MSG {} hello", msg)),
+        Ok(Command::EditMsg {
+            msg: Uuid::parse_str(&msg).unwrap(),
+            text: String::from("hello"),
+        })
+    );
+    assert_eq!(
+        app.execute(&mut abby, format!("EDIT MSG {} hijacked", msg)),
+        "PERMISSION DENIED"
+    );
+    assert_eq!(
+        app.execute(&mut sarah, format!("EDIT MSG {} hello", Uuid::new_v4())),
+        "INVALID MSG PROVIDED"
+    );
+    let edited = app.execute(&mut sarah, format!("EDIT MSG {} hello; all", msg));
+    let fields: Vec<&str> = edited.trim_end().split(';').collect();
+    assert_eq!(fields[1], "hello\\s all", "{}", edited);
+    assert_eq!(fields[8].split_once(' ').unwrap().1, "helo\\s all");
+    assert_eq!(fields[2], fields[8].split(' ').next().unwrap());
+    assert!(feed.next().unwrap().starts_with("PUSH EDIT "));
+
+    // The owner moderates anyone's messages, but nobody changes system ones.
+    let added = first_id(&added).to_string();
+    assert_eq!(
+        app.execute(&mut curtis, format!("DELETE MSG {}", added)),
+        "ERROR: Can't change a system message."
+    );
+    let deleted = app.execute(&mut curtis, format!("DELETE MSG {}", msg));
+    let fields: Vec<&str> = deleted.trim_end().split(';').collect();
+    assert_eq!(fields.len(), 8, "{}", deleted);
+    assert_eq!(fields[1], "");
+    assert!(!fields[7].is_empty());
+    assert!(feed.next().unwrap().starts_with("PUSH DELETE "));
+    assert_eq!(
+        app.execute(&mut sarah, format!("EDIT MSG {} back", msg)),
+        "ERROR: That message was deleted."
+    );
+
+    app.execute(&mut sarah, String::from("PROTOCOL JSON"));
+    let resp: Value =
+        serde_json::from_str(&app.execute(&mut sarah, format!("GET MSG ID {}", msg))).unwrap();
+    let data = &resp["data"][0];
+    assert_eq!(data["text"], "");
+    assert!(data["deleted"].is_string());
+    assert_eq!(data["history"], json!([]));
+}
+
+#[test]
+fn read_markers_give_unread_counts_and_receipts() {
+    let mut app = App::new();
+    for (name, email) in &[
+        ("Curtis Jones", "curtis@mail.ca"),
+        ("Sarah Parsons", "sarah@mail.ca"),
+        ("Abby-gail Jones", "abby@mail.ca"),
+    ] {
+        app.register(name, email, "password").unwrap();
+    }
+    let id = |app: &App, name| app.get_user("NAME", name).unwrap().read().unwrap().id();
+    let (curtis_id, sarah_id) = (id(&app, "Curtis"), id(&app, "Sarah"));
+    let mut curtis = login(&mut app, "curtis@mail.ca");
+    let mut sarah = login(&mut app, "sarah@mail.ca");
+    let mut abby = login(&mut app, "abby@mail.ca");
+    let conv = app.execute(&mut curtis, format!("ADD CONV {} Chat", sarah_id));
+    let conv = first_id(&conv).to_string();
+    let msgs: Vec<String> = (0..3)
+        .map(|i| {
+            let msg = app.execute(&mut sarah, format!("SEND MSG {} hi {}", conv, i));
+            first_id(&msg).to_string()
+        })
+        .collect();
+
+    assert_eq!(
+        Command::parse(&format!("MARK READ {} {}", conv, msgs[1])),
+        Ok(Command::MarkRead {
+            conv: Uuid::parse_str(&conv).unwrap(),
+            msg: Uuid::parse_str(&msgs[1]).unwrap(),
+        })
+    );
+    let unread =
+        |app: &mut App, session: &mut Session| app.execute(session, String::from("GET UNREAD"));
+    assert_eq!(unread(&mut app, &mut curtis), format!("{};Chat;3\n", conv));
+    // Nobody has unread messages of their own.
+    assert_eq!(unread(&mut app, &mut sarah), format!("{};Chat;0\n", conv));
+    assert_eq!(unread(&mut app, &mut abby), "NO CONVS FOUND");
+
+    let marked = app.execute(&mut curtis, format!("MARK READ {} {}", conv, msgs[1]));
+    let fields: Vec<&str> = marked.trim_end().split(';').collect();
+    assert_eq!(
+        fields[..3],
+        [curtis_id.to_string(), conv.clone(), msgs[1].clone()]
+    );
+    assert_eq!(unread(&mut app, &mut curtis), format!("{};Chat;1\n", conv));
+    // Markers don't move back.
+    assert_eq!(
+        app.execute(&mut curtis, format!("MARK READ {} {}", conv, msgs[0])),
+        marked
+    );
+    assert_eq!(
+        app.execute(&mut abby, format!("MARK READ {} {}", conv, msgs[0])),
+        "ERROR: User not in that conv."
+    );
+    let other = app.execute(&mut curtis, format!("ADD CONV {} Other", curtis_id));
+    let other = first_id(&other).to_string();
+    assert_eq!(
+        app.execute(&mut curtis, format!("MARK READ {} {}", other, msgs[0])),
+        "ERROR: That message isn't in that conv."
+    );
+
+    // Receipts are for members to see.
+    assert_eq!(
+        app.execute(&mut abby, format!("GET READS {}", conv)),
+        "PERMISSION DENIED"
+    );
+    assert_eq!(
+        app.execute(&mut sarah, format!("GET READS {}", other)),
+        "PERMISSION DENIED"
+    );
+    assert_eq!(
+        app.execute(&mut sarah, format!("GET READS {}", conv)),
+        marked
+    );
+    assert_eq!(
+        app.execute(&mut curtis, format!("GET READS {}", other)),
+        "NO READS FOUND"
+    );
+
+    app.execute(&mut curtis, String::from("PROTOCOL JSON"));
+    let resp: Value =
+        serde_json::from_str(&app.execute(&mut curtis, String::from("GET UNREAD"))).unwrap();
+    assert_eq!(resp["type"], "unread");
+    let unread: Vec<(Value, Value)> = resp["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["id"].clone(), c["unread"].clone()))
+        .collect();
+    assert_eq!(
+        unread,
+        vec![(json!(conv), json!(1)), (json!(other), json!(0))]
+    );
+}
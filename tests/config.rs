@@ -0,0 +1,89 @@
+// Tests for reading the config file and command line flags.
+//
+
+use chat_server::*;
+use std::{env, fs, net::IpAddr};
+use uuid::Uuid;
+
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|a| a.to_string()).collect()
+}
+
+fn config_file(contents: &str) -> String {
+    let path = env::temp_dir().join(format!("chat_server-{}.conf", Uuid::new_v4()));
+    fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn flags_override_the_config_file() {
+    let file = config_file(
+        "# Listen everywhere.\nbind = 0.0.0.0\nport = 9000  # raw protocol\nws_port = off\nthreads = 8\n",
+    );
+    let config = match Config::from_args(args(&[
+        "--config",
+        &file,
+        "--port=9100",
+        "--log-level",
+        "debug",
+        "--load-mode=lenient",
+    ])) {
+        Ok(Startup::Run(config)) => config,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(config.bind, "0.0.0.0".parse::<IpAddr>().unwrap());
+    assert_eq!(config.port, 9100);
+    assert_eq!(config.ws_port, None);
+    assert_eq!(config.http_port, Some(8082));
+    assert_eq!(config.threads, 8);
+    assert_eq!(config.log_level, LogLevel::Debug);
+    assert_eq!(config.data_dir, "files");
+    assert_eq!(config.load_mode, LoadMode::Lenient);
+    assert_eq!(config.storage, StorageKind::Files);
+}
+
+#[test]
+fn bad_settings_are_rejected() {
+    let err = |list: &[&str]| Config::from_args(args(list)).unwrap_err();
+    assert!(err(&["--threads", "0"]).contains("threads"));
+    assert!(err(&["--port", "70000"]).starts_with("--port"));
+    assert!(err(&["--http-port", "8080"]).contains("used twice"));
+    assert!(err(&["--bind", "localhost"]).contains("IP address"));
+    assert!(err(&["--log-level", "loud"]).starts_with("--log-level"));
+    assert!(err(&["--data-dir", "no/such/dir"]).contains("not a directory"));
+    assert!(err(&["--load-mode", "loose"]).starts_with("--load-mode"));
+    assert!(err(&["--storage", "postgres"]).starts_with("--storage"));
+    assert!(err(&["--max-connections", "0"]).contains("max_connections"));
+    assert!(err(&["--idle-timeout", "0"]).contains("idle_timeout"));
+    assert!(err(&["--token-lifetime", "0"]).contains("token_lifetime"));
+    assert!(err(&["--admin", "curtis"]).starts_with("--admin"));
+    assert!(err(&["--colour", "blue"]).contains("unknown setting"));
+    assert!(err(&["--port"]).contains("needs a value"));
+
+    let file = config_file("port = 9000\nthreads\n");
+    let e = Config::from_file(&file).unwrap_err();
+    assert!(e.ends_with(":2: expected 'key = value'"), "{}", e);
+}
+
+#[test]
+fn printed_config_reads_back_the_same() {
+    let config = match Config::from_args(args(&[
+        "--print-config",
+        "--http-port",
+        "off",
+        "--admin",
+        "mail@curtisjones.ca",
+        "--storage",
+        if cfg!(feature = "sqlite") {
+            "sqlite"
+        } else {
+            "files"
+        },
+    ])) {
+        Ok(Startup::PrintConfig(config)) => config,
+        other => panic!("{:?}", other),
+    };
+    let printed = config.to_string();
+    assert!(printed.contains("http_port = off\n"));
+    assert_eq!(Config::parse(&printed).unwrap(), config);
+}
@@ -0,0 +1,218 @@
+// Integration tests for the HTTP/JSON API.
+//
+
+use chat_server::*;
+use serde_json::{json, Value};
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+fn start_server(app: App) -> SocketAddr {
+    let server = HttpServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), 4).unwrap();
+    let addr = server.local_addr().unwrap();
+    let app = Arc::new(Mutex::new(app));
+    thread::spawn(move || server.serve(app));
+    addr
+}
+
+// One keep-alive connection, optionally logged in.
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    token: Option<String>,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Client {
+            stream,
+            reader,
+            token: None,
+        }
+    }
+
+    fn send(&mut self, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let auth = match &self.token {
+            Some(token) => format!("Authorization: Bearer {}\r\n", token),
+            None => String::new(),
+        };
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            auth,
+            body.len(),
+            body
+        );
+        self.stream.write_all(req.as_bytes()).unwrap();
+        self.recv()
+    }
+
+    fn recv(&mut self) -> (u16, Value) {
+        let mut status = String::new();
+        self.reader.read_line(&mut status).unwrap();
+        let status: u16 = status.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(n) = line.strip_prefix("content-length:") {
+                len = n.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        self.reader.read_exact(&mut body).unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn login(&mut self, email: &str) {
+        let (status, body) = self.send(
+            "POST",
+            "/login",
+            Some(json!({ "email": email, "password": "password" })),
+        );
+        assert_eq!(status, 200, "{}", body);
+        self.token = Some(body["token"].as_str().unwrap().to_string());
+    }
+}
+
+#[test]
+fn serves_users_convs_and_messages() {
+    let addr = start_server(App::new());
+    let mut client = Client::connect(addr);
+
+    let (status, curtis) = client.send(
+        "POST",
+        "/users",
+        Some(json!({ "name": "Curtis Jones", "email": "curtis@mail.ca", "password": "password" })),
+    );
+    assert_eq!(status, 201, "{}", curtis);
+    assert_eq!(curtis["name"], "Curtis Jones");
+    assert!(curtis.get("password").is_none());
+    let (status, sarah) = client.send(
+        "POST",
+        "/users",
+        Some(json!({ "name": "Sarah Parsons", "email": "sarah@mail.ca", "password": "password" })),
+    );
+    assert_eq!(status, 201);
+    let (status, _) = client.send(
+        "POST",
+        "/users",
+        Some(json!({ "name": "Sarah Again", "email": "sarah@mail.ca", "password": "password" })),
+    );
+    assert_eq!(status, 400);
+
+    assert_eq!(client.send("GET", "/users?name=sarah", None).0, 401);
+    let (status, _) = client.send(
+        "POST",
+        "/login",
+        Some(json!({ "email": "curtis@mail.ca", "password": "wrong-password" })),
+    );
+    assert_eq!(status, 401);
+    client.login("curtis@mail.ca");
+
+    let (status, users) = client.send("GET", "/users?name=sarah+par", None);
+    assert_eq!(status, 200);
+    assert_eq!(users, json!([sarah.clone()]));
+    let path = format!("/users/{}", sarah["id"].as_str().unwrap());
+    assert_eq!(client.send("GET", &path, None), (200, sarah.clone()));
+    let path = format!("/users/{}", uuid::Uuid::new_v4());
+    assert_eq!(client.send("GET", &path, None).0, 404);
+    assert_eq!(client.send("GET", "/users/not-an-id", None).0, 400);
+    assert_eq!(client.send("GET", "/users", None).0, 400);
+    assert_eq!(client.send("DELETE", "/users", None).0, 405);
+    assert_eq!(client.send("GET", "/nothing", None).0, 404);
+
+    let (status, conv) = client.send(
+        "POST",
+        "/convs",
+        Some(json!({ "name": "Chat", "members": [sarah["id"]] })),
+    );
+    assert_eq!(status, 201, "{}", conv);
+    assert_eq!(conv["members"][0]["id"], curtis["id"]);
+    assert_eq!(conv["members"][0]["role"], "owner");
+    let messages = format!("/convs/{}/messages", conv["id"].as_str().unwrap());
+    let (status, msg) = client.send("POST", &messages, Some(json!({ "text": "hello" })));
+    assert_eq!(status, 201, "{}", msg);
+    assert_eq!(msg["text"], "hello");
+    assert_eq!(msg["kind"], "chat");
+    assert_eq!(client.send("POST", &messages, Some(json!({}))).0, 400);
+    let (status, _) = client.send("POST", &messages, None);
+    assert_eq!(status, 400);
+
+    let (status, msgs) = client.send("GET", &format!("{}?newest=5", messages), None);
+    assert_eq!(status, 200);
+    assert_eq!(msgs, json!([msg]));
+    let (_, next) = client.send("POST", &messages, Some(json!({ "text": "again" })));
+    let before = format!(
+        "{}?before={}&limit=1",
+        messages,
+        next["id"].as_str().unwrap()
+    );
+    assert_eq!(client.send("GET", &before, None), (200, json!([msg])));
+    let after = format!("{}?after={}", messages, msg["id"].as_str().unwrap());
+    assert_eq!(client.send("GET", &after, None), (200, json!([next])));
+    let bad = format!("{}?after=soon", messages);
+    assert_eq!(client.send("GET", &bad, None).0, 400);
+    let (status, convs) = client.send("GET", "/convs?name=Chat", None);
+    assert_eq!(status, 200);
+    assert_eq!(convs[0]["id"], conv["id"]);
+    assert_ne!(convs[0]["last_msg"], conv["last_msg"]);
+    let (status, convs) = client.send("GET", "/convs?name=Nothing", None);
+    assert_eq!((status, convs), (200, json!([])));
+
+    let path = format!("/rels?with={}", sarah["id"].as_str().unwrap());
+    assert_eq!(
+        client.send("GET", &path, None),
+        (200, json!({ "status": "Neutral" }))
+    );
+
+    // Someone outside the conversation gets the same denial as elsewhere.
+    let mut abby = Client::connect(addr);
+    abby.send(
+        "POST",
+        "/users",
+        Some(json!({ "name": "Abby", "email": "abby@mail.ca", "password": "password" })),
+    );
+    abby.login("abby@mail.ca");
+    assert_eq!(abby.send("GET", &messages, None).0, 403);
+    let (status, _) = abby.send("POST", &messages, Some(json!({ "text": "hi" })));
+    assert_eq!(status, 403);
+
+    assert_eq!(client.send("POST", "/logout", None).0, 200);
+    assert_eq!(client.send("GET", &messages, None).0, 401);
+}
+
+#[test]
+fn rejects_bad_requests() {
+    let addr = start_server(App::new());
+
+    let mut client = Client::connect(addr);
+    client
+        .stream
+        .write_all(b"POST /login HTTP/1.1\r\nContent-Length: 5\r\n\r\n{nope")
+        .unwrap();
+    assert_eq!(client.recv().0, 400);
+
+    let mut client = Client::connect(addr);
+    client.stream.write_all(b"NONSENSE\r\n\r\n").unwrap();
+    assert_eq!(client.recv().0, 400);
+
+    let mut client = Client::connect(addr);
+    let req = format!(
+        "POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+        MAX_FRAME + 1
+    );
+    client.stream.write_all(req.as_bytes()).unwrap();
+    assert_eq!(client.recv().0, 413);
+}
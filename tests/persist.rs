@@ -0,0 +1,618 @@
+// Tests for saving and recovering the data files.
+//
+
+use chat_server::*;
+use proptest::prelude::*;
+use std::{env, fs, path::PathBuf};
+use uuid::Uuid;
+
+struct DataDir {
+    dir: PathBuf,
+}
+
+impl DataDir {
+    fn new() -> DataDir {
+        let dir = env::temp_dir().join(format!("chat_server-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["msgs", "convs", "users", "rels"] {
+            fs::copy(format!("files/{}.default", name), dir.join(name)).unwrap();
+        }
+        DataDir { dir }
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_str().unwrap().to_string()
+    }
+
+    // The default files have no reads file, as data dirs saved before read
+    // markers existed don't.
+    fn paths(&self) -> [String; 5] {
+        [
+            self.path("msgs"),
+            self.path("convs"),
+            self.path("users"),
+            self.path("rels"),
+            self.path("reads"),
+        ]
+    }
+
+    fn recover(&self) -> Result<(), LoadError> {
+        let [m, c, u, r, rd] = self.paths();
+        App::recover(&m, &c, &u, &r, &rd)
+    }
+
+    fn load(&self) -> App {
+        let [m, c, u, r, rd] = self.paths();
+        let mut app = App::new();
+        app.load_users(&u, LoadMode::Strict).unwrap();
+        app.load_convs(&c, LoadMode::Strict).unwrap();
+        app.load_msgs(&m, LoadMode::Strict).unwrap();
+        app.load_rels(&r, LoadMode::Strict).unwrap();
+        app.load_reads(&rd, LoadMode::Strict).unwrap();
+        app
+    }
+
+    fn save(&self, app: &App) {
+        let [m, c, u, r, rd] = self.paths();
+        app.save(&m, &c, &u, &r, &rd).unwrap();
+    }
+
+    fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.dir.join(name)).unwrap()
+    }
+
+    fn write(&self, name: &str, contents: &str) {
+        fs::write(self.dir.join(name), contents).unwrap();
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn records(contents: &str) -> usize {
+    contents.lines().filter(|l| !l.starts_with('#')).count()
+}
+
+#[test]
+fn save_truncates_and_keeps_headers() {
+    let data = DataDir::new();
+    data.recover().unwrap();
+    assert_eq!(records(&data.read("users")), 3);
+
+    let mut app = App::new();
+    app.add_user("Only One", "one@mail.ca").unwrap();
+    data.save(&app);
+
+    let users = data.read("users");
+    assert_eq!(records(&users), 1);
+    assert!(users.starts_with("# Format of the Users file:"));
+    assert!(users.contains("# Generation: 1"));
+    assert_eq!(data.read("manifest").trim(), "1");
+
+    data.recover().unwrap();
+    let app = data.load();
+    assert!(app.get_user("NAME", "Only One").is_some());
+    assert!(app.get_user("NAME", "Curtis").is_none());
+}
+
+#[test]
+fn recover_discards_uncommitted_save() {
+    let data = DataDir::new();
+    let app = data.load();
+    data.save(&app);
+
+    // Crash after writing a temp file but before the manifest moved on.
+    data.write("users.tmp", "# Generation: 2\nbroken");
+    data.recover().unwrap();
+    assert!(!data.dir.join("users.tmp").exists());
+    assert_eq!(records(&data.read("users")), 3);
+}
+
+#[test]
+fn recover_finishes_committed_save() {
+    let data = DataDir::new();
+    let mut app = data.load();
+    data.save(&app);
+    app.add_user("New Person", "new@mail.ca").unwrap();
+    data.save(&app);
+
+    // Crash after the manifest moved to generation 3 and one file was renamed.
+    let users = data
+        .read("users")
+        .replace("# Generation: 2", "# Generation: 3");
+    for name in &["msgs", "convs", "rels", "reads"] {
+        let contents = data
+            .read(name)
+            .replace("# Generation: 2", "# Generation: 3");
+        data.write(&format!("{}.tmp", name), &contents);
+    }
+    data.write("users", &users);
+    data.write("manifest", "3\n");
+
+    data.recover().unwrap();
+    for name in &["msgs", "convs", "users", "rels", "reads"] {
+        assert!(data.read(name).contains("# Generation: 3"));
+        assert!(!data.dir.join(format!("{}.tmp", name)).exists());
+    }
+    assert!(data.load().get_user("NAME", "New Person").is_some());
+}
+
+#[test]
+fn recover_rejects_mixed_generations() {
+    let data = DataDir::new();
+    let app = data.load();
+    data.save(&app);
+    data.write("manifest", "2\n");
+    assert!(data.recover().is_err());
+
+    // The error names the file that is out of step, not just the first one.
+    data.write("manifest", "1\n");
+    data.recover().unwrap();
+    let users = data
+        .read("users")
+        .replacen("# Generation: 1", "# Generation: 3", 1);
+    data.write("users", &users);
+    let e = data.recover().unwrap_err();
+    assert!(e.file.ends_with("users"), "{}", e);
+    assert!(e.reason.contains("generation 3"), "{}", e);
+}
+
+fn flat_files(data: &DataDir) -> App {
+    let storage = FlatFileStorage::new(data.dir.to_str().unwrap());
+    App::open(Box::new(storage), LoadMode::Strict).unwrap().0
+}
+
+// Adds a user, a conversation and a message, returning the conversation id.
+fn add_records(app: &mut App) -> String {
+    let curtis = app.get_user("NAME", "Curtis").unwrap();
+    let sarah = app.get_user("NAME", "Sarah").unwrap();
+    let dan = app.add_user("Dan Smith", "dan@mail.ca").unwrap();
+    let conv = app.add_conv("Chat", vec![curtis, dan]).unwrap();
+    app.send_msg(sarah, Conversation::clone(&conv), "lost")
+        .unwrap_err();
+    let dan = app.get_user("NAME", "Dan").unwrap();
+    app.send_msg(dan, Conversation::clone(&conv), "kept; for good")
+        .unwrap();
+    let id = conv.read().unwrap().id();
+    id.to_string()
+}
+
+fn check_records(app: &mut App, conv: &str) {
+    let dan = app.get_user("NAME", "Dan Smith").unwrap();
+    app.set_password(&dan, "password").unwrap();
+    let mut session = Session::new();
+    app.execute(&mut session, String::from("LOGIN dan@mail.ca password"));
+    let msgs = app.execute(&mut session, format!("GET MSG CONV {}", conv));
+    assert!(msgs.contains(";kept\\s for good;"), "{}", msgs);
+    assert_eq!(msgs.lines().count(), 1);
+}
+
+// The default files hold no passwords, so Curtis is given one to log in with.
+fn login_curtis(app: &mut App) -> Session {
+    let curtis = app.get_user("NAME", "Curtis").unwrap();
+    if !curtis.read().unwrap().has_password() {
+        app.set_password(&curtis, "password").unwrap();
+    }
+    let mut session = Session::new();
+    let resp = app.execute(
+        &mut session,
+        String::from("LOGIN mail@curtisjones.ca password"),
+    );
+    assert!(resp.starts_with("LOGGED IN"), "{}", resp);
+    session
+}
+
+#[test]
+fn journal_replays_changes_after_a_crash() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    let conv = add_records(&mut app);
+    // Crash: nothing saved.
+    drop(app);
+
+    check_records(&mut flat_files(&data), &conv);
+}
+
+#[test]
+fn flush_folds_journal_into_data_files() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    app.add_user("Dan Smith", "dan@mail.ca").unwrap();
+    app.flush().unwrap();
+    assert_eq!(records(&data.read("journal")), 0);
+    assert!(data.read("users").contains("Dan Smith"));
+
+    // A journal left behind by a crash between the save and the reset is
+    // already in the data files and must not be replayed twice.
+    app.add_user("Eve Stone", "eve@mail.ca").unwrap();
+    let journal = data.read("journal");
+    app.flush().unwrap();
+    data.write("journal", &journal);
+    drop(app);
+
+    let app = flat_files(&data);
+    let eves = app.get_user_mult("NAME", "eve").unwrap();
+    assert_eq!(eves.len(), 1);
+}
+
+#[test]
+fn read_markers_survive_a_reload() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    let conv = add_records(&mut app);
+    let mut curtis = login_curtis(&mut app);
+    let msgs = app.execute(&mut curtis, format!("GET MSG CONV {}", conv));
+    let msg = msgs.split(';').next().unwrap();
+    let marked = app.execute(&mut curtis, format!("MARK READ {} {}", conv, msg));
+    let unread = app.execute(&mut curtis, String::from("GET UNREAD"));
+    assert!(unread.contains(&format!("{};Chat;0\n", conv)), "{}", unread);
+
+    // Once from the journal, then from the saved files.
+    for _ in 0..2 {
+        drop(app);
+        app = flat_files(&data);
+        curtis = login_curtis(&mut app);
+        assert_eq!(
+            app.execute(&mut curtis, format!("GET READS {}", conv)),
+            marked
+        );
+        assert_eq!(app.execute(&mut curtis, String::from("GET UNREAD")), unread);
+        app.flush().unwrap();
+    }
+    assert_eq!(records(&data.read("reads")), 1);
+}
+
+#[test]
+fn conv_history_pages_by_time_then_id() {
+    let data = DataDir::new();
+    let conv = "b175b943-38c8-4182-90b9-dacaa88d5f9b";
+    let curtis = "9197c428-26e8-440a-a239-50ef19e1dc66";
+    let id = |n: u8| format!("00000000-0000-4000-8000-00000000000{}", n);
+    // b and c were sent at the same time, and are out of order in the file.
+    let msgs: String = [
+        (3, "c", "2020-06-01T00:00:01+00:00"),
+        (2, "b", "2020-06-01T00:00:01+00:00"),
+        (1, "a", "2020-06-01T00:00:00+00:00"),
+        (4, "d", "2020-06-01T00:00:02+00:00"),
+    ]
+    .iter()
+    .map(|&(n, text, time)| format!("{};{};{};{};{};chat\n", id(n), text, time, curtis, conv))
+    .collect();
+    data.write("msgs", &msgs);
+    let mut app = data.load();
+    let mut session = login_curtis(&mut app);
+    let mut get = |query: String| {
+        let resp = app.execute(&mut session, format!("GET MSG {}", query));
+        resp.lines()
+            .map(|l| l.split(';').nth(1).unwrap_or(l).to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+
+    let mut page = |query: String| get(format!("CONV {} {}", conv, query));
+
+    assert_eq!(page(String::new()), "a,b,c,d");
+    assert_eq!(page(String::from("2")), "c,d");
+    assert_eq!(page(format!("BEFORE {} 2", id(4))), "b,c");
+    assert_eq!(page(format!("BEFORE {} 1", id(3))), "b");
+    assert_eq!(page(format!("AFTER {}", id(2))), "c,d");
+    assert_eq!(page(format!("AFTER {}", id(4))), "NO MSGS FOUND");
+    assert_eq!(page(String::from("BEFORE 2020-06-01T00:00:01Z")), "a");
+    assert_eq!(page(String::from("AFTER 2020-06-01T00:00:01Z 5")), "d");
+    assert_eq!(
+        page(format!("AFTER {}", Uuid::new_v4())),
+        "INVALID MSG PROVIDED"
+    );
+
+    // Searches by author and time page the same way.
+    assert_eq!(get(format!("USER {} 3", curtis)), "b,c,d");
+    assert_eq!(get(format!("USER {} BEFORE {} 2", curtis, id(3))), "a,b");
+    assert_eq!(get(format!("USER {} AFTER {}", curtis, id(3))), "d");
+    let day = "2020-06-01T00:00:00Z 2020-06-01T00:00:01Z";
+    assert_eq!(get(format!("TIME {}", day)), "a,b,c");
+    assert_eq!(get(format!("TIME {} 1", day)), "c");
+    assert_eq!(get(format!("TIME {} AFTER {} 1", day, id(1))), "b");
+    assert_eq!(
+        get(format!("TIME {} AFTER {}", day, id(3))),
+        "NO MSGS FOUND"
+    );
+    assert_eq!(
+        get(format!("TIME {} BEFORE 2020-05-01T00:00:00Z", day)),
+        "NO MSGS FOUND"
+    );
+    assert_eq!(
+        get(String::from(
+            "TIME 2020-06-02T00:00:00Z 2020-06-01T00:00:00Z"
+        )),
+        "NO MSGS FOUND"
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_keeps_changes_without_a_flush() {
+    let data = DataDir::new();
+    let db = data.path("chat.db");
+    let mut app = App::open(
+        Box::new(SqliteStorage::open(&db).unwrap()),
+        LoadMode::Strict,
+    )
+    .unwrap()
+    .0;
+    // Seed it with the same users as the flat files.
+    for user in data.load().get_user_mult("EMAIL", "@").unwrap() {
+        let user = user.read().unwrap();
+        app.add_user(user.name(), user.email()).unwrap();
+    }
+    let conv = add_records(&mut app);
+    drop(app);
+
+    let mut app = App::open(
+        Box::new(SqliteStorage::open(&db).unwrap()),
+        LoadMode::Strict,
+    )
+    .unwrap()
+    .0;
+    check_records(&mut app, &conv);
+    assert_eq!(app.get_user_mult("EMAIL", "@").unwrap().len(), 4);
+}
+
+fn open_flat_files(data: &DataDir, mode: LoadMode) -> Result<(App, LoadReport), LoadError> {
+    let storage = FlatFileStorage::new(data.dir.to_str().unwrap());
+    App::open(Box::new(storage), mode)
+}
+
+#[test]
+fn strict_load_stops_at_bad_record() {
+    let data = DataDir::new();
+    let users = data.read("users");
+    let bad = users + "not-a-uuid;Bad;bad@mail.ca;2020-05-03T00:00:00Z\n";
+    data.write("users", &bad);
+    let line = bad.lines().count();
+
+    let err = open_flat_files(&data, LoadMode::Strict).unwrap_err();
+    assert_eq!(err.file, data.path("users"));
+    assert_eq!(err.line, line);
+    assert_eq!(err.field, "id");
+    assert!(err
+        .to_string()
+        .contains(&format!("users:{}: invalid id", line)));
+}
+
+#[test]
+fn lenient_load_skips_bad_records() {
+    let data = DataDir::new();
+    let curtis = data.load().get_user("NAME", "Curtis").unwrap();
+    let curtis = curtis.read().unwrap().id();
+    let ghost = Uuid::new_v4();
+    let now = "2020-05-03T00:00:00+00:00";
+    data.write(
+        "users",
+        &(data.read("users") + &format!("{};Late;late@mail.ca;yesterday\n", Uuid::new_v4())),
+    );
+    data.write(
+        "convs",
+        &(data.read("convs")
+            + &format!(
+                "{};Ghosts;{},{};{};{}\n",
+                Uuid::new_v4(),
+                curtis,
+                ghost,
+                now,
+                now
+            )),
+    );
+    data.write(
+        "msgs",
+        &(data.read("msgs")
+            + &format!(
+                "{};boo;{};{};{}\n",
+                Uuid::new_v4(),
+                now,
+                ghost,
+                Uuid::new_v4()
+            )),
+    );
+    data.write("rels", &(data.read("rels") + "short\n"));
+
+    let (app, report) = open_flat_files(&data, LoadMode::Lenient).unwrap();
+    let fields: Vec<_> = report.skipped.iter().map(|e| e.field).collect();
+    assert_eq!(fields, vec!["create_time", "members", "user", "mem1"]);
+    assert!(report.skipped.iter().all(|e| e.line > 0));
+    assert!(report.skipped[1].reason.contains(&ghost.to_string()));
+    assert!(app.get_user("NAME", "Curtis").is_some());
+    assert!(app.get_user("NAME", "Late").is_none());
+
+    assert!(open_flat_files(&data, LoadMode::Strict).is_err());
+}
+
+#[test]
+fn missing_data_file_is_a_load_error() {
+    let data = DataDir::new();
+    fs::remove_file(data.dir.join("rels")).unwrap();
+    let err = open_flat_files(&data, LoadMode::Lenient).unwrap_err();
+    assert_eq!(err.file, data.path("rels"));
+    assert_eq!(err.field, "file");
+}
+
+#[test]
+fn bad_journal_entry_is_reported_by_line() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    app.add_user("Dan Smith", "dan@mail.ca").unwrap();
+    drop(app);
+    let journal = data.read("journal") + "USER nonsense\n";
+    data.write("journal", &journal);
+
+    let err = open_flat_files(&data, LoadMode::Strict).unwrap_err();
+    assert_eq!(err.file, data.path("journal"));
+    assert_eq!(err.line, journal.lines().count());
+
+    let (app, report) = open_flat_files(&data, LoadMode::Lenient).unwrap();
+    assert_eq!(report.skipped.len(), 1);
+    assert!(app.get_user("NAME", "Dan").is_some());
+}
+
+// The data files with the generation line taken out, for comparing saves.
+fn snapshot(data: &DataDir) -> Vec<String> {
+    ["msgs", "convs", "users", "rels"]
+        .iter()
+        .map(|name| {
+            data.read(name)
+                .lines()
+                .filter(|l| !l.starts_with("# Generation"))
+                .collect::<Vec<&str>>()
+                .join("\n")
+        })
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn save_then_load_round_trips_any_text(
+        names in prop::collection::vec(any::<String>(), 1..4),
+        conv_name in any::<String>(),
+        texts in prop::collection::vec(any::<String>(), 0..8),
+    ) {
+        let data = DataDir::new();
+        let mut app = App::new();
+        let mut users = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let name = format!("u{}", name);
+            let email = format!("{}{}@mail.ca", name.replace(char::is_whitespace, ""), i);
+            users.push(app.add_user(&name, &email).unwrap());
+        }
+        let conv = app.add_conv(&format!("c{}", conv_name), users.clone()).unwrap();
+        let mut sent = Vec::new();
+        for (i, text) in texts.iter().enumerate() {
+            let text = format!("m{}", text);
+            let from = User::clone(&users[i % users.len()]);
+            let msg = app.send_msg(from, Conversation::clone(&conv), &text).unwrap();
+            sent.push((msg.read().unwrap().id, text));
+        }
+        data.save(&app);
+        let saved = snapshot(&data);
+
+        let app = data.load();
+        for (id, text) in sent {
+            let msg = app.get_msg(id).unwrap();
+            prop_assert_eq!(&msg.read().unwrap().text, &text);
+        }
+        data.save(&app);
+        prop_assert_eq!(snapshot(&data), saved);
+    }
+}
+
+#[test]
+fn passwords_survive_a_reload() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    assert!(app.login("mail@curtisjones.ca", "password").is_err());
+    app.register("Dan Smith", "dan@mail.ca", "password")
+        .unwrap();
+    drop(app);
+
+    let mut app = flat_files(&data);
+    assert!(app.login("dan@mail.ca", "password").is_ok());
+    assert!(app.login("dan@mail.ca", "changeme").is_err());
+    app.flush().unwrap();
+    assert!(data.read("users").contains("$argon2id$"));
+}
+
+#[test]
+fn admins_are_made_by_email_and_survive_a_reload() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    let users = app.get_user_mult("EMAIL", "@").unwrap();
+    assert!(users.iter().all(|u| !u.read().unwrap().is_admin()));
+    assert!(app.make_admin("nobody@mail.ca").is_err());
+    app.make_admin("MAIL@curtisjones.ca").unwrap();
+    drop(app);
+
+    let app = flat_files(&data);
+    let curtis = app.get_user("NAME", "Curtis").unwrap();
+    assert!(curtis.read().unwrap().is_admin());
+}
+
+#[test]
+fn reading_a_relationship_changes_nothing() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    let sarah = app.get_user("NAME", "Sarah").unwrap();
+    let abby = app.add_user("Abby Two", "abby2@mail.ca").unwrap();
+    assert!(matches!(
+        app.get_rel_status(&sarah, &abby),
+        RelStatus::Neutral
+    ));
+    app.flush().unwrap();
+    let before = records(&fs::read_to_string("files/rels.default").unwrap());
+    assert_eq!(records(&data.read("rels")), before);
+
+    app.befriend(&sarah, &abby, FriendAction::Request).unwrap();
+    app.flush().unwrap();
+    assert!(data
+        .read("rels")
+        .contains(&format!("Pending,{}", sarah.read().unwrap().id())));
+    assert_eq!(records(&data.read("rels")), before + 1);
+}
+
+#[test]
+fn conversation_roles_survive_a_reload() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    let conv = "b175b943-38c8-4182-90b9-dacaa88d5f9b";
+    let abby = "fa85cfcb-183d-4b3c-b89e-68736d22d0f8";
+    let mut curtis = login_curtis(&mut app);
+    app.execute(&mut curtis, format!("ADD MEMBER {} {}", conv, abby));
+    app.execute(&mut curtis, format!("SET ROLE {} {} admin", conv, abby));
+    drop(app);
+
+    let mut app = flat_files(&data);
+    let mut curtis = login_curtis(&mut app);
+    let resp = app.execute(&mut curtis, String::from("GET CONV NAME Basic"));
+    assert!(resp.contains(&format!("{}:admin", abby)), "{}", resp);
+    assert!(
+        resp.contains("9197c428-26e8-440a-a239-50ef19e1dc66:owner"),
+        "{}",
+        resp
+    );
+}
+
+#[test]
+fn edited_and_deleted_messages_survive_a_reload() {
+    let data = DataDir::new();
+    let mut app = flat_files(&data);
+    let conv = add_records(&mut app);
+    let mut curtis = login_curtis(&mut app);
+    let send = |app: &mut App, session: &mut Session, text: &str| {
+        let resp = app.execute(session, format!("SEND MSG {} {}", conv, text));
+        resp.split(';').next().unwrap().to_string()
+    };
+    let kept = send(&mut app, &mut curtis, "frist");
+    let gone = send(&mut app, &mut curtis, "oops");
+    app.execute(&mut curtis, format!("EDIT MSG {} first", kept));
+    app.execute(&mut curtis, format!("EDIT MSG {} first!", kept));
+    app.execute(&mut curtis, format!("DELETE MSG {}", gone));
+    let before = app.execute(&mut curtis, format!("GET MSG CONV {}", conv));
+
+    // Once from the journal, then from the saved files.
+    for _ in 0..2 {
+        drop(app);
+        app = flat_files(&data);
+        curtis = login_curtis(&mut app);
+        let after = app.execute(&mut curtis, format!("GET MSG CONV {}", conv));
+        assert_eq!(after, before);
+        app.flush().unwrap();
+    }
+    let kept = app.get_msg(Uuid::parse_str(&kept).unwrap()).unwrap();
+    let kept = kept.read().unwrap();
+    assert_eq!(kept.text, "first!");
+    let history: Vec<&str> = kept.history.iter().map(|v| v.text.as_str()).collect();
+    assert_eq!(history, vec!["frist", "first"]);
+    assert!(kept.edited.is_some());
+}
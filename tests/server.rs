@@ -0,0 +1,392 @@
+// Integration tests for the TCP server.
+//
+
+use chat_server::*;
+use std::{
+    io::prelude::*,
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+struct Server {
+    shutdown: ShutdownHandle,
+    thread: thread::JoinHandle<()>,
+}
+
+fn start_server(app: App) -> (SocketAddr, Server) {
+    start_limited(app, MAX_CONNECTIONS, IDLE_TIMEOUT)
+}
+
+fn start_limited(app: App, max_conns: usize, idle: Duration) -> (SocketAddr, Server) {
+    let server = TcpServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), 4)
+        .unwrap()
+        .with_limits(max_conns, idle);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let app = Arc::new(Mutex::new(app));
+    let thread = thread::spawn(move || server.serve(app));
+    (addr, Server { shutdown, thread })
+}
+
+fn stop_server(server: Server) {
+    server.shutdown.stop();
+    server.thread.join().unwrap();
+}
+
+struct Client {
+    stream: TcpStream,
+    reader: FrameReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        // A server that never answers fails the test rather than hanging it.
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let reader = FrameReader::new(stream.try_clone().unwrap());
+        Client { stream, reader }
+    }
+
+    fn send(&mut self, req: &str) {
+        write_line(&mut self.stream, req).unwrap();
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        self.reader.read_sized().unwrap()
+    }
+
+    fn request(&mut self, req: &str) -> String {
+        self.send(req);
+        self.recv().unwrap()
+    }
+
+    // Connects and resumes the session for `token`.
+    fn login(addr: SocketAddr, token: &str) -> Client {
+        let mut client = Client::connect(addr);
+        let resp = client.request(&format!("LOGIN TOKEN {}", token));
+        assert!(resp.starts_with("LOGGED IN"), "{}", resp);
+        client
+    }
+}
+
+// An app with `users` users, and a session token for the first one.
+fn test_app(users: usize) -> (App, String) {
+    let mut app = App::new();
+    for i in 0..users {
+        app.register(
+            &format!("User {}", i),
+            &format!("user{}@mail.ca", i),
+            "password",
+        )
+        .unwrap();
+    }
+    let (_, token) = app.login("user0@mail.ca", "password").unwrap();
+    (app, token)
+}
+
+#[test]
+fn serves_many_clients_at_once() {
+    let (app, token) = test_app(8);
+    let (addr, server) = start_server(app);
+
+    let clients: Vec<_> = (0..32)
+        .map(|i| {
+            let token = token.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let mut client = Client::login(addr, &token);
+                    let resp = client.request(&format!("GET USER EMAIL user{}@", i % 8));
+                    assert!(resp.contains(&format!("User {}", i % 8)), "{}", resp);
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    stop_server(server);
+}
+
+#[test]
+fn idle_connections_do_not_hold_workers() {
+    let (app, token) = test_app(1);
+    let (addr, server) = start_server(app);
+
+    // Twice as many open connections as the server has workers.
+    let mut clients: Vec<Client> = (0..8).map(|_| Client::login(addr, &token)).collect();
+    for client in clients.iter_mut() {
+        let resp = client.request("PROTOCOL JSON");
+        assert!(resp.contains("PROTOCOL JSON"), "{}", resp);
+    }
+    for client in clients.iter_mut().rev() {
+        let resp = client.request("GET USER EMAIL user0@");
+        assert!(resp.contains("User 0"), "{}", resp);
+    }
+    drop(clients);
+
+    stop_server(server);
+}
+
+#[test]
+fn keeps_serving_after_a_panic_while_holding_the_app() {
+    let (app, token) = test_app(1);
+    let server = TcpServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), 4).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let app = Arc::new(Mutex::new(app));
+    let poisoner = Arc::clone(&app);
+    let panicked = thread::spawn(move || {
+        let _app = poisoner.lock().unwrap();
+        panic!("handler failed");
+    });
+    assert!(panicked.join().is_err());
+    assert!(app.is_poisoned());
+    let thread = thread::spawn(move || server.serve(app));
+
+    let mut client = Client::login(addr, &token);
+    let resp = client.request("GET USER EMAIL user0@");
+    assert!(resp.contains("User 0"), "{}", resp);
+    drop(client);
+
+    stop_server(Server { shutdown, thread });
+}
+
+#[test]
+fn refuses_connections_past_the_limit() {
+    let (app, token) = test_app(1);
+    let (addr, server) = start_limited(app, 2, IDLE_TIMEOUT);
+
+    let first = Client::login(addr, &token);
+    let mut second = Client::login(addr, &token);
+    let mut refused = Client::connect(addr);
+    assert_eq!(refused.recv(), None);
+    assert!(second.request("GET USER EMAIL user0@").contains("User 0"));
+
+    // Closing one makes room, once its thread has finished.
+    drop(first);
+    let resp = (0..50).find_map(|_| {
+        thread::sleep(Duration::from_millis(20));
+        let mut client = Client::connect(addr);
+        write_line(&mut client.stream, &format!("LOGIN TOKEN {}", token)).ok()?;
+        client.reader.read_sized().ok()?
+    });
+    assert!(resp.unwrap().starts_with("LOGGED IN"));
+    drop(second);
+
+    stop_server(server);
+}
+
+#[test]
+fn closes_idle_connections_but_not_subscribers() {
+    let (app, token) = test_app(1);
+    let (addr, server) = start_limited(app, MAX_CONNECTIONS, Duration::from_millis(200));
+
+    let mut idle = Client::login(addr, &token);
+    let mut subscriber = Client::login(addr, &token);
+    assert_eq!(subscriber.request("SUBSCRIBE ALL"), "SUBSCRIBED ALL");
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(idle.recv(), None);
+    assert!(subscriber
+        .request("GET USER EMAIL user0@")
+        .contains("User 0"));
+    drop(subscriber);
+
+    stop_server(server);
+}
+
+#[test]
+fn serves_many_requests_per_connection() {
+    let (app, token) = test_app(4);
+    let (addr, server) = start_server(app);
+
+    let mut client = Client::login(addr, &token);
+    for i in 0..50 {
+        let resp = client.request(&format!("GET USER EMAIL user{}@", i % 4));
+        assert!(resp.contains(&format!("User {}", i % 4)), "{}", resp);
+    }
+    drop(client);
+
+    stop_server(server);
+}
+
+#[test]
+fn accepts_frames_longer_than_512_bytes() {
+    let name = "x".repeat(2000);
+    let mut app = App::new();
+    app.register(&name, "long@mail.ca", "password").unwrap();
+    let (_, token) = app.login("long@mail.ca", "password").unwrap();
+    let (addr, server) = start_server(app);
+
+    let mut client = Client::login(addr, &token);
+    let resp = client.request(&format!("GET USER NAME {}", name));
+    assert!(resp.contains("long@mail.ca"), "{}", resp);
+    drop(client);
+
+    stop_server(server);
+}
+
+#[test]
+fn reports_malformed_frames() {
+    let (app, token) = test_app(1);
+    let (addr, server) = start_server(app);
+
+    let mut client = Client::login(addr, &token);
+    client
+        .stream
+        .write_all(b"GET USER NAME \xff\xfe\n")
+        .unwrap();
+    assert!(client.recv().unwrap().starts_with("MALFORMED FRAME"));
+    // An invalid UTF-8 line doesn't lose the frame boundary, so the
+    // connection stays usable.
+    assert!(client.request("GET USER NAME User 0").contains("user0@"));
+
+    let oversized = vec![b'a'; MAX_FRAME + 1];
+    client.stream.write_all(&oversized).unwrap();
+    assert!(client.recv().unwrap().starts_with("MALFORMED FRAME"));
+    assert_eq!(client.recv(), None);
+
+    stop_server(server);
+}
+
+#[test]
+fn requires_login_before_anything_else() {
+    let (app, _) = test_app(2);
+    let (addr, server) = start_server(app);
+
+    let mut client = Client::connect(addr);
+    assert_eq!(client.request("GET USER EMAIL user1@"), "NOT LOGGED IN");
+    assert!(client
+        .request("LOGIN user1@mail.ca wrong-password")
+        .starts_with("ERROR"));
+    assert_eq!(client.request("GET USER EMAIL user1@"), "NOT LOGGED IN");
+
+    let resp = client.request("LOGIN user1@mail.ca password");
+    let token = resp.split(' ').nth(3).unwrap().to_string();
+    let resp = client.request("GET USER EMAIL user1@");
+    assert!(resp.contains("User 1;user1@mail.ca;"), "{}", resp);
+    assert!(!resp.contains("argon2"), "{}", resp);
+
+    // The token works from another connection until it is logged out.
+    let mut other = Client::login(addr, &token);
+    assert_eq!(client.request("LOGOUT"), "LOGGED OUT");
+    assert_eq!(other.request("GET USER EMAIL user1@"), "NOT LOGGED IN");
+    assert_eq!(
+        other.request(&format!("LOGIN TOKEN {}", token)),
+        "INVALID TOKEN"
+    );
+    drop((client, other));
+
+    stop_server(server);
+}
+
+#[test]
+fn pushes_new_messages_to_subscribers() {
+    let (mut app, token) = test_app(3);
+    let users: Vec<User> = (0..3)
+        .map(|i| {
+            app.get_user("EMAIL", &format!("user{}@mail.ca", i))
+                .unwrap()
+        })
+        .collect();
+    let conv = app.add_conv("Chat", users[..2].to_vec()).unwrap();
+    let conv = conv.read().unwrap().id();
+    let (addr, server) = start_server(app);
+
+    let mut sender = Client::login(addr, &token);
+    let mut member = Client::connect(addr);
+    member.request("LOGIN user1@mail.ca password");
+    assert_eq!(member.request("SUBSCRIBE ALL"), "SUBSCRIBED ALL");
+    let mut outsider = Client::connect(addr);
+    outsider.request("LOGIN user2@mail.ca password");
+    assert_eq!(outsider.request("SUBSCRIBE ALL"), "SUBSCRIBED ALL");
+
+    for i in 0..3 {
+        sender.request(&format!("SEND MSG {} hello {}", conv, i));
+    }
+    for i in 0..3 {
+        let push = member.recv().unwrap();
+        assert!(push.starts_with("PUSH MSG "), "{}", push);
+        assert!(push.contains(&format!(";hello {};", i)), "{}", push);
+    }
+    // Replies still come back in between pushes.
+    assert!(member.request("GET USER EMAIL user0@").contains("User 0"));
+    assert!(outsider.request("GET USER EMAIL user0@").contains("User 0"));
+
+    assert_eq!(member.request("UNSUBSCRIBE"), "UNSUBSCRIBED");
+    sender.request(&format!("SEND MSG {} bye", conv));
+    assert!(member.request("GET USER EMAIL user0@").contains("User 0"));
+    drop((sender, member, outsider));
+
+    stop_server(server);
+}
+
+#[test]
+fn logging_in_again_ends_the_old_subscription() {
+    let (mut app, token) = test_app(3);
+    let users: Vec<User> = (0..2)
+        .map(|i| {
+            app.get_user("EMAIL", &format!("user{}@mail.ca", i))
+                .unwrap()
+        })
+        .collect();
+    let conv = app.add_conv("Chat", users).unwrap();
+    let conv = conv.read().unwrap().id();
+    let (addr, server) = start_server(app);
+
+    let mut sender = Client::login(addr, &token);
+    let mut member = Client::connect(addr);
+    member.request("LOGIN user1@mail.ca password");
+    assert_eq!(member.request("SUBSCRIBE ALL"), "SUBSCRIBED ALL");
+    let resp = member.request("LOGIN user2@mail.ca password");
+    assert!(resp.starts_with("LOGGED IN"), "{}", resp);
+    assert_eq!(member.request("LOGOUT"), "LOGGED OUT");
+
+    sender.request(&format!("SEND MSG {} hello", conv));
+    // A push would have arrived ahead of the reply.
+    assert_eq!(member.request("PROTOCOL TEXT"), "PROTOCOL TEXT");
+    drop((sender, member));
+
+    stop_server(server);
+}
+
+#[test]
+fn only_admins_can_end_the_server() {
+    let (mut app, token) = test_app(2);
+    let admin = app.get_user("EMAIL", "user0@mail.ca").unwrap();
+    app.set_admin(&admin, true).unwrap();
+    let (addr, server) = start_server(app);
+
+    let mut client = Client::connect(addr);
+    assert_eq!(client.request("END"), "NOT LOGGED IN");
+    client.request("LOGIN user1@mail.ca password");
+    assert_eq!(client.request("END"), "PERMISSION DENIED");
+    assert!(client.request("GET USER EMAIL user0@").contains("User 0"));
+
+    // Every open connection is closed once the reply to END is out.
+    let mut admin = Client::login(addr, &token);
+    assert_eq!(admin.request("END"), "SHUTTING DOWN");
+    assert_eq!(admin.recv(), None);
+    assert_eq!(client.recv(), None);
+    server.thread.join().unwrap();
+}
+
+#[test]
+fn shutting_down_finishes_the_request_in_flight() {
+    let (app, token) = test_app(1);
+    let (addr, server) = start_server(app);
+
+    let mut client = Client::login(addr, &token);
+    let mut idle = Client::login(addr, &token);
+    client.send("GET USER EMAIL user0@");
+    server.shutdown.stop();
+    assert!(client.recv().unwrap().contains("User 0"));
+    assert_eq!(client.recv(), None);
+    assert_eq!(idle.recv(), None);
+    server.thread.join().unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}
@@ -0,0 +1,95 @@
+// Tests for the thread pool the servers run connections on.
+//
+
+use chat_server::*;
+use std::{
+    sync::{mpsc, Arc, Barrier, Mutex},
+    thread,
+    time::Duration,
+};
+
+// Waits for the pool to have run `jobs` jobs.
+fn wait_for_completed(pool: &ThreadPool, jobs: usize) -> PoolStats {
+    for _ in 0..500 {
+        let stats = pool.stats();
+        if stats.completed >= jobs {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("jobs never finished: {:?}", pool.stats());
+}
+
+#[test]
+fn hands_back_job_results() {
+    let pool = ThreadPool::new(4).unwrap();
+    let handles: Vec<_> = (0..20)
+        .map(|i| pool.execute(move || i * i).unwrap())
+        .collect();
+    let results: Vec<i32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, (0..20).map(|i| i * i).collect::<Vec<_>>());
+    assert!(ThreadPool::new(0).is_err());
+}
+
+#[test]
+fn survives_panicking_jobs() {
+    let pool = ThreadPool::new(1).unwrap();
+    let handle = pool.execute(|| -> u32 { panic!("bad request") }).unwrap();
+    assert_eq!(handle.join(), Err("Job panicked!"));
+    // The only worker is still there for the next job.
+    assert_eq!(pool.execute(|| 7).unwrap().join(), Ok(7));
+
+    let stats = wait_for_completed(&pool, 2);
+    assert_eq!(stats.panicked, 1);
+    assert_eq!((stats.threads, stats.active, stats.idle), (1, 0, 1));
+}
+
+#[test]
+fn turns_jobs_away_when_the_queue_is_full() {
+    let pool = ThreadPool::with_queue(2, 1).unwrap();
+    let barrier = Arc::new(Barrier::new(3));
+    let (release, released) = mpsc::channel::<()>();
+    let released = Arc::new(Mutex::new(released));
+    // Both workers busy, and one job waiting.
+    for _ in 0..2 {
+        let barrier = Arc::clone(&barrier);
+        let released = Arc::clone(&released);
+        pool.execute(move || {
+            barrier.wait();
+            let _ = released.lock().unwrap().recv();
+        })
+        .unwrap();
+    }
+    barrier.wait();
+    let queued = pool.execute(|| "queued").unwrap();
+
+    let stats = pool.stats();
+    assert_eq!((stats.active, stats.idle, stats.queued), (2, 0, 1));
+    assert_eq!(
+        pool.try_execute(|| "rejected").unwrap_err(),
+        "Thread pool queue is full!"
+    );
+
+    drop(release);
+    assert_eq!(queued.join(), Ok("queued"));
+    let stats = wait_for_completed(&pool, 3);
+    assert_eq!((stats.active, stats.queued, stats.panicked), (0, 0, 0));
+}
+
+#[test]
+fn runs_queued_jobs_before_dropping() {
+    let pool = ThreadPool::with_queue(1, 8).unwrap();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                i
+            })
+            .unwrap()
+        })
+        .collect();
+    drop(pool);
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Ok(i));
+    }
+}
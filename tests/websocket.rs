@@ -0,0 +1,206 @@
+// Integration tests for the WebSocket transport.
+//
+
+use chat_server::*;
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+struct Server {
+    shutdown: ShutdownHandle,
+    thread: thread::JoinHandle<()>,
+}
+
+fn start_server(app: App) -> (SocketAddr, Server) {
+    let server = WsServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), 4).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let app = Arc::new(Mutex::new(app));
+    let thread = thread::spawn(move || server.serve(app));
+    (addr, Server { shutdown, thread })
+}
+
+// Open connections are told the server is going away.
+fn stop_server(addr: SocketAddr, server: Server) {
+    let mut client = Client::connect(addr);
+    client.request("GET USER EMAIL user0@");
+    server.shutdown.stop();
+    assert_eq!(client.recv().close_code(), Some(1001));
+    server.thread.join().unwrap();
+}
+
+const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    // Sends the upgrade request, returning the status line and headers.
+    fn handshake(addr: SocketAddr, headers: &str) -> (Client, Vec<String>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let req = format!("GET /chat HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut resp = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            resp.push(line);
+        }
+        (Client { stream, reader }, resp)
+    }
+
+    fn connect(addr: SocketAddr) -> Client {
+        let (client, resp) = Client::handshake(
+            addr,
+            "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+        );
+        assert_eq!(resp[0], "HTTP/1.1 101 Switching Protocols");
+        assert!(resp.contains(&String::from(
+            "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        )));
+        client
+    }
+
+    fn send(&mut self, frame: WsFrame) {
+        frame.write(&mut self.stream, Some(MASK)).unwrap();
+    }
+
+    fn send_text(&mut self, text: &str) {
+        self.send(WsFrame::text(text));
+    }
+
+    fn recv(&mut self) -> WsFrame {
+        let frame = WsFrame::read(&mut self.reader).unwrap().unwrap();
+        assert!(!frame.masked);
+        frame
+    }
+
+    fn recv_text(&mut self) -> String {
+        let frame = self.recv();
+        assert_eq!(frame.opcode, Opcode::Text, "{:?}", frame);
+        String::from_utf8(frame.payload).unwrap()
+    }
+
+    fn request(&mut self, req: &str) -> String {
+        self.send_text(req);
+        self.recv_text()
+    }
+}
+
+fn test_app() -> App {
+    let mut app = App::new();
+    for i in 0..2 {
+        app.register(
+            &format!("User {}", i),
+            &format!("user{}@mail.ca", i),
+            "password",
+        )
+        .unwrap();
+    }
+    let users: Vec<User> = (0..2)
+        .map(|i| {
+            app.get_user("EMAIL", &format!("user{}@mail.ca", i))
+                .unwrap()
+        })
+        .collect();
+    app.add_conv("Chat", users).unwrap();
+    app
+}
+
+#[test]
+fn accept_key_matches_the_rfc_example() {
+    assert_eq!(
+        WsServer::accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+}
+
+#[test]
+fn runs_commands_and_pushes_messages() {
+    let (addr, server) = start_server(test_app());
+
+    let mut sender = Client::connect(addr);
+    assert_eq!(sender.request("GET USER NAME User 1"), "NOT LOGGED IN");
+    assert!(sender
+        .request("LOGIN user0@mail.ca password")
+        .starts_with("LOGGED IN"));
+    let conv = sender.request("GET CONV NAME Chat");
+    let conv = conv.split(';').next().unwrap().to_string();
+
+    let mut member = Client::connect(addr);
+    member.request("LOGIN user1@mail.ca password");
+    assert_eq!(member.request("SUBSCRIBE ALL"), "SUBSCRIBED ALL");
+
+    // A message split over several frames, with a ping in the middle.
+    let mut first = WsFrame::text(&format!("SEND MSG {} hello", conv));
+    first.fin = false;
+    sender.send(first);
+    sender.send(WsFrame::new(Opcode::Ping, b"are you there".to_vec()));
+    sender.send(WsFrame::new(Opcode::Continuation, b" world".to_vec()));
+    let pong = sender.recv();
+    assert_eq!(pong.opcode, Opcode::Pong);
+    assert_eq!(pong.payload, b"are you there");
+    assert!(sender.recv_text().contains(";hello world;"));
+
+    let push = member.recv_text();
+    assert!(push.starts_with("PUSH MSG "), "{}", push);
+    assert!(push.contains(";hello world;"), "{}", push);
+
+    member.send(WsFrame::close(1000, "bye"));
+    assert_eq!(member.recv().close_code(), Some(1000));
+    assert!(WsFrame::read(&mut member.reader).unwrap().is_none());
+    drop((sender, member));
+
+    stop_server(addr, server);
+}
+
+#[test]
+fn rejects_bad_handshakes_and_frames() {
+    let (addr, server) = start_server(test_app());
+
+    let (_, resp) = Client::handshake(addr, "Sec-WebSocket-Version: 13\r\n");
+    assert_eq!(resp[0], "HTTP/1.1 400 Bad Request");
+
+    let mut client = Client::connect(addr);
+    WsFrame::text("LOGOUT")
+        .write(&mut client.stream, None)
+        .unwrap();
+    assert_eq!(client.recv().close_code(), Some(1002));
+
+    let mut client = Client::connect(addr);
+    client.send(WsFrame::new(Opcode::Binary, vec![1, 2, 3]));
+    assert_eq!(client.recv().close_code(), Some(1003));
+
+    let mut client = Client::connect(addr);
+    client.send(WsFrame::new(Opcode::Text, vec![0xff, 0xfe]));
+    assert_eq!(client.recv().close_code(), Some(1007));
+
+    let mut client = Client::connect(addr);
+    client.send(WsFrame::text(&"a".repeat(MAX_FRAME + 1)));
+    assert_eq!(client.recv().close_code(), Some(1009));
+
+    // The close code sent back is ours, never the client's.
+    for (code, reply) in [(4000, 1000), (1001, 1000), (1005, 1002), (2999, 1002)] {
+        let mut client = Client::connect(addr);
+        client.send(WsFrame::close(code, ""));
+        assert_eq!(client.recv().close_code(), Some(reply), "{}", code);
+    }
+    let mut client = Client::connect(addr);
+    client.send(WsFrame::new(Opcode::Close, vec![3]));
+    assert_eq!(client.recv().close_code(), Some(1002));
+
+    stop_server(addr, server);
+}